@@ -0,0 +1,21 @@
+-- Large task args and event bodies, the row only keeps {"$payload": id}
+create table if not exists chang.payloads
+	( id uuid primary key default uuid_generate_v4()
+	, source text not null
+	, payload jsonb not null
+	, size integer not null
+	, created_at timestamptz not null default now()
+	);
+
+create index chang_payloads_source_idx on chang.payloads using btree(source, created_at);
+
+alter table chang.payloads alter column payload set storage extended;
+
+-- lz4 compresses faster than the default pglz, it needs postgres 14 built with lz4
+do $$
+begin
+	alter table chang.payloads alter column payload set compression lz4;
+exception when others then
+	null;
+end
+$$;
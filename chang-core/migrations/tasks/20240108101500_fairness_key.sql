@@ -0,0 +1,4 @@
+alter table chang.tasks
+  add column if not exists fairness_key text;
+
+create index chang_task_fairness_key on chang.tasks using btree(queue, fairness_key, scheduled_at, state);
@@ -0,0 +1,4 @@
+alter table chang.periodic_tasks
+  add column if not exists paused boolean not null default false,
+  add column if not exists created_at timestamptz not null default now(),
+  add column if not exists updated_at timestamptz not null default now();
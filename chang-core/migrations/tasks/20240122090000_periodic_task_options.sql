@@ -0,0 +1,19 @@
+alter table chang.tasks
+  add column if not exists timeout_ms bigint;
+
+alter table chang.periodic_tasks
+  add column if not exists name text,
+  add column if not exists args jsonb not null default 'null'::jsonb,
+  add column if not exists priority smallint,
+  add column if not exists max_attempts smallint,
+  add column if not exists tags text[] not null default '{}',
+  add column if not exists timeout_ms bigint;
+
+update chang.periodic_tasks
+   set name = kind
+ where name is null;
+
+alter table chang.periodic_tasks
+  alter column name set not null,
+  drop constraint if exists periodic_tasks_queue_kind_key,
+  add constraint periodic_tasks_queue_name_key unique(queue, name);
@@ -0,0 +1,8 @@
+alter table chang.tasks
+  add column if not exists periodic_name text;
+
+-- one task per periodic job and slot, so several schedulers can insert the
+-- same slot without enqueuing a job twice
+create unique index chang_task_periodic_slot
+    on chang.tasks using btree(queue, kind, periodic_name, scheduled_at)
+ where periodic_name is not null;
@@ -0,0 +1,17 @@
+create type chang.misfire_policy as enum(
+  'skip',
+  'run_once',
+  'run_all'
+);
+
+alter table chang.periodic_tasks
+  add column if not exists misfire_policy chang.misfire_policy not null default 'skip',
+  add column if not exists scheduled_until timestamptz;
+
+-- only pending runs block a slot, so finished slots can be backfilled
+drop index if exists chang.chang_task_periodic_slot;
+
+create unique index chang_task_periodic_slot
+    on chang.tasks using btree(queue, kind, periodic_name, scheduled_at)
+ where periodic_name is not null
+   and state in ('available', 'retryable', 'running', 'scheduled');
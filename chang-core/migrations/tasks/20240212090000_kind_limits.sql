@@ -0,0 +1,34 @@
+-- caps the number of running tasks per kind across all runners, `running`
+-- is kept up to date by the trigger below
+create table if not exists chang.kind_limits (
+  kind text primary key,
+  max_running integer not null check (max_running >= 0),
+  running integer not null default 0,
+  created_at timestamptz not null default now(),
+  updated_at timestamptz not null default now()
+);
+
+create or replace function chang.count_running_kinds() returns trigger as $$
+begin
+  if tg_op in ('UPDATE', 'DELETE') and old.state = 'running' then
+    update chang.kind_limits
+       set running = greatest(running - 1, 0)
+     where kind = old.kind;
+  end if;
+
+  if tg_op in ('INSERT', 'UPDATE') and new.state = 'running' then
+    update chang.kind_limits
+       set running = running + 1
+     where kind = new.kind;
+  end if;
+
+  return null;
+end;
+$$ language plpgsql;
+
+drop trigger if exists chang_count_running_kinds on chang.tasks;
+
+create trigger chang_count_running_kinds
+ after insert or update of state, kind or delete on chang.tasks
+   for each row
+execute function chang.count_running_kinds();
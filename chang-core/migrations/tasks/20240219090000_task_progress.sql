@@ -0,0 +1,4 @@
+alter table chang.tasks
+  add column if not exists progress real,
+  add column if not exists progress_message text,
+  add column if not exists progress_data jsonb;
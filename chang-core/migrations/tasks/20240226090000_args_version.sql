@@ -0,0 +1,6 @@
+-- version of the args' shape, older args are upcast before they're deserialized
+alter table chang.tasks
+	add column args_version smallint not null default 1;
+
+alter table chang.periodic_tasks
+	add column args_version smallint not null default 1;
@@ -0,0 +1,3 @@
+alter table chang.tasks add column expires_at timestamptz;
+
+create index chang_task_expires_at on chang.tasks using btree(queue, expires_at) where expires_at is not null;
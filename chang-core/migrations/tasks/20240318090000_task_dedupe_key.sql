@@ -0,0 +1,9 @@
+alter table chang.tasks
+  add column if not exists dedupe_key text;
+
+-- at most one waiting task per key, debounced and throttled enqueues merge
+-- into it
+create unique index chang_task_dedupe_key
+    on chang.tasks using btree(queue, kind, dedupe_key)
+ where dedupe_key is not null
+   and state in ('available', 'retryable', 'scheduled');
@@ -0,0 +1,2 @@
+create index if not exists chang_task_history_task_id on chang.task_history using btree(task_id, created_at);
+create index if not exists chang_task_error_task_id on chang.task_error using btree(task_id, created_at);
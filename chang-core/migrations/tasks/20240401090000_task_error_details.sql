@@ -0,0 +1,2 @@
+alter table chang.task_error
+  add column if not exists details jsonb;
@@ -0,0 +1,3 @@
+-- serves the aging claim, which walks the priorities of waiting tasks and
+-- takes the oldest tasks of each
+create index if not exists chang_task_waiting_priority on chang.tasks using btree(queue, priority, scheduled_at, id) where state in ('available', 'retryable');
@@ -0,0 +1,12 @@
+-- the payload offloaded args refer to, so orphaned payloads can be found
+-- with an index instead of a scan of all args
+alter table chang.tasks
+  add column if not exists payload_id uuid
+      generated always as (
+        case
+          when args->>'$payload' ~ '^[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12}$'
+          then (args->>'$payload')::uuid
+        end
+      ) stored;
+
+create index if not exists chang_task_payload_id on chang.tasks using btree(payload_id) where payload_id is not null;
@@ -0,0 +1,8 @@
+-- a task that already ran doesn't hold its key, so it can fail and retry
+-- while a duplicate waits
+drop index if exists chang.chang_task_dedupe_key;
+
+create unique index chang_task_dedupe_key
+    on chang.tasks using btree(queue, kind, dedupe_key)
+ where dedupe_key is not null
+   and state in ('available', 'scheduled');
@@ -0,0 +1,27 @@
+-- discards the waiting tasks of `queue`, or only those of `kind`, that are
+-- past their deadline instead of running them late, every claim calls it
+-- and leaves out the returned ids
+create or replace function chang.discard_expired(queue text, kind text default null)
+returns setof uuid as $$
+	with expired_tasks as (
+		select id, state
+		  from chang.tasks
+		 where tasks.queue = $1
+		   and ($2 is null or tasks.kind = $2)
+		   and expires_at <= now()
+		   and (state = 'available' or state = 'retryable')
+		   for update skip locked
+	), expired_history as (
+		insert into chang.task_history(task_id, from_state, to_state, comment)
+		select id as task_id
+		     , expired_tasks.state as from_state
+		     , 'discarded'::chang.tasks_state as to_state
+		     , 'expired' as comment
+		  from expired_tasks
+		returning task_id
+	)
+	update chang.tasks
+	   set state = 'discarded'
+	 where id in (select task_id from expired_history)
+	returning id
+$$ language sql;
@@ -0,0 +1,25 @@
+-- whether `kind` already runs as many tasks as its limit allows, claims
+-- leave such kinds out before locking anything
+create or replace function chang.kind_at_limit(kind text) returns boolean as $$
+	select exists (
+		select 1
+		  from chang.kind_limits
+		 where kind_limits.kind = $1
+		   and kind_limits.running >= kind_limits.max_running
+	)
+$$ language sql stable;
+
+-- locks the counters of the limited `kinds` a claim is about to take tasks
+-- of and returns their free slots. Only the counters of the claimed kinds
+-- are locked, so concurrent claims of a limited kind wait for each other
+-- and then see the updated counts. The counts only rise once the claim
+-- moves its tasks to running, so the claim splits the free slots between
+-- its tasks itself, ranking them per kind.
+create or replace function chang.lock_kind_limits(kinds text[])
+returns table(kind text, free integer) as $$
+	select kind_limits.kind
+	     , kind_limits.max_running - kind_limits.running as free
+	  from chang.kind_limits
+	 where kind_limits.kind = any($1)
+	   for update
+$$ language sql;
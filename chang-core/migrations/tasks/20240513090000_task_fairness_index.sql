@@ -0,0 +1,4 @@
+-- serve the fair claim, which walks the keys of waiting tasks and takes the
+-- oldest tasks of each, and counts the running tasks of a key
+create index if not exists chang_task_waiting_fairness on chang.tasks using btree(queue, (coalesce('key:' || fairness_key, 'none')), scheduled_at, id) where state in ('available', 'retryable');
+create index if not exists chang_task_running_fairness on chang.tasks using btree(queue, (coalesce('key:' || fairness_key, 'none'))) where state = 'running';
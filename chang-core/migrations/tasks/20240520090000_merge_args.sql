@@ -0,0 +1,18 @@
+-- merges the args of a deduped insert into the waiting task like `jsonb ||`.
+-- Args of different versions have different shapes, so merging them is
+-- rejected instead of producing args no version describes.
+create or replace function chang.merge_args(
+	waiting jsonb,
+	waiting_version smallint,
+	args jsonb,
+	args_version smallint
+) returns jsonb as $$
+begin
+	if waiting_version is distinct from args_version then
+		raise exception 'cannot merge args of version % into args of version %', args_version, waiting_version
+			using errcode = 'data_exception';
+	end if;
+
+	return waiting || args;
+end;
+$$ language plpgsql immutable;
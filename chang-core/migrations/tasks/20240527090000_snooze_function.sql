@@ -0,0 +1,60 @@
+-- snoozes a running task, returns its id or null if it isn't running. A
+-- deduped task whose key got a waiting duplicate while it ran becomes
+-- retryable instead of available, only one task of a key waits. The check
+-- can't see a duplicate inserted concurrently, the unique index catches it
+-- once that insert commits and the task becomes retryable after all.
+create or replace function chang.snooze(snoozed_id uuid, snoozed_until timestamptz, new_args jsonb)
+returns uuid as $$
+declare
+	snoozed chang.tasks;
+	next_state chang.tasks_state := 'retryable';
+begin
+	-- a task that already finished stays finished
+	select *
+	  into snoozed
+	  from chang.tasks
+	 where id = snoozed_id
+	   and state = 'running'
+	   for update;
+
+	if not found then
+		return null;
+	end if;
+
+	-- retryable tasks stay out of the dedupe index
+	update chang.tasks
+	   set state = 'retryable'
+	     , scheduled_at = snoozed_until
+	     , attempt = greatest(attempt - 1, 0)
+	     , args = coalesce(new_args, args)
+	 where id = snoozed_id;
+
+	if snoozed.dedupe_key is null or not exists (
+		select 1
+		  from chang.tasks as waiting
+		 where waiting.queue = snoozed.queue
+		   and waiting.kind = snoozed.kind
+		   and waiting.dedupe_key = snoozed.dedupe_key
+		   and waiting.state in ('available', 'scheduled')
+	) then
+		begin
+			update chang.tasks
+			   set state = 'available'
+			 where id = snoozed_id;
+			next_state := 'available';
+		exception when unique_violation then
+			next_state := 'retryable';
+		end;
+	end if;
+
+	insert into chang.task_history(task_id, from_state, to_state, comment)
+	values (
+		snoozed_id
+	  , 'running'
+	  , next_state
+	  , 'snoozed until ' || to_char(snoozed_until at time zone 'UTC', 'YYYY-MM-DD"T"HH24:MI:SS"Z"')
+	);
+
+	return snoozed_id;
+end;
+$$ language plpgsql;
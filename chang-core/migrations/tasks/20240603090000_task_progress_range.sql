@@ -0,0 +1,3 @@
+-- progress is a percentage, `not valid` leaves rows written before alone
+alter table chang.tasks
+	add constraint chang_task_progress_range check (progress >= 0 and progress <= 100) not valid;
@@ -100,7 +100,7 @@ pub async fn create(database_url: &str) -> Result<(), DatabaseError> {
         .and_then(|mut segments| segments.next())
         .expect("database name");
 
-    name::is_valid(&database)?;
+    name::is_valid(database)?;
 
     url.set_path("postgres");
 
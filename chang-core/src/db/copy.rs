@@ -0,0 +1,185 @@
+use chrono::{DateTime, TimeZone, Utc};
+use futures::{Stream, StreamExt};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+/// Rows written by a bulk insert so far, passed to the progress callback
+/// after every committed chunk.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CopyProgress {
+    pub rows: u64,
+    pub chunks: u64,
+}
+
+pub(crate) const TEXT_OID: u32 = 25;
+pub(crate) const VARCHAR_OID: u32 = 1043;
+
+const SIGNATURE: &[u8] = b"PGCOPY\n\xff\r\n\0";
+
+/// A value in the binary format of `copy ... from stdin (format binary)`.
+pub(crate) trait CopyField {
+    fn write(&self, buf: &mut Vec<u8>);
+}
+
+fn write_len(buf: &mut Vec<u8>, len: usize) {
+    buf.extend_from_slice(&(len as i32).to_be_bytes());
+}
+
+impl<T: CopyField> CopyField for Option<T> {
+    fn write(&self, buf: &mut Vec<u8>) {
+        match self {
+            Some(value) => value.write(buf),
+            None => buf.extend_from_slice(&(-1i32).to_be_bytes()),
+        }
+    }
+}
+
+impl<T: CopyField + ?Sized> CopyField for &T {
+    fn write(&self, buf: &mut Vec<u8>) {
+        (**self).write(buf)
+    }
+}
+
+impl CopyField for i16 {
+    fn write(&self, buf: &mut Vec<u8>) {
+        write_len(buf, 2);
+        buf.extend_from_slice(&self.to_be_bytes());
+    }
+}
+
+impl CopyField for i32 {
+    fn write(&self, buf: &mut Vec<u8>) {
+        write_len(buf, 4);
+        buf.extend_from_slice(&self.to_be_bytes());
+    }
+}
+
+impl CopyField for i64 {
+    fn write(&self, buf: &mut Vec<u8>) {
+        write_len(buf, 8);
+        buf.extend_from_slice(&self.to_be_bytes());
+    }
+}
+
+impl CopyField for str {
+    fn write(&self, buf: &mut Vec<u8>) {
+        write_len(buf, self.len());
+        buf.extend_from_slice(self.as_bytes());
+    }
+}
+
+impl CopyField for String {
+    fn write(&self, buf: &mut Vec<u8>) {
+        self.as_str().write(buf)
+    }
+}
+
+impl CopyField for Uuid {
+    fn write(&self, buf: &mut Vec<u8>) {
+        write_len(buf, 16);
+        buf.extend_from_slice(self.as_bytes());
+    }
+}
+
+impl CopyField for DateTime<Utc> {
+    /// Microseconds since 2000-01-01.
+    fn write(&self, buf: &mut Vec<u8>) {
+        let epoch = Utc.with_ymd_and_hms(2000, 1, 1, 0, 0, 0).unwrap();
+        let micros = (*self - epoch).num_microseconds().unwrap_or(i64::MAX);
+        micros.write(buf)
+    }
+}
+
+/// Written as `jsonb`, a version byte followed by the text.
+impl CopyField for serde_json::Value {
+    fn write(&self, buf: &mut Vec<u8>) {
+        let text = self.to_string();
+        write_len(buf, text.len() + 1);
+        buf.push(1);
+        buf.extend_from_slice(text.as_bytes());
+    }
+}
+
+/// A one dimensional array of strings, `oid` is the element type the column
+/// expects, `text` or `varchar`.
+pub(crate) struct TextArray<'a>(pub &'a [String], pub u32);
+
+impl CopyField for TextArray<'_> {
+    fn write(&self, buf: &mut Vec<u8>) {
+        let TextArray(items, oid) = self;
+        let mut array = Vec::new();
+        array.extend_from_slice(&1i32.to_be_bytes()); // dimensions
+        array.extend_from_slice(&0i32.to_be_bytes()); // has nulls
+        array.extend_from_slice(&oid.to_be_bytes());
+        array.extend_from_slice(&(items.len() as i32).to_be_bytes());
+        array.extend_from_slice(&1i32.to_be_bytes()); // lower bound
+        for item in items.iter() {
+            item.write(&mut array);
+        }
+
+        write_len(buf, array.len());
+        buf.extend_from_slice(&array);
+    }
+}
+
+/// The rows of one `copy` statement.
+pub(crate) struct BinaryCopy {
+    buf: Vec<u8>,
+}
+
+impl BinaryCopy {
+    pub fn new() -> Self {
+        let mut buf = Vec::new();
+        buf.extend_from_slice(SIGNATURE);
+        buf.extend_from_slice(&0i32.to_be_bytes()); // flags
+        buf.extend_from_slice(&0i32.to_be_bytes()); // header extension
+        BinaryCopy { buf }
+    }
+
+    pub fn row(&mut self, fields: &[&dyn CopyField]) {
+        self.buf
+            .extend_from_slice(&(fields.len() as i16).to_be_bytes());
+        for field in fields {
+            field.write(&mut self.buf);
+        }
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        self.buf.extend_from_slice(&(-1i16).to_be_bytes());
+        self.buf
+    }
+}
+
+/// Writes the rows of each chunk with `copy`, every chunk in its own
+/// transaction. `encode` turns a chunk into `(statement, rows)` pairs that
+/// are copied in order, so a chunk can fill several tables.
+pub(crate) async fn copy_chunks<T, S>(
+    db: &PgPool,
+    items: S,
+    chunk_size: usize,
+    encode: impl Fn(Vec<T>) -> Vec<(&'static str, BinaryCopy)>,
+    mut on_progress: impl FnMut(CopyProgress),
+) -> sqlx::Result<CopyProgress>
+where
+    S: Stream<Item = T>,
+{
+    let mut progress = CopyProgress::default();
+    let mut chunks = std::pin::pin!(items.chunks(chunk_size.max(1)));
+
+    while let Some(chunk) = chunks.next().await {
+        let rows = chunk.len() as u64;
+        let mut tx = db.begin().await?;
+        for (statement, copy) in encode(chunk) {
+            let mut copy_in = tx.copy_in_raw(statement).await?;
+            copy_in.send(copy.finish()).await?;
+            copy_in.finish().await?;
+        }
+        tx.commit().await?;
+
+        progress.rows += rows;
+        progress.chunks += 1;
+        on_progress(progress);
+    }
+
+    Ok(progress)
+}
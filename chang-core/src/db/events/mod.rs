@@ -1,17 +1,85 @@
+use futures::Stream;
 use serde_json;
-use sqlx::PgExecutor;
+use sqlx::{PgExecutor, PgPool};
 
-use crate::{error::Result, events::transform::EventData};
+use crate::db::copy::{copy_chunks, BinaryCopy, CopyProgress};
+use crate::{
+    error::Result,
+    events::transform::{EventData, EventRecord},
+};
+
+const COPY_EVENTS: &str =
+    "copy chang.events(id, kind, body, created_at) from stdin (format binary)";
 
 pub struct EventsService;
 
 impl EventsService {
     pub async fn batch_insert(db: impl PgExecutor<'_>, data: EventData) -> Result<()> {
         let events = serde_json::to_value(&data.events)?;
-        sqlx::query_file!("src/db/events/sql/batch_insert.sql", events)
+        let payloads = serde_json::to_value(&data.payloads)?;
+        sqlx::query_file!("src/db/events/sql/batch_insert.sql", events, payloads)
             .execute(db)
             .await?;
 
         Ok(())
     }
+
+    /// Streams events into `chang.events` with a binary `copy`, see
+    /// [`crate::db::tasks::TaskService::copy_insert`].
+    pub async fn copy_insert<S>(
+        db: &PgPool,
+        events: S,
+        chunk_size: usize,
+        on_progress: impl FnMut(CopyProgress),
+    ) -> sqlx::Result<CopyProgress>
+    where
+        S: Stream<Item = EventRecord>,
+    {
+        let encode = |events: Vec<EventRecord>| {
+            let mut rows = BinaryCopy::new();
+            for event in events.iter() {
+                rows.row(&[&event.id, &event.kind, &event.body, &event.created_at]);
+            }
+            vec![(COPY_EVENTS, rows)]
+        };
+
+        copy_chunks(db, events, chunk_size, encode, on_progress).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{db::migration, utils};
+
+    #[tokio::test]
+    async fn copies_events() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::events(&prepare.pool).await;
+
+        let events =
+            (0..10).map(|index| EventRecord::new("copied", serde_json::json!({ "index": index })));
+        let progress =
+            EventsService::copy_insert(&prepare.pool, futures::stream::iter(events), 4, |_| {})
+                .await
+                .unwrap();
+        assert_eq!(
+            progress,
+            CopyProgress {
+                rows: 10,
+                chunks: 3
+            }
+        );
+
+        let count: i64 =
+            sqlx::query_scalar("select count(*) from chang.events where kind = 'copied'")
+                .fetch_one(&prepare.pool)
+                .await
+                .unwrap();
+        assert_eq!(count, 10);
+
+        utils::test::cleanup(prepare).await;
+    }
 }
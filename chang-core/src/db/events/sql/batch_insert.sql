@@ -1,7 +1,13 @@
 /*
  * $1 json - Array of logs 
+ * $2 json - Array of offloaded bodies
  */
  
+with payloads as (
+	insert into chang.payloads(id, source, payload, size)
+	select id, 'event', payload, size
+	  from jsonb_to_recordset($2) as payloads(id uuid, payload jsonb, size integer)
+)
 insert into chang.events
 select *
   from jsonb_to_recordset($1) as event_records
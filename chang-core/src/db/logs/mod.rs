@@ -1,10 +1,26 @@
+use chrono::{DateTime, Utc};
 use serde_json;
 use sqlx::PgExecutor;
+use uuid::Uuid;
 
 use crate::{error::Result, otel::logs::transform::LogData};
 
 pub struct LogsService;
 
+/// A row of `chang.logs`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogLine {
+    pub id: Uuid,
+    pub time: Option<DateTime<Utc>>,
+    pub observed_time: DateTime<Utc>,
+    pub severity_number: i64,
+    pub severity_text: Option<String>,
+    pub body: Option<String>,
+    pub attributes: serde_json::Value,
+    pub span_id: Option<String>,
+    pub trace_id: Option<String>,
+}
+
 impl LogsService {
     pub async fn batch_insert(db: impl PgExecutor<'_>, data: LogData) -> Result<()> {
         let logs = serde_json::to_value(&data.logs)?;
@@ -14,4 +30,76 @@ impl LogsService {
 
         Ok(())
     }
+
+    /// Log lines a task's handler emitted through
+    /// [`crate::otel::logs::ChangLogBridge`], oldest first. `attempt` limits
+    /// them to a single run.
+    pub async fn get_task_logs(
+        db: impl PgExecutor<'_>,
+        task_id: &Uuid,
+        attempt: Option<i16>,
+    ) -> Result<Vec<LogLine>> {
+        let logs = sqlx::query_file_as!(
+            LogLine,
+            "src/db/logs/sql/get_task_logs.sql",
+            task_id,
+            attempt.map(i32::from)
+        )
+        .fetch_all(db)
+        .await?;
+
+        Ok(logs)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::migration;
+    use crate::utils;
+
+    #[tokio::test]
+    async fn gets_logs_of_a_task() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::otel(&prepare.pool).await;
+
+        let task_id = Uuid::new_v4();
+        let lines = [
+            (task_id, 1, "first attempt"),
+            (task_id, 2, "second attempt"),
+            (Uuid::new_v4(), 1, "other task"),
+        ];
+        for (id, attempt, body) in lines {
+            sqlx::query(
+                "insert into chang.logs(severity_number, body, attributes, time)
+                 values (9, $1, jsonb_build_object('task.id', $2::text, 'task.attempt', $3::integer), clock_timestamp())",
+            )
+            .bind(body)
+            .bind(id.to_string())
+            .bind(attempt)
+            .execute(&prepare.pool)
+            .await
+            .unwrap();
+        }
+
+        let bodies = |logs: Vec<LogLine>| {
+            logs.into_iter()
+                .filter_map(|log| log.body)
+                .collect::<Vec<String>>()
+        };
+
+        let logs = LogsService::get_task_logs(&prepare.pool, &task_id, None)
+            .await
+            .unwrap();
+        assert_eq!(bodies(logs), vec!["first attempt", "second attempt"]);
+
+        let logs = LogsService::get_task_logs(&prepare.pool, &task_id, Some(2))
+            .await
+            .unwrap();
+        assert_eq!(bodies(logs), vec!["second attempt"]);
+
+        utils::test::cleanup(prepare).await;
+    }
 }
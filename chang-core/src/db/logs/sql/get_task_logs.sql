@@ -0,0 +1,21 @@
+/*
+ * $1 uuid - task id
+ * $2 integer - attempt, null for every attempt
+ */
+select id
+     , time
+     , observed_time
+     , severity_number
+     , severity_text
+     , body
+     , attributes
+     , span_id
+     , trace_id
+  from chang.logs
+ where attributes @> jsonb_build_object('task.id', $1::uuid::text)
+   and (
+         $2::integer is null
+      or attributes @> jsonb_build_object('task.attempt', $2::integer)
+       )
+ order by coalesce(time, observed_time) asc
+        , observed_time asc
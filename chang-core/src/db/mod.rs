@@ -1,6 +1,8 @@
+pub mod copy;
 pub mod events;
 pub mod logs;
 pub mod metrics;
 pub mod migration;
+pub mod payloads;
 pub mod spans;
 pub mod tasks;
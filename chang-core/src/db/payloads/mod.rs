@@ -0,0 +1,111 @@
+use serde::Serialize;
+use sqlx::PgExecutor;
+use uuid::Uuid;
+
+/// The field of the reference that replaces an offloaded payload.
+pub const PAYLOAD_FIELD: &str = "$payload";
+
+/// A task's args or an event's body stored in `chang.payloads`, which keeps
+/// them compressed and out of line.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Payload {
+    pub id: Uuid,
+    pub payload: serde_json::Value,
+    /// Bytes of the serialized payload.
+    pub size: i32,
+}
+
+impl Payload {
+    /// Splits `value` into a reference and its payload when it serializes to
+    /// more than `threshold` bytes, `keep` are top level fields that are also
+    /// kept on the reference, so they can still be queried.
+    pub fn offload(
+        value: serde_json::Value,
+        threshold: usize,
+        keep: &[String],
+    ) -> (serde_json::Value, Option<Payload>) {
+        let size = value.to_string().len();
+        if size <= threshold {
+            return (value, None);
+        }
+
+        let id = Uuid::new_v4();
+        let mut reference = serde_json::Map::new();
+        if let Some(fields) = value.as_object() {
+            for name in keep {
+                if let Some(field) = fields.get(name) {
+                    reference.insert(name.clone(), field.clone());
+                }
+            }
+        }
+        reference.insert(PAYLOAD_FIELD.to_string(), serde_json::json!(id));
+
+        let payload = Payload {
+            id,
+            payload: value,
+            size: i32::try_from(size).unwrap_or(i32::MAX),
+        };
+
+        (serde_json::Value::Object(reference), Some(payload))
+    }
+
+    /// The id of the payload `value` refers to, if it was offloaded.
+    pub fn reference(value: &serde_json::Value) -> Option<Uuid> {
+        value
+            .get(PAYLOAD_FIELD)
+            .and_then(|id| id.as_str())
+            .and_then(|id| id.parse().ok())
+    }
+}
+
+pub struct PayloadService;
+
+impl PayloadService {
+    pub async fn get(
+        db: impl PgExecutor<'_>,
+        id: &Uuid,
+    ) -> sqlx::Result<Option<serde_json::Value>> {
+        let row = sqlx::query_file!("src/db/payloads/sql/get_payload.sql", id)
+            .fetch_optional(db)
+            .await?;
+
+        Ok(row.map(|row| row.payload))
+    }
+
+    /// Loads the payload `value` refers to, values that weren't offloaded
+    /// are returned as they are.
+    pub async fn resolve(
+        db: impl PgExecutor<'_>,
+        value: serde_json::Value,
+    ) -> sqlx::Result<serde_json::Value> {
+        let Some(id) = Payload::reference(&value) else {
+            return Ok(value);
+        };
+
+        PayloadService::get(db, &id)
+            .await?
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn offloads_above_the_threshold() {
+        let value = serde_json::json!({ "tenant": "chang", "body": "x".repeat(64) });
+
+        let (small, payload) = Payload::offload(value.clone(), 1024, &[]);
+        assert_eq!(small, value);
+        assert!(payload.is_none());
+
+        let (reference, payload) = Payload::offload(value.clone(), 32, &["tenant".to_string()]);
+        let payload = payload.unwrap();
+        assert_eq!(payload.payload, value);
+        assert_eq!(payload.size as usize, value.to_string().len());
+        assert_eq!(reference["tenant"], "chang");
+        assert_eq!(Payload::reference(&reference), Some(payload.id));
+        assert_eq!(Payload::reference(&value), None);
+    }
+}
@@ -0,0 +1,6 @@
+/*
+ * $1 uuid - payload id
+ */
+select payload
+  from chang.payloads
+ where id = $1
@@ -1,8 +1,17 @@
 use chrono::{DateTime, Utc};
+use futures::{future, Stream, StreamExt};
 use serde::{Deserialize, Serialize};
-use sqlx::PgExecutor;
+use sqlx::{PgConnection, PgExecutor, PgPool};
+use std::time::Duration;
 use uuid::Uuid;
 
+use crate::db::copy::{copy_chunks, BinaryCopy, CopyProgress, TextArray, TEXT_OID, VARCHAR_OID};
+use crate::db::payloads::Payload;
+use crate::task::{
+    ArgsBinding, ArgsEncryption, EncryptionError, Failure, MisfirePolicy, PeriodicJob,
+    PeriodicScheduleError, TaskTimeline, DEFAULT_QUEUE,
+};
+
 pub fn try_from(
     task: impl TryInto<TaskBuilder, Error = serde_json::Error>,
 ) -> Result<TaskBuilder, serde_json::Error> {
@@ -11,6 +20,8 @@ pub fn try_from(
 
 #[derive(PartialEq, Debug, Clone, Serialize)]
 pub struct NewTask {
+    /// Generated by the builder, so encrypted args can be bound to the task.
+    pub id: Uuid,
     pub scheduled_at: Option<DateTime<Utc>>,
     pub max_attempts: i16,
     pub attempted_by: Vec<String>,
@@ -21,6 +32,73 @@ pub struct NewTask {
     pub queue: Option<String>,
     pub depends_on: Option<Uuid>,
     pub dependend_id: Option<Uuid>,
+    pub fairness_key: Option<String>,
+    pub timeout_ms: Option<i64>,
+    pub periodic_name: Option<String>,
+    pub args_version: i16,
+    pub expires_at: Option<DateTime<Utc>>,
+    /// The offloaded args, `args` then only holds the reference.
+    #[serde(skip)]
+    pub payload: Option<Payload>,
+    /// Only supported by [`TaskService::insert`], batch inserts reject it.
+    #[serde(skip)]
+    pub dedupe: Option<Dedupe>,
+}
+
+/// Merges an insert into the waiting task of the same queue, kind and key,
+/// see [`TaskBuilder::debounce`] and [`TaskBuilder::throttle`].
+#[derive(PartialEq, Debug, Clone)]
+pub struct Dedupe {
+    pub key: String,
+    pub mode: DedupeMode,
+    pub merge: MergeArgs,
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum DedupeMode {
+    /// Runs `delay` after the last insert.
+    Debounce { delay: Duration },
+    /// Runs at most once per `window`.
+    Throttle { window: Duration },
+}
+
+impl DedupeMode {
+    fn name(&self) -> &'static str {
+        match self {
+            DedupeMode::Debounce { .. } => "debounce",
+            DedupeMode::Throttle { .. } => "throttle",
+        }
+    }
+
+    fn millis(&self) -> i64 {
+        match self {
+            DedupeMode::Debounce { delay } => delay.as_millis() as i64,
+            DedupeMode::Throttle { window } => window.as_millis() as i64,
+        }
+    }
+}
+
+/// How the args of an insert merge into the waiting task.
+#[derive(PartialEq, Debug, Clone, Copy, Default)]
+pub enum MergeArgs {
+    /// The new args replace the waiting task's.
+    #[default]
+    Replace,
+    /// The waiting task keeps its args.
+    Keep,
+    /// Objects are merged shallowly with the new fields winning, arrays are
+    /// concatenated. The insert fails if the args versions differ.
+    Merge,
+}
+
+impl MergeArgs {
+    fn name(&self) -> &'static str {
+        match self {
+            MergeArgs::Replace => "replace",
+            MergeArgs::Keep => "keep",
+            MergeArgs::Merge => "merge",
+        }
+    }
 }
 
 impl NewTask {
@@ -48,6 +126,16 @@ pub trait TaskKind {
     fn kind() -> String
     where
         Self: Sized;
+
+    /// The version of the args' shape. Bump it when the args change and
+    /// register an upcaster from the previous version, see
+    /// [`crate::task::TasksBuilder::upcast`].
+    fn version() -> i16
+    where
+        Self: Sized,
+    {
+        1
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -65,6 +153,17 @@ pub struct Task {
     pub queue: Option<String>,
     pub depends_on: Option<Uuid>,
     pub dependend_id: Option<Uuid>,
+    pub fairness_key: Option<String>,
+    pub timeout_ms: Option<i64>,
+    pub periodic_name: Option<String>,
+    /// Last progress reported by the handler, see [`crate::task::Progress`].
+    pub progress: Option<f32>,
+    pub progress_message: Option<String>,
+    pub progress_data: Option<serde_json::Value>,
+    pub args_version: i16,
+    /// Claims discard the task instead of running it after this.
+    pub expires_at: Option<DateTime<Utc>>,
+    pub dedupe_key: Option<String>,
 }
 
 impl Task {
@@ -80,6 +179,12 @@ pub enum TaskBuildError {
 
     #[error("args missing")]
     ArgsMissing,
+
+    #[error(transparent)]
+    Encryption(#[from] EncryptionError),
+
+    #[error("encrypted or offloaded args can't be merged")]
+    MergeOpaqueArgs,
 }
 
 struct TaskBuilderInner {
@@ -93,6 +198,16 @@ struct TaskBuilderInner {
     queue: Option<String>,
     depends_on: Option<Uuid>,
     dependend_id: Option<Uuid>,
+    fairness_key: Option<String>,
+    timeout: Option<Duration>,
+    periodic_name: Option<String>,
+    args_version: i16,
+    encryption: Option<ArgsEncryption>,
+    plain_fields: Vec<String>,
+    offload_above: Option<usize>,
+    expires_at: Option<DateTime<Utc>>,
+    dedupe: Option<(String, DedupeMode)>,
+    merge_args: MergeArgs,
 }
 
 pub struct TaskBuilder {
@@ -112,6 +227,16 @@ impl Default for TaskBuilder {
             queue: None,
             depends_on: None,
             dependend_id: None,
+            fairness_key: None,
+            timeout: None,
+            periodic_name: None,
+            args_version: 1,
+            encryption: None,
+            plain_fields: vec![],
+            offload_above: None,
+            expires_at: None,
+            dedupe: None,
+            merge_args: MergeArgs::default(),
         };
 
         TaskBuilder { inner }
@@ -130,6 +255,7 @@ impl TaskBuilder {
 
         self.inner.kind = Some(kind);
         self.inner.args = Some(args);
+        self.inner.args_version = T::version();
 
         Ok(())
     }
@@ -144,6 +270,49 @@ impl TaskBuilder {
         self
     }
 
+    /// Defaults to 1, [`TaskBuilder::task`] uses the version of the task kind.
+    pub fn args_version(mut self, version: i16) -> Self {
+        self.set_args_version(version);
+        self
+    }
+
+    pub fn set_args_version(&mut self, version: i16) {
+        self.inner.args_version = version;
+    }
+
+    /// Encrypts the args when the task is built, see [`ArgsEncryption`].
+    pub fn encrypt(mut self, encryption: &ArgsEncryption) -> Self {
+        self.set_encrypt(encryption);
+        self
+    }
+
+    pub fn set_encrypt(&mut self, encryption: &ArgsEncryption) {
+        self.inner.encryption = Some(encryption.clone());
+    }
+
+    /// Top level fields of encrypted args that are stored in plain text, so
+    /// they can still be queried.
+    pub fn plain_fields(mut self, fields: &[&str]) -> Self {
+        self.set_plain_fields(fields);
+        self
+    }
+
+    pub fn set_plain_fields(&mut self, fields: &[&str]) {
+        self.inner.plain_fields = fields.iter().map(|field| field.to_string()).collect();
+    }
+
+    /// Stores args that serialize to more than `bytes` in `chang.payloads`,
+    /// handlers load them with [`crate::task::LazyArgs`]. Plain fields stay
+    /// on the task.
+    pub fn offload_above(mut self, bytes: usize) -> Self {
+        self.set_offload_above(bytes);
+        self
+    }
+
+    pub fn set_offload_above(&mut self, bytes: usize) {
+        self.inner.offload_above = Some(bytes);
+    }
+
     pub fn depends_on(mut self, dependend_id: &Uuid) -> Self {
         self.set_depends_on(dependend_id);
         self
@@ -180,6 +349,55 @@ impl TaskBuilder {
         self.inner.dependend_id = Some(*dependend_id);
     }
 
+    /// Groups the task for `SchedulingStrategy::Fair`, e.g. by tenant id.
+    pub fn fairness_key(mut self, key: &str) -> Self {
+        self.set_fairness_key(key);
+        self
+    }
+
+    pub fn set_fairness_key(&mut self, key: &str) {
+        self.inner.fairness_key = Some(key.to_string());
+    }
+
+    pub fn max_attempts(mut self, max_attempts: i16) -> Self {
+        self.set_max_attempts(max_attempts);
+        self
+    }
+
+    pub fn set_max_attempts(&mut self, max_attempts: i16) {
+        self.inner.max_attempts = Some(max_attempts);
+    }
+
+    pub fn tags(mut self, tags: &[String]) -> Self {
+        self.set_tags(tags);
+        self
+    }
+
+    pub fn set_tags(&mut self, tags: &[String]) {
+        self.inner.tags = tags.to_vec();
+    }
+
+    /// Fails the run if the handler hasn't returned after `timeout`.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.set_timeout(timeout);
+        self
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.inner.timeout = Some(timeout);
+    }
+
+    /// Marks the task as a run of the periodic job `name`. A queue holds at
+    /// most one task per job, kind and `scheduled_at`.
+    pub fn periodic_name(mut self, name: &str) -> Self {
+        self.set_periodic_name(name);
+        self
+    }
+
+    pub fn set_periodic_name(&mut self, name: &str) {
+        self.inner.periodic_name = Some(name.to_string());
+    }
+
     pub fn scheduled_at(mut self, scheduled_at: &DateTime<Utc>) -> Self {
         self.set_scheduled_at(scheduled_at);
         self
@@ -189,12 +407,78 @@ impl TaskBuilder {
         self.inner.scheduled_at = Some(*scheduled_at);
     }
 
+    /// Discards the task with an `expired` history entry if it hasn't been
+    /// claimed by then.
+    pub fn expires_at(mut self, expires_at: &DateTime<Utc>) -> Self {
+        self.set_expires_at(expires_at);
+        self
+    }
+
+    pub fn set_expires_at(&mut self, expires_at: &DateTime<Utc>) {
+        self.inner.expires_at = Some(*expires_at);
+    }
+
+    /// Merges into a waiting task of the same queue, kind and `key` and pushes
+    /// it back to run `delay` after this insert. Without one the task is
+    /// scheduled `delay` from now.
+    pub fn debounce(mut self, key: &str, delay: Duration) -> Self {
+        self.set_debounce(key, delay);
+        self
+    }
+
+    pub fn set_debounce(&mut self, key: &str, delay: Duration) {
+        self.inner.dedupe = Some((key.to_string(), DedupeMode::Debounce { delay }));
+    }
+
+    /// Runs the tasks of `key` at most once per `window`, inserts merge into
+    /// a waiting task and a new one waits until a window after the last.
+    pub fn throttle(mut self, key: &str, window: Duration) -> Self {
+        self.set_throttle(key, window);
+        self
+    }
+
+    pub fn set_throttle(&mut self, key: &str, window: Duration) {
+        self.inner.dedupe = Some((key.to_string(), DedupeMode::Throttle { window }));
+    }
+
+    /// How debounced and throttled args merge into the waiting task,
+    /// defaults to [`MergeArgs::Replace`].
+    pub fn merge_args(mut self, merge: MergeArgs) -> Self {
+        self.set_merge_args(merge);
+        self
+    }
+
+    pub fn set_merge_args(&mut self, merge: MergeArgs) {
+        self.inner.merge_args = merge;
+    }
+
     pub fn build(self) -> Result<NewTask, TaskBuildError> {
         let inner = self.inner;
+        let id = Uuid::new_v4();
         let kind = inner.kind.ok_or(TaskBuildError::KindMissing)?;
-        let args = inner.args.ok_or(TaskBuildError::ArgsMissing)?;
+        let mut args = inner.args.ok_or(TaskBuildError::ArgsMissing)?;
+        if let Some(encryption) = inner.encryption {
+            let binding = ArgsBinding::new(
+                inner.queue.as_deref().unwrap_or(DEFAULT_QUEUE),
+                &kind,
+                &id,
+                inner.dedupe.as_ref().map(|(key, _)| key.as_str()),
+            );
+            args = encryption.encrypt(args, &inner.plain_fields, &binding)?;
+        }
+
+        let mut payload = None;
+        if let Some(threshold) = inner.offload_above {
+            (args, payload) = Payload::offload(args, threshold, &inner.plain_fields);
+        }
+
+        let opaque = ArgsEncryption::is_encrypted(&args) || payload.is_some();
+        if inner.merge_args == MergeArgs::Merge && inner.dedupe.is_some() && opaque {
+            return Err(TaskBuildError::MergeOpaqueArgs);
+        }
 
         let task = NewTask {
+            id,
             scheduled_at: inner.scheduled_at,
             max_attempts: inner.max_attempts.unwrap_or(3),
             priority: inner.priority.unwrap_or(3),
@@ -205,12 +489,136 @@ impl TaskBuilder {
             args,
             depends_on: inner.depends_on,
             dependend_id: inner.dependend_id,
+            fairness_key: inner.fairness_key,
+            timeout_ms: inner.timeout.map(|timeout| timeout.as_millis() as i64),
+            periodic_name: inner.periodic_name,
+            args_version: inner.args_version,
+            expires_at: inner.expires_at,
+            payload,
+            dedupe: inner.dedupe.map(|(key, mode)| Dedupe {
+                key,
+                mode,
+                merge: inner.merge_args,
+            }),
         };
 
         Ok(task)
     }
 }
 
+/// A state change of a task, stored in `chang.task_history`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TaskHistory {
+    pub id: Uuid,
+    pub task_id: Uuid,
+    pub from_state: TaskState,
+    pub to_state: TaskState,
+    pub comment: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// An error a run of a task failed with, stored in `chang.task_error`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TaskErrorRecord {
+    pub id: Uuid,
+    pub task_id: Uuid,
+    pub error: Option<String>,
+    /// Set with [`crate::task::Retry::details`].
+    pub details: Option<serde_json::Value>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// A cap on the running tasks of a kind across all runners, stored in
+/// `chang.kind_limits`. Claims skip the kind while `running` has reached
+/// `max_running`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KindLimit {
+    pub kind: String,
+    pub max_running: i32,
+    pub running: i32,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// A schedule stored in `chang.periodic_tasks`, the scheduler reads these
+/// on every run, so changes take effect without a restart.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeriodicTask {
+    pub id: Uuid,
+    pub queue: String,
+    pub name: String,
+    pub kind: String,
+    pub schedule: String,
+    pub args: serde_json::Value,
+    pub priority: Option<i16>,
+    pub max_attempts: Option<i16>,
+    pub tags: Vec<String>,
+    pub timeout_ms: Option<i64>,
+    pub misfire_policy: MisfirePolicy,
+    pub args_version: i16,
+    /// End of the window the scheduler has enqueued tasks for.
+    pub scheduled_until: Option<DateTime<Utc>>,
+    pub paused: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl PeriodicTask {
+    pub fn job(&self) -> Result<PeriodicJob, PeriodicScheduleError> {
+        Ok(PeriodicJob {
+            name: self.name.clone(),
+            kind: self.kind.clone(),
+            schedule: self.schedule.parse()?,
+            args: self.args.clone(),
+            priority: self.priority,
+            max_attempts: self.max_attempts,
+            tags: self.tags.clone(),
+            timeout: self
+                .timeout_ms
+                .map(|timeout_ms| Duration::from_millis(timeout_ms.max(0) as u64)),
+            misfire_policy: self.misfire_policy,
+            args_version: self.args_version,
+        })
+    }
+}
+
+const COPY_TASKS: &str = "copy chang.tasks(id, max_attempts, scheduled_at, priority, args, attempted_by, kind, queue, tags, depends_on, dependend_id, fairness_key, timeout_ms, periodic_name, args_version, expires_at) from stdin (format binary)";
+const COPY_PAYLOADS: &str =
+    "copy chang.payloads(id, source, payload, size) from stdin (format binary)";
+
+fn encode_tasks(tasks: Vec<NewTask>) -> Vec<(&'static str, BinaryCopy)> {
+    let now = Utc::now();
+    let mut payloads = BinaryCopy::new();
+    let mut rows = BinaryCopy::new();
+
+    for task in tasks.iter() {
+        if let Some(payload) = &task.payload {
+            payloads.row(&[&payload.id, &"task", &payload.payload, &payload.size]);
+        }
+
+        rows.row(&[
+            &task.id,
+            &task.max_attempts,
+            &task.scheduled_at.unwrap_or(now),
+            &task.priority,
+            &task.args,
+            &TextArray(&task.attempted_by, TEXT_OID),
+            &task.kind,
+            &task.queue.as_deref().unwrap_or(DEFAULT_QUEUE),
+            &TextArray(&task.tags, VARCHAR_OID),
+            &task.depends_on,
+            &task.dependend_id,
+            &task.fairness_key,
+            &task.timeout_ms,
+            &task.periodic_name,
+            &task.args_version,
+            &task.expires_at,
+        ]);
+    }
+
+    vec![(COPY_PAYLOADS, payloads), (COPY_TASKS, rows)]
+}
+
 pub struct TaskService;
 
 impl TaskService {
@@ -226,7 +634,20 @@ impl TaskService {
             task.queue,
             &task.tags,
             task.depends_on,
-            task.dependend_id
+            task.dependend_id,
+            task.fairness_key,
+            task.timeout_ms,
+            task.periodic_name,
+            task.args_version,
+            task.payload.as_ref().map(|payload| payload.id),
+            task.payload.as_ref().map(|payload| &payload.payload),
+            task.payload.as_ref().map(|payload| payload.size),
+            task.expires_at,
+            task.dedupe.as_ref().map(|dedupe| &dedupe.key),
+            task.dedupe.as_ref().map(|dedupe| dedupe.mode.name()),
+            task.dedupe.as_ref().map(|dedupe| dedupe.mode.millis()),
+            task.dedupe.as_ref().map(|dedupe| dedupe.merge.name()),
+            task.id
         )
         .fetch_one(db)
         .await?;
@@ -238,8 +659,18 @@ impl TaskService {
         db: impl PgExecutor<'_>,
         tasks: &[NewTask],
     ) -> crate::error::Result<Vec<Uuid>> {
-        let data = serde_json::to_value(&tasks)?;
-        let rows = sqlx::query_file!("src/db/tasks/sql/batch_insert.sql", data)
+        if tasks.iter().any(|task| task.dedupe.is_some()) {
+            return Err(crate::error::Error::BulkDedupe);
+        }
+
+        let data = serde_json::to_value(tasks)?;
+        let payloads = serde_json::to_value(
+            tasks
+                .iter()
+                .filter_map(|task| task.payload.as_ref())
+                .collect::<Vec<_>>(),
+        )?;
+        let rows = sqlx::query_file!("src/db/tasks/sql/batch_insert.sql", data, payloads)
             .fetch_all(db)
             .await?;
 
@@ -247,6 +678,35 @@ impl TaskService {
         Ok(ids)
     }
 
+    /// Streams tasks into `chang.tasks` with a binary `copy`, for backfills
+    /// too large for [`TaskService::batch_insert`]. Every `chunk_size` tasks
+    /// are committed on their own and reported to `on_progress`, wrap an
+    /// iterator with `futures::stream::iter`. Unlike `batch_insert`, duplicate
+    /// tasks of a periodic job aren't skipped but fail their chunk. The copy
+    /// stops at the first debounced or throttled task, the tasks before it
+    /// stay committed.
+    pub async fn copy_insert<S>(
+        db: &PgPool,
+        tasks: S,
+        chunk_size: usize,
+        on_progress: impl FnMut(CopyProgress),
+    ) -> crate::error::Result<CopyProgress>
+    where
+        S: Stream<Item = NewTask>,
+    {
+        let mut dedupe = false;
+        let tasks = tasks.take_while(|task| {
+            dedupe = task.dedupe.is_some();
+            future::ready(!dedupe)
+        });
+        let progress = copy_chunks(db, tasks, chunk_size, encode_tasks, on_progress).await?;
+
+        if dedupe {
+            return Err(crate::error::Error::BulkDedupe);
+        }
+        Ok(progress)
+    }
+
     pub async fn get_tasks_by_kind(
         db: impl PgExecutor<'_>,
         kind: &str,
@@ -265,14 +725,83 @@ impl TaskService {
         Ok(rows)
     }
 
+    /// Checks for tasks of `kind` that haven't finished yet, without
+    /// claiming them.
+    pub async fn has_pending_tasks(
+        db: impl PgExecutor<'_>,
+        kind: &str,
+        queue: &str,
+    ) -> sqlx::Result<bool> {
+        let row = sqlx::query_file!("src/db/tasks/sql/has_pending_tasks.sql", kind, queue)
+            .fetch_one(db)
+            .await?;
+
+        Ok(row.exists)
+    }
+
+    /// Checks for tasks of `kind` other than `except` that wait to be
+    /// claimed.
+    pub async fn has_queued_tasks(
+        db: impl PgExecutor<'_>,
+        kind: &str,
+        queue: &str,
+        except: &Uuid,
+    ) -> sqlx::Result<bool> {
+        let row = sqlx::query_file!("src/db/tasks/sql/has_queued_tasks.sql", kind, queue, except)
+            .fetch_one(db)
+            .await?;
+
+        Ok(row.exists)
+    }
+
     pub async fn get_tasks(
         db: impl PgExecutor<'_>,
         queue: &str,
         limit: i64,
+        excluded_kinds: &[String],
     ) -> sqlx::Result<Vec<Task>> {
-        let rows = sqlx::query_file_as!(Task, "src/db/tasks/sql/get_tasks.sql", queue, limit)
-            .fetch_all(db)
+        let rows = sqlx::query_file_as!(
+            Task,
+            "src/db/tasks/sql/get_tasks.sql",
+            queue,
+            limit,
+            excluded_kinds
+        )
+        .fetch_all(db)
+        .await?;
+        Ok(rows)
+    }
+
+    /// Counts up to `limit` tasks of `kind` that are due, without claiming
+    /// them.
+    pub async fn count_due_tasks(
+        db: impl PgExecutor<'_>,
+        queue: &str,
+        kind: &str,
+        limit: i64,
+    ) -> sqlx::Result<i64> {
+        let row = sqlx::query_file!("src/db/tasks/sql/count_due_tasks.sql", queue, kind, limit)
+            .fetch_one(db)
             .await?;
+
+        Ok(row.due)
+    }
+
+    pub async fn get_batch_tasks(
+        db: impl PgExecutor<'_>,
+        queue: &str,
+        kind: &str,
+        limit: i64,
+    ) -> sqlx::Result<Vec<Task>> {
+        let rows = sqlx::query_file_as!(
+            Task,
+            "src/db/tasks/sql/get_batch_tasks.sql",
+            queue,
+            kind,
+            limit
+        )
+        .fetch_all(db)
+        .await?;
         Ok(rows)
     }
 
@@ -280,12 +809,35 @@ impl TaskService {
         db: impl PgExecutor<'_>,
         queue: &str,
         limit: i64,
+        excluded_kinds: &[String],
     ) -> sqlx::Result<Vec<Task>> {
         let rows = sqlx::query_file_as!(
             Task,
             "src/db/tasks/sql/get_priority_tasks.sql",
             queue,
-            limit
+            limit,
+            excluded_kinds
+        )
+        .fetch_all(db)
+        .await?;
+
+        Ok(rows)
+    }
+
+    pub async fn get_aging_priority_tasks(
+        db: impl PgExecutor<'_>,
+        queue: &str,
+        limit: i64,
+        rate: f64,
+        excluded_kinds: &[String],
+    ) -> sqlx::Result<Vec<Task>> {
+        let rows = sqlx::query_file_as!(
+            Task,
+            "src/db/tasks/sql/get_aging_priority_tasks.sql",
+            queue,
+            limit,
+            rate,
+            excluded_kinds
         )
         .fetch_all(db)
         .await?;
@@ -293,6 +845,74 @@ impl TaskService {
         Ok(rows)
     }
 
+    /// Takes the oldest tasks of the keys whose oldest task waited the
+    /// longest, round robin. With `max_per_key` only these keys are locked
+    /// before the claim, so concurrent claims count the tasks the other
+    /// claims started.
+    pub async fn get_fair_tasks(
+        db: &PgPool,
+        queue: &str,
+        limit: i64,
+        max_per_key: Option<i64>,
+        excluded_kinds: &[String],
+    ) -> sqlx::Result<Vec<Task>> {
+        let mut tx = db.begin().await?;
+        let rows =
+            TaskService::claim_fair_tasks(&mut tx, queue, limit, max_per_key, excluded_kinds)
+                .await?;
+        tx.commit().await?;
+
+        Ok(rows)
+    }
+
+    async fn claim_fair_tasks(
+        tx: &mut PgConnection,
+        queue: &str,
+        limit: i64,
+        max_per_key: Option<i64>,
+        excluded_kinds: &[String],
+    ) -> sqlx::Result<Vec<Task>> {
+        // discarded up front, so the keys of expired tasks are left out
+        sqlx::query_file!("src/db/tasks/sql/discard_expired.sql", queue)
+            .fetch_one(&mut *tx)
+            .await?;
+
+        let keys = sqlx::query_file!(
+            "src/db/tasks/sql/get_fairness_keys.sql",
+            queue,
+            limit,
+            max_per_key,
+            excluded_kinds
+        )
+        .fetch_all(&mut *tx)
+        .await?
+        .into_iter()
+        .map(|row| row.token)
+        .collect::<Vec<String>>();
+
+        if keys.is_empty() {
+            return Ok(vec![]);
+        }
+
+        if max_per_key.is_some() {
+            sqlx::query_file!("src/db/tasks/sql/lock_fairness_keys.sql", queue, &keys)
+                .fetch_one(&mut *tx)
+                .await?;
+        }
+
+        sqlx::query_file_as!(
+            Task,
+            "src/db/tasks/sql/get_fair_tasks.sql",
+            queue,
+            limit,
+            max_per_key,
+            excluded_kinds,
+            &keys
+        )
+        .fetch_all(&mut *tx)
+        .await
+    }
+
     pub async fn get_sheduled_task(
         db: impl PgExecutor<'_>,
         task_id: &Uuid,
@@ -310,39 +930,1565 @@ impl TaskService {
         Ok(())
     }
 
+    /// Puts a running task back in the queue at `scheduled_at` without
+    /// counting the current run as an attempt, fails with `RowNotFound` if
+    /// the task isn't running.
+    pub async fn snooze(
+        db: impl PgExecutor<'_>,
+        task_id: &Uuid,
+        scheduled_at: &DateTime<Utc>,
+        args: Option<serde_json::Value>,
+    ) -> sqlx::Result<()> {
+        sqlx::query_file!("src/db/tasks/sql/snooze.sql", task_id, scheduled_at, args)
+            .fetch_optional(db)
+            .await?
+            .ok_or(sqlx::Error::RowNotFound)?;
+
+        Ok(())
+    }
+
+    /// Takes the session level advisory lock for `name` if it's free. The
+    /// lock belongs to the connection, see [`crate::task::AdvisoryLock`].
+    pub async fn try_advisory_lock(db: impl PgExecutor<'_>, name: &str) -> sqlx::Result<bool> {
+        let row = sqlx::query_file!("src/db/tasks/sql/try_advisory_lock.sql", name)
+            .fetch_one(db)
+            .await?;
+
+        Ok(row.locked)
+    }
+
+    pub async fn advisory_unlock(db: impl PgExecutor<'_>, name: &str) -> sqlx::Result<bool> {
+        let row = sqlx::query_file!("src/db/tasks/sql/advisory_unlock.sql", name)
+            .fetch_one(db)
+            .await?;
+
+        Ok(row.unlocked)
+    }
+
+    /// Stores the progress of a running task and notifies
+    /// `chang_task_progress`. Returns `false` if the task isn't running.
+    pub async fn update_progress(
+        db: impl PgExecutor<'_>,
+        task_id: &Uuid,
+        progress: Option<f32>,
+        message: Option<&str>,
+        data: Option<&serde_json::Value>,
+    ) -> sqlx::Result<bool> {
+        let row = sqlx::query_file!(
+            "src/db/tasks/sql/update_progress.sql",
+            task_id,
+            progress,
+            message,
+            data
+        )
+        .fetch_one(db)
+        .await?;
+
+        Ok(row.updated > 0)
+    }
+
     pub async fn get_task(db: impl PgExecutor<'_>, task_id: &Uuid) -> sqlx::Result<Option<Task>> {
         sqlx::query_file_as!(Task, "src/db/tasks/sql/get_task.sql", task_id)
             .fetch_optional(db)
             .await
     }
 
-    pub async fn get_all(db: impl PgExecutor<'_>, ids: &Vec<Uuid>) -> sqlx::Result<Vec<Task>> {
-        sqlx::query_file_as!(Task, "src/db/tasks/sql/get_all.sql", ids)
+    /// Seals the data keys of encrypted args with the current key of
+    /// `encryption`, in batches of `batch_size`, so keys that were rotated
+    /// out can be dropped. Only tasks that may still run are touched.
+    /// Returns the number of updated tasks.
+    pub async fn rewrap_args(
+        db: &PgPool,
+        encryption: &ArgsEncryption,
+        batch_size: i64,
+    ) -> crate::error::Result<u64> {
+        let key_id = encryption.current_key_id();
+        let mut updated = 0;
+
+        loop {
+            let rows = sqlx::query_file!(
+                "src/db/tasks/sql/get_encrypted_args.sql",
+                key_id,
+                batch_size
+            )
             .fetch_all(db)
-            .await
+            .await?;
+
+            if rows.is_empty() {
+                return Ok(updated);
+            }
+
+            let mut tx = db.begin().await?;
+            for row in rows {
+                let binding =
+                    ArgsBinding::new(&row.queue, &row.kind, &row.id, row.dedupe_key.as_deref());
+                if let Some(args) = encryption.rewrap(&row.args, &binding)? {
+                    sqlx::query_file!("src/db/tasks/sql/set_args.sql", row.id, args)
+                        .execute(&mut *tx)
+                        .await?;
+                    updated += 1;
+                }
+            }
+            tx.commit().await?;
+        }
     }
 
-    pub async fn failed(db: impl PgExecutor<'_>, task_id: &Uuid, error: &str) -> sqlx::Result<()> {
-        sqlx::query_file!("src/db/tasks/sql/failed.sql", task_id, error)
+    /// Deletes offloaded args no task refers to anymore, returns the number
+    /// of deleted payloads.
+    pub async fn delete_orphaned_payloads(db: impl PgExecutor<'_>) -> sqlx::Result<u64> {
+        let result = sqlx::query_file!("src/db/tasks/sql/delete_orphaned_payloads.sql")
             .execute(db)
             .await?;
 
-        Ok(())
+        Ok(result.rows_affected())
     }
 
-    pub async fn set_state(
+    pub async fn get_all(db: impl PgExecutor<'_>, ids: &Vec<Uuid>) -> sqlx::Result<Vec<Task>> {
+        sqlx::query_file_as!(Task, "src/db/tasks/sql/get_all.sql", ids)
+            .fetch_all(db)
+            .await
+    }
+
+    /// The state changes of the task, oldest first.
+    pub async fn history(
         db: impl PgExecutor<'_>,
         task_id: &Uuid,
-        state: &TaskState,
-    ) -> sqlx::Result<()> {
-        sqlx::query_file!(
-            "src/db/tasks/sql/set_state.sql",
-            task_id,
-            state as &TaskState
+    ) -> sqlx::Result<Vec<TaskHistory>> {
+        sqlx::query_file_as!(
+            TaskHistory,
+            "src/db/tasks/sql/get_task_history.sql",
+            task_id
         )
-        .execute(db)
-        .await?;
+        .fetch_all(db)
+        .await
+    }
 
-        Ok(())
+    /// The errors the task failed with, oldest first.
+    pub async fn errors(
+        db: impl PgExecutor<'_>,
+        task_id: &Uuid,
+    ) -> sqlx::Result<Vec<TaskErrorRecord>> {
+        sqlx::query_file_as!(
+            TaskErrorRecord,
+            "src/db/tasks/sql/get_task_errors.sql",
+            task_id
+        )
+        .fetch_all(db)
+        .await
+    }
+
+    /// The runs of the task with their errors, see [`TaskTimeline`].
+    pub async fn timeline(db: &PgPool, task_id: &Uuid) -> sqlx::Result<TaskTimeline> {
+        let history = TaskService::history(db, task_id).await?;
+        let errors = TaskService::errors(db, task_id).await?;
+
+        Ok(TaskTimeline::new(task_id, history, errors))
+    }
+
+    pub async fn failed(db: impl PgExecutor<'_>, task_id: &Uuid, error: &str) -> sqlx::Result<()> {
+        TaskService::fail(db, task_id, &Failure::new(error)).await
+    }
+
+    /// Moves the task to `retryable`, or to `discarded` if the failure is
+    /// permanent or the task used up its attempts.
+    pub async fn fail(
+        db: impl PgExecutor<'_>,
+        task_id: &Uuid,
+        failure: &Failure,
+    ) -> sqlx::Result<()> {
+        sqlx::query_file!(
+            "src/db/tasks/sql/failed.sql",
+            task_id,
+            failure.error,
+            failure.details,
+            failure.permanent,
+            failure.retry_after.map(|delay| delay.as_millis() as i64)
+        )
+        .execute(db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Stores a periodic job, returns `None` if the queue already has a job
+    /// with the same name.
+    pub async fn add_periodic_task(
+        db: impl PgExecutor<'_>,
+        queue: &str,
+        job: &PeriodicJob,
+    ) -> sqlx::Result<Option<PeriodicTask>> {
+        sqlx::query_file_as!(
+            PeriodicTask,
+            "src/db/tasks/sql/add_periodic_task.sql",
+            queue,
+            job.name,
+            job.kind,
+            job.schedule.to_string(),
+            job.args,
+            job.priority,
+            job.max_attempts,
+            &job.tags,
+            job.timeout.map(|timeout| timeout.as_millis() as i64),
+            job.misfire_policy as MisfirePolicy,
+            job.args_version
+        )
+        .fetch_optional(db)
+        .await
+    }
+
+    /// Replaces the stored job with the same name. Runs it enqueued for
+    /// later are cancelled, so the scheduler enqueues the new job from now.
+    pub async fn update_periodic_task(
+        db: impl PgExecutor<'_>,
+        queue: &str,
+        job: &PeriodicJob,
+    ) -> sqlx::Result<Option<PeriodicTask>> {
+        sqlx::query_file_as!(
+            PeriodicTask,
+            "src/db/tasks/sql/update_periodic_task.sql",
+            queue,
+            job.name,
+            job.kind,
+            job.schedule.to_string(),
+            job.args,
+            job.priority,
+            job.max_attempts,
+            &job.tags,
+            job.timeout.map(|timeout| timeout.as_millis() as i64),
+            job.misfire_policy as MisfirePolicy,
+            job.args_version
+        )
+        .fetch_optional(db)
+        .await
+    }
+
+    /// Paused schedules are kept but no tasks are scheduled for them, runs
+    /// that are already enqueued are cancelled.
+    pub async fn pause_periodic_task(
+        db: impl PgExecutor<'_>,
+        queue: &str,
+        name: &str,
+    ) -> sqlx::Result<Option<PeriodicTask>> {
+        sqlx::query_file_as!(
+            PeriodicTask,
+            "src/db/tasks/sql/set_periodic_task_paused.sql",
+            queue,
+            name,
+            true
+        )
+        .fetch_optional(db)
+        .await
+    }
+
+    pub async fn resume_periodic_task(
+        db: impl PgExecutor<'_>,
+        queue: &str,
+        name: &str,
+    ) -> sqlx::Result<Option<PeriodicTask>> {
+        sqlx::query_file_as!(
+            PeriodicTask,
+            "src/db/tasks/sql/set_periodic_task_paused.sql",
+            queue,
+            name,
+            false
+        )
+        .fetch_optional(db)
+        .await
+    }
+
+    /// Cancels the enqueued runs of the schedule, returns `false` if there
+    /// was no schedule to delete.
+    pub async fn delete_periodic_task(
+        db: impl PgExecutor<'_>,
+        queue: &str,
+        name: &str,
+    ) -> sqlx::Result<bool> {
+        let row = sqlx::query_file!("src/db/tasks/sql/delete_periodic_task.sql", queue, name)
+            .fetch_optional(db)
+            .await?;
+
+        Ok(row.is_some())
+    }
+
+    pub async fn get_periodic_task(
+        db: impl PgExecutor<'_>,
+        queue: &str,
+        name: &str,
+    ) -> sqlx::Result<Option<PeriodicTask>> {
+        sqlx::query_file_as!(
+            PeriodicTask,
+            "src/db/tasks/sql/get_periodic_task.sql",
+            queue,
+            name
+        )
+        .fetch_optional(db)
+        .await
+    }
+
+    /// Marks the jobs as scheduled up to `scheduled_until`.
+    pub async fn set_periodic_tasks_scheduled_until(
+        db: impl PgExecutor<'_>,
+        queue: &str,
+        names: &[String],
+        scheduled_until: &DateTime<Utc>,
+    ) -> sqlx::Result<()> {
+        sqlx::query_file!(
+            "src/db/tasks/sql/set_periodic_tasks_scheduled_until.sql",
+            queue,
+            names,
+            scheduled_until
+        )
+        .execute(db)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Enqueues `job` for every slot in `from..=to`, e.g. to reprocess a
+    /// period. Slots with a pending task are skipped.
+    pub async fn backfill_periodic_task(
+        db: impl PgExecutor<'_>,
+        queue: &str,
+        job: &PeriodicJob,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+    ) -> crate::error::Result<Vec<Uuid>> {
+        let tasks = job
+            .slots(from, to)
+            .map(|scheduled_at| job.task_builder(&scheduled_at, queue).build())
+            .collect::<Result<Vec<NewTask>, TaskBuildError>>()?;
+
+        if tasks.is_empty() {
+            return Ok(vec![]);
+        }
+
+        TaskService::batch_insert(db, &tasks).await
+    }
+
+    pub async fn get_periodic_tasks(
+        db: impl PgExecutor<'_>,
+        queue: &str,
+    ) -> sqlx::Result<Vec<PeriodicTask>> {
+        sqlx::query_file_as!(
+            PeriodicTask,
+            "src/db/tasks/sql/get_periodic_tasks.sql",
+            queue
+        )
+        .fetch_all(db)
+        .await
+    }
+
+    /// Limits the running tasks of `kind`, unless the kind has a limit
+    /// already.
+    pub async fn add_kind_limit(
+        db: impl PgExecutor<'_>,
+        kind: &str,
+        max_running: i32,
+    ) -> sqlx::Result<Option<KindLimit>> {
+        sqlx::query_file_as!(
+            KindLimit,
+            "src/db/tasks/sql/add_kind_limit.sql",
+            kind,
+            max_running
+        )
+        .fetch_optional(db)
+        .await
+    }
+
+    /// Creates or changes the limit of `kind`, takes effect with the next
+    /// claim.
+    pub async fn set_kind_limit(
+        db: impl PgExecutor<'_>,
+        kind: &str,
+        max_running: i32,
+    ) -> sqlx::Result<KindLimit> {
+        sqlx::query_file_as!(
+            KindLimit,
+            "src/db/tasks/sql/set_kind_limit.sql",
+            kind,
+            max_running
+        )
+        .fetch_one(db)
+        .await
+    }
+
+    pub async fn delete_kind_limit(db: impl PgExecutor<'_>, kind: &str) -> sqlx::Result<bool> {
+        let row = sqlx::query_file!("src/db/tasks/sql/delete_kind_limit.sql", kind)
+            .fetch_optional(db)
+            .await?;
+
+        Ok(row.is_some())
+    }
+
+    pub async fn get_kind_limits(db: impl PgExecutor<'_>) -> sqlx::Result<Vec<KindLimit>> {
+        sqlx::query_file_as!(KindLimit, "src/db/tasks/sql/get_kind_limits.sql")
+            .fetch_all(db)
+            .await
+    }
+
+    pub async fn set_state(
+        db: impl PgExecutor<'_>,
+        task_id: &Uuid,
+        state: &TaskState,
+    ) -> sqlx::Result<()> {
+        sqlx::query_file!(
+            "src/db/tasks/sql/set_state.sql",
+            task_id,
+            state as &TaskState
+        )
+        .execute(db)
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::migration;
+    use crate::db::payloads::PayloadService;
+    use crate::task::{schedule_periodic_job, FromTaskContext, PeriodicHorizon};
+    use crate::utils;
+    use chrono::Duration;
+
+    #[tokio::test]
+    async fn aging_lets_waiting_tasks_overtake_higher_priority() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::tasks(&prepare.pool).await;
+
+        let waiting_since = Utc::now() - Duration::hours(1);
+        let waiting = Task::builder()
+            .kind("waiting")
+            .args(serde_json::Value::Null)
+            .priority(1)
+            .scheduled_at(&waiting_since)
+            .queue(&prepare.name)
+            .build()
+            .unwrap()
+            .insert(&prepare.pool)
+            .await
+            .unwrap();
+
+        let urgent = Task::builder()
+            .kind("urgent")
+            .args(serde_json::Value::Null)
+            .priority(1000)
+            .queue(&prepare.name)
+            .build()
+            .unwrap()
+            .insert(&prepare.pool)
+            .await
+            .unwrap();
+
+        let tasks =
+            TaskService::get_aging_priority_tasks(&prepare.pool, &prepare.name, 1, 1.0, &[])
+                .await
+                .unwrap();
+
+        assert_eq!(tasks.first().map(|task| task.id), Some(waiting));
+
+        let tasks = TaskService::get_priority_tasks(&prepare.pool, &prepare.name, 1, &[])
+            .await
+            .unwrap();
+
+        assert_eq!(tasks.first().map(|task| task.id), Some(urgent));
+
+        utils::test::cleanup(prepare).await;
+    }
+
+    #[tokio::test]
+    async fn fair_spreads_claims_across_keys() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::tasks(&prepare.pool).await;
+
+        let earlier = Utc::now() - Duration::minutes(5);
+        for _ in 0..3 {
+            insert_keyed_task(&prepare, "busy", &earlier).await;
+        }
+        let quiet = insert_keyed_task(&prepare, "quiet", &Utc::now()).await;
+
+        let tasks = TaskService::get_fair_tasks(&prepare.pool, &prepare.name, 2, None, &[])
+            .await
+            .unwrap();
+
+        let mut keys = tasks
+            .iter()
+            .filter_map(|task| task.fairness_key.clone())
+            .collect::<Vec<_>>();
+        keys.sort();
+
+        assert_eq!(keys, vec!["busy".to_string(), "quiet".to_string()]);
+        assert!(tasks.iter().any(|task| task.id == quiet));
+
+        utils::test::cleanup(prepare).await;
+    }
+
+    #[tokio::test]
+    async fn fair_treats_tasks_without_key_as_one_key() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::tasks(&prepare.pool).await;
+
+        let earlier = Utc::now() - Duration::minutes(5);
+        for _ in 0..3 {
+            Task::builder()
+                .kind("keyed")
+                .args(serde_json::Value::Null)
+                .scheduled_at(&earlier)
+                .queue(&prepare.name)
+                .build()
+                .unwrap()
+                .insert(&prepare.pool)
+                .await
+                .unwrap();
+        }
+        let named_none = insert_keyed_task(&prepare, "none", &Utc::now()).await;
+
+        let tasks = TaskService::get_fair_tasks(&prepare.pool, &prepare.name, 2, Some(1), &[])
+            .await
+            .unwrap();
+
+        assert_eq!(tasks.len(), 2);
+        assert!(tasks.iter().any(|task| task.id == named_none));
+        assert!(tasks.iter().any(|task| task.fairness_key.is_none()));
+
+        utils::test::cleanup(prepare).await;
+    }
+
+    #[tokio::test]
+    async fn fair_respects_max_per_key() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::tasks(&prepare.pool).await;
+
+        let earlier = Utc::now() - Duration::minutes(5);
+        for _ in 0..3 {
+            insert_keyed_task(&prepare, "busy", &earlier).await;
+        }
+
+        let tasks = TaskService::get_fair_tasks(&prepare.pool, &prepare.name, 10, Some(2), &[])
+            .await
+            .unwrap();
+        assert_eq!(tasks.len(), 2);
+
+        let tasks = TaskService::get_fair_tasks(&prepare.pool, &prepare.name, 10, Some(2), &[])
+            .await
+            .unwrap();
+        assert!(tasks.is_empty());
+
+        utils::test::cleanup(prepare).await;
+    }
+
+    #[tokio::test]
+    async fn fair_respects_max_per_key_across_concurrent_claims() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::tasks(&prepare.pool).await;
+
+        let earlier = Utc::now() - Duration::minutes(5);
+        Task::builder()
+            .kind("batched")
+            .args(serde_json::Value::Null)
+            .fairness_key("busy")
+            .scheduled_at(&(earlier - Duration::minutes(1)))
+            .queue(&prepare.name)
+            .build()
+            .unwrap()
+            .insert(&prepare.pool)
+            .await
+            .unwrap();
+        for _ in 0..3 {
+            insert_keyed_task(&prepare, "busy", &earlier).await;
+        }
+
+        // the first claim skips a kind the second one takes, and is still
+        // open while the second one runs
+        let mut tx = prepare.pool.begin().await.unwrap();
+        let excluded = vec!["batched".to_string()];
+        let first = TaskService::claim_fair_tasks(&mut tx, &prepare.name, 10, Some(2), &excluded)
+            .await
+            .unwrap();
+        assert_eq!(first.len(), 2);
+
+        // the test pool has a single connection, which the first claim holds
+        let pool = PgPool::connect(&prepare.connection_string.to_string())
+            .await
+            .unwrap();
+        let second = tokio::spawn({
+            let pool = pool.clone();
+            let queue = prepare.name.clone();
+            async move { TaskService::get_fair_tasks(&pool, &queue, 10, Some(2), &[]).await }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        tx.commit().await.unwrap();
+
+        let second = second.await.unwrap().unwrap();
+        assert!(second.is_empty());
+        pool.close().await;
+
+        utils::test::cleanup(prepare).await;
+    }
+
+    #[tokio::test]
+    async fn snoozes_only_running_tasks() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::tasks(&prepare.pool).await;
+
+        let id = Task::builder()
+            .kind("poll_status")
+            .args(serde_json::Value::Null)
+            .scheduled_at(&(Utc::now() - Duration::minutes(1)))
+            .queue(&prepare.name)
+            .build()
+            .unwrap()
+            .insert(&prepare.pool)
+            .await
+            .unwrap();
+
+        let later = Utc::now() + Duration::minutes(5);
+        let res = TaskService::snooze(&prepare.pool, &id, &later, None).await;
+        assert!(matches!(res, Err(sqlx::Error::RowNotFound)));
+
+        TaskService::get_tasks(&prepare.pool, &prepare.name, 1, &[])
+            .await
+            .unwrap();
+        TaskService::complete(&prepare.pool, &id).await.unwrap();
+
+        let res = TaskService::snooze(&prepare.pool, &id, &later, None).await;
+        assert!(matches!(res, Err(sqlx::Error::RowNotFound)));
+
+        let task = TaskService::get_task(&prepare.pool, &id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(task.state, TaskState::Completed);
+        assert_eq!(task.attempt, 1);
+
+        let history = TaskService::history(&prepare.pool, &id).await.unwrap();
+        assert_eq!(history.len(), 2);
+
+        utils::test::cleanup(prepare).await;
+    }
+
+    #[tokio::test]
+    async fn every_claim_discards_expired_tasks() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::tasks(&prepare.pool).await;
+
+        let pool = &prepare.pool;
+        let queue = &prepare.name;
+        for strategy in ["fcfs", "priority", "aging", "fair", "batch"] {
+            let expired = Task::builder()
+                .kind("send_otp")
+                .args(serde_json::Value::Null)
+                .scheduled_at(&(Utc::now() - Duration::minutes(10)))
+                .expires_at(&(Utc::now() - Duration::minutes(1)))
+                .queue(queue)
+                .build()
+                .unwrap()
+                .insert(pool)
+                .await
+                .unwrap();
+
+            let tasks = match strategy {
+                "fcfs" => TaskService::get_tasks(pool, queue, 10, &[]).await,
+                "priority" => TaskService::get_priority_tasks(pool, queue, 10, &[]).await,
+                "aging" => TaskService::get_aging_priority_tasks(pool, queue, 10, 1.0, &[]).await,
+                "fair" => TaskService::get_fair_tasks(pool, queue, 10, None, &[]).await,
+                _ => TaskService::get_batch_tasks(pool, queue, "send_otp", 10).await,
+            }
+            .unwrap();
+            assert!(tasks.is_empty(), "{} claimed an expired task", strategy);
+
+            let task = TaskService::get_task(pool, &expired)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(task.state, TaskState::Discarded, "{}", strategy);
+        }
+
+        utils::test::cleanup(prepare).await;
+    }
+
+    #[tokio::test]
+    async fn discards_expired_tasks() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::tasks(&prepare.pool).await;
+
+        let insert = |expires_at: DateTime<Utc>| {
+            Task::builder()
+                .kind("send_otp")
+                .args(serde_json::Value::Null)
+                .scheduled_at(&(Utc::now() - Duration::minutes(10)))
+                .expires_at(&expires_at)
+                .queue(&prepare.name)
+                .build()
+                .unwrap()
+                .insert(&prepare.pool)
+        };
+        let expired = insert(Utc::now() - Duration::minutes(1)).await.unwrap();
+        let pending = insert(Utc::now() + Duration::minutes(1)).await.unwrap();
+
+        let tasks = TaskService::get_tasks(&prepare.pool, &prepare.name, 10, &[])
+            .await
+            .unwrap();
+        assert_eq!(tasks.len(), 1);
+        assert_eq!(tasks[0].id, pending);
+
+        let task = TaskService::get_task(&prepare.pool, &expired)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(task.state, TaskState::Discarded);
+
+        let comment: String =
+            sqlx::query_scalar("select comment from chang.task_history where task_id = $1")
+                .bind(expired)
+                .fetch_one(&prepare.pool)
+                .await
+                .unwrap();
+        assert_eq!(comment, "expired");
+
+        utils::test::cleanup(prepare).await;
+    }
+
+    #[tokio::test]
+    async fn debounces_inserts() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::tasks(&prepare.pool).await;
+
+        let insert = |key: &str, args: serde_json::Value| {
+            Task::builder()
+                .kind("reindex_user")
+                .args(args)
+                .debounce(key, std::time::Duration::from_secs(60))
+                .merge_args(MergeArgs::Merge)
+                .queue(&prepare.name)
+                .build()
+                .unwrap()
+                .insert(&prepare.pool)
+        };
+
+        let first = insert("user-1", serde_json::json!({ "name": true }))
+            .await
+            .unwrap();
+        let second = insert("user-1", serde_json::json!({ "email": true }))
+            .await
+            .unwrap();
+        let other = insert("user-2", serde_json::json!({})).await.unwrap();
+        assert_eq!(first, second);
+        assert_ne!(first, other);
+
+        let task = TaskService::get_task(&prepare.pool, &first)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            task.args,
+            serde_json::json!({ "name": true, "email": true })
+        );
+        assert_eq!(task.dedupe_key.as_deref(), Some("user-1"));
+        assert!(task.scheduled_at.unwrap() > Utc::now() + Duration::seconds(50));
+
+        utils::test::cleanup(prepare).await;
+    }
+
+    #[tokio::test]
+    async fn dedupe_keeps_args_and_version_together() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::tasks(&prepare.pool).await;
+
+        let insert = |version: i16, merge: MergeArgs| {
+            Task::builder()
+                .kind("reindex_user")
+                .args(serde_json::json!({ "version": version }))
+                .args_version(version)
+                .debounce("user-1", std::time::Duration::from_secs(60))
+                .merge_args(merge)
+                .queue(&prepare.name)
+                .build()
+                .unwrap()
+                .insert(&prepare.pool)
+        };
+
+        let id = insert(1, MergeArgs::Replace).await.unwrap();
+        insert(2, MergeArgs::Replace).await.unwrap();
+        let task = TaskService::get_task(&prepare.pool, &id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(task.args, serde_json::json!({ "version": 2 }));
+        assert_eq!(task.args_version, 2);
+
+        insert(3, MergeArgs::Keep).await.unwrap();
+        assert!(insert(1, MergeArgs::Merge).await.is_err());
+        let task = TaskService::get_task(&prepare.pool, &id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(task.args, serde_json::json!({ "version": 2 }));
+        assert_eq!(task.args_version, 2);
+
+        utils::test::cleanup(prepare).await;
+    }
+
+    #[tokio::test]
+    async fn running_duplicate_fails_while_a_sibling_waits() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::tasks(&prepare.pool).await;
+
+        let insert = |args: serde_json::Value| {
+            Task::builder()
+                .kind("reindex_user")
+                .args(args)
+                .debounce("user-1", std::time::Duration::ZERO)
+                .queue(&prepare.name)
+                .build()
+                .unwrap()
+                .insert(&prepare.pool)
+        };
+        let state = |id: Uuid| {
+            let pool = prepare.pool.clone();
+            async move {
+                TaskService::get_task(&pool, &id)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .state
+            }
+        };
+
+        let running = insert(serde_json::json!(1)).await.unwrap();
+        let claimed = TaskService::get_tasks(&prepare.pool, &prepare.name, 10, &[])
+            .await
+            .unwrap();
+        assert_eq!(claimed[0].id, running);
+
+        let waiting = insert(serde_json::json!(2)).await.unwrap();
+        assert_ne!(running, waiting);
+
+        TaskService::failed(&prepare.pool, &running, "boom")
+            .await
+            .unwrap();
+        assert_eq!(state(running).await, TaskState::Retryable);
+
+        // inserts merge into the waiting task, not the retrying one
+        assert_eq!(insert(serde_json::json!(3)).await.unwrap(), waiting);
+
+        let claimed = TaskService::get_tasks(&prepare.pool, &prepare.name, 10, &[])
+            .await
+            .unwrap();
+        assert_eq!(claimed.len(), 2);
+        let waiting = insert(serde_json::json!(4)).await.unwrap();
+
+        // a snoozed duplicate doesn't take the key from the waiting task
+        let now = Utc::now();
+        for task in claimed.iter() {
+            TaskService::snooze(&prepare.pool, &task.id, &now, None)
+                .await
+                .unwrap();
+            assert_eq!(state(task.id).await, TaskState::Retryable);
+        }
+        assert_eq!(state(waiting).await, TaskState::Available);
+
+        utils::test::cleanup(prepare).await;
+    }
+
+    #[tokio::test]
+    async fn snooze_retries_behind_a_concurrently_inserted_duplicate() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::tasks(&prepare.pool).await;
+
+        let task = || {
+            Task::builder()
+                .kind("reindex_user")
+                .args(serde_json::Value::Null)
+                .debounce("user-1", std::time::Duration::ZERO)
+                .queue(&prepare.name)
+                .build()
+                .unwrap()
+        };
+
+        let running = task().insert(&prepare.pool).await.unwrap();
+        TaskService::get_tasks(&prepare.pool, &prepare.name, 10, &[])
+            .await
+            .unwrap();
+
+        // the duplicate isn't committed yet when the snooze looks for it
+        let mut tx = prepare.pool.begin().await.unwrap();
+        let waiting = task().insert(&mut *tx).await.unwrap();
+
+        let pool = PgPool::connect(&prepare.connection_string.to_string())
+            .await
+            .unwrap();
+        let snooze = tokio::spawn({
+            let pool = pool.clone();
+            async move { TaskService::snooze(&pool, &running, &Utc::now(), None).await }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        tx.commit().await.unwrap();
+
+        snooze.await.unwrap().unwrap();
+        pool.close().await;
+
+        let state = |id: Uuid| {
+            let pool = prepare.pool.clone();
+            async move {
+                TaskService::get_task(&pool, &id)
+                    .await
+                    .unwrap()
+                    .unwrap()
+                    .state
+            }
+        };
+        assert_eq!(state(running).await, TaskState::Retryable);
+        assert_eq!(state(waiting).await, TaskState::Available);
+
+        let history = TaskService::history(&prepare.pool, &running).await.unwrap();
+        assert_eq!(history.last().unwrap().to_state, TaskState::Retryable);
+
+        utils::test::cleanup(prepare).await;
+    }
+
+    #[tokio::test]
+    async fn throttles_inserts() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::tasks(&prepare.pool).await;
+
+        let insert = |args: serde_json::Value| {
+            Task::builder()
+                .kind("sync_account")
+                .args(args)
+                .throttle("account-1", std::time::Duration::from_secs(3600))
+                .merge_args(MergeArgs::Keep)
+                .queue(&prepare.name)
+                .build()
+                .unwrap()
+                .insert(&prepare.pool)
+        };
+
+        let first = insert(serde_json::json!(1)).await.unwrap();
+        let claimed = TaskService::get_tasks(&prepare.pool, &prepare.name, 10, &[])
+            .await
+            .unwrap();
+        assert_eq!(claimed[0].id, first);
+
+        // the first one runs, the next waits for the window to pass
+        let second = insert(serde_json::json!(2)).await.unwrap();
+        let third = insert(serde_json::json!(3)).await.unwrap();
+        assert_ne!(first, second);
+        assert_eq!(second, third);
+
+        let task = TaskService::get_task(&prepare.pool, &second)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(task.args, serde_json::json!(2));
+        assert_eq!(
+            task.scheduled_at.unwrap(),
+            claimed[0].scheduled_at.unwrap() + Duration::hours(1)
+        );
+
+        utils::test::cleanup(prepare).await;
+    }
+
+    #[tokio::test]
+    async fn throttle_pushes_back_a_waiting_task() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::tasks(&prepare.pool).await;
+
+        let builder = |args: serde_json::Value| {
+            Task::builder()
+                .kind("sync_account")
+                .args(args)
+                .queue(&prepare.name)
+        };
+
+        let first = builder(serde_json::json!(1))
+            .throttle("account-1", std::time::Duration::from_secs(3600))
+            .build()
+            .unwrap()
+            .insert(&prepare.pool)
+            .await
+            .unwrap();
+        let claimed = TaskService::get_tasks(&prepare.pool, &prepare.name, 10, &[])
+            .await
+            .unwrap();
+        assert_eq!(claimed[0].id, first);
+
+        let debounced = builder(serde_json::json!(2))
+            .debounce("account-1", std::time::Duration::ZERO)
+            .build()
+            .unwrap()
+            .insert(&prepare.pool)
+            .await
+            .unwrap();
+        let throttled = builder(serde_json::json!(3))
+            .throttle("account-1", std::time::Duration::from_secs(3600))
+            .build()
+            .unwrap()
+            .insert(&prepare.pool)
+            .await
+            .unwrap();
+        assert_eq!(debounced, throttled);
+
+        let task = TaskService::get_task(&prepare.pool, &throttled)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            task.scheduled_at.unwrap(),
+            claimed[0].scheduled_at.unwrap() + Duration::hours(1)
+        );
+
+        utils::test::cleanup(prepare).await;
+    }
+
+    #[tokio::test]
+    async fn rejects_dedupe_in_bulk_inserts() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::tasks(&prepare.pool).await;
+
+        let task = |key: Option<&str>| {
+            let builder = Task::builder()
+                .kind("reindex_user")
+                .args(serde_json::Value::Null)
+                .queue(&prepare.name);
+            match key {
+                Some(key) => builder.debounce(key, std::time::Duration::ZERO),
+                None => builder,
+            }
+            .build()
+            .unwrap()
+        };
+
+        let batch =
+            TaskService::batch_insert(&prepare.pool, &[task(None), task(Some("user-1"))]).await;
+        assert!(matches!(batch, Err(crate::error::Error::BulkDedupe)));
+
+        let tasks = vec![task(None), task(Some("user-1")), task(None)];
+        let copy =
+            TaskService::copy_insert(&prepare.pool, futures::stream::iter(tasks), 10, |_| {}).await;
+        assert!(matches!(copy, Err(crate::error::Error::BulkDedupe)));
+
+        let inserted =
+            TaskService::get_tasks_by_kind(&prepare.pool, "reindex_user", &prepare.name, 10)
+                .await
+                .unwrap();
+        assert_eq!(inserted.len(), 1);
+        assert!(inserted[0].dedupe_key.is_none());
+
+        utils::test::cleanup(prepare).await;
+    }
+
+    #[tokio::test]
+    async fn manages_periodic_tasks() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::tasks(&prepare.pool).await;
+
+        let queue = &prepare.name;
+        let report_eu = PeriodicJob::builder()
+            .name("report_eu")
+            .kind("report")
+            .schedule("CRON_TZ=Europe/Berlin 0 9 * * *".parse().unwrap())
+            .args(serde_json::json!({ "region": "eu" }))
+            .priority(10)
+            .build()
+            .unwrap();
+        let report_us = PeriodicJob::builder()
+            .name("report_us")
+            .kind("report")
+            .schedule("CRON_TZ=America/New_York 0 9 * * *".parse().unwrap())
+            .args(serde_json::json!({ "region": "us" }))
+            .timeout(std::time::Duration::from_secs(60))
+            .build()
+            .unwrap();
+
+        for job in [&report_eu, &report_us] {
+            let added = TaskService::add_periodic_task(&prepare.pool, queue, job)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&added.job().unwrap(), job);
+            assert!(!added.paused);
+        }
+
+        let duplicate = TaskService::add_periodic_task(&prepare.pool, queue, &report_eu)
+            .await
+            .unwrap();
+        assert!(duplicate.is_none());
+
+        let mut hourly = report_eu.clone();
+        hourly.schedule = "0 * * * *".parse().unwrap();
+        let updated = TaskService::update_periodic_task(&prepare.pool, queue, &hourly)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(updated.job().unwrap(), hourly);
+
+        let paused = TaskService::pause_periodic_task(&prepare.pool, queue, "report_eu")
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(paused.paused);
+
+        let resumed = TaskService::resume_periodic_task(&prepare.pool, queue, "report_eu")
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!resumed.paused);
+
+        let names = TaskService::get_periodic_tasks(&prepare.pool, queue)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|periodic_task| periodic_task.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["report_eu", "report_us"]);
+
+        let deleted = TaskService::delete_periodic_task(&prepare.pool, queue, "report_eu")
+            .await
+            .unwrap();
+        assert!(deleted);
+
+        let missing = TaskService::pause_periodic_task(&prepare.pool, queue, "report_eu")
+            .await
+            .unwrap();
+        assert!(missing.is_none());
+
+        utils::test::cleanup(prepare).await;
+    }
+
+    #[tokio::test]
+    async fn cancels_pending_runs_of_changed_periodic_tasks() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::tasks(&prepare.pool).await;
+
+        let queue = &prepare.name;
+        let job = PeriodicJob::builder()
+            .name("sync")
+            .kind("sync")
+            .schedule("*/5 * * * *".parse().unwrap())
+            .args(serde_json::Value::Null)
+            .build()
+            .unwrap();
+        TaskService::add_periodic_task(&prepare.pool, queue, &job)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let pending_runs = || async {
+            sqlx::query_scalar::<_, i64>(
+                "select count(*) from chang.tasks where queue = $1 and periodic_name = 'sync' and state in ('available', 'scheduled')",
+            )
+            .bind(queue)
+            .fetch_one(&prepare.pool)
+            .await
+            .unwrap()
+        };
+        let horizon = PeriodicHorizon::default();
+        let schedule = || async {
+            schedule_periodic_job(&prepare.pool, queue, &job, &Utc::now(), &horizon).await
+        };
+
+        schedule().await.unwrap();
+        assert!(pending_runs().await > 0);
+
+        let paused = TaskService::pause_periodic_task(&prepare.pool, queue, "sync")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(pending_runs().await, 0);
+        assert!(paused.scheduled_until.unwrap() <= Utc::now());
+
+        let resumed = TaskService::resume_periodic_task(&prepare.pool, queue, "sync")
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(resumed.scheduled_until.unwrap() <= Utc::now());
+
+        schedule().await.unwrap();
+        assert!(pending_runs().await > 0);
+
+        let updated = TaskService::update_periodic_task(&prepare.pool, queue, &job)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(pending_runs().await, 0);
+        assert!(updated.scheduled_until.unwrap() <= Utc::now());
+
+        schedule().await.unwrap();
+        assert!(pending_runs().await > 0);
+
+        let deleted = TaskService::delete_periodic_task(&prepare.pool, queue, "sync")
+            .await
+            .unwrap();
+        assert!(deleted);
+        assert_eq!(pending_runs().await, 0);
+
+        let comments = sqlx::query_scalar::<_, String>(
+            "select distinct comment from chang.task_history where to_state = 'cancelled' order by comment",
+        )
+        .fetch_all(&prepare.pool)
+        .await
+        .unwrap();
+        assert_eq!(
+            comments,
+            vec![
+                "periodic task deleted",
+                "periodic task paused",
+                "periodic task updated"
+            ]
+        );
+
+        utils::test::cleanup(prepare).await;
+    }
+
+    #[tokio::test]
+    async fn caps_running_tasks_per_kind() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::tasks(&prepare.pool).await;
+
+        let queue = &prepare.name;
+        for kind in [
+            "transcode",
+            "transcode",
+            "transcode",
+            "transcode",
+            "thumbnail",
+        ] {
+            Task::builder()
+                .kind(kind)
+                .args(serde_json::Value::Null)
+                .queue(queue)
+                .build()
+                .unwrap()
+                .insert(&prepare.pool)
+                .await
+                .unwrap();
+        }
+
+        TaskService::add_kind_limit(&prepare.pool, "transcode", 2)
+            .await
+            .unwrap();
+        let kept = TaskService::add_kind_limit(&prepare.pool, "transcode", 10)
+            .await
+            .unwrap();
+        assert!(kept.is_none(), "replaced an existing limit");
+
+        let claim = || TaskService::get_tasks(&prepare.pool, queue, 10, &[]);
+        let count = |tasks: &[Task], kind: &str| tasks.iter().filter(|t| t.kind == kind).count();
+
+        let claimed = claim().await.unwrap();
+        assert_eq!(count(&claimed, "transcode"), 2);
+        assert_eq!(count(&claimed, "thumbnail"), 1);
+        assert!(claim().await.unwrap().is_empty());
+
+        let transcode = claimed.iter().find(|t| t.kind == "transcode").unwrap();
+        TaskService::complete(&prepare.pool, &transcode.id)
+            .await
+            .unwrap();
+
+        let limits = TaskService::get_kind_limits(&prepare.pool).await.unwrap();
+        assert_eq!(limits.len(), 1);
+        assert_eq!(limits[0].running, 1);
+
+        assert_eq!(count(&claim().await.unwrap(), "transcode"), 1);
+
+        let limit = TaskService::set_kind_limit(&prepare.pool, "transcode", 5)
+            .await
+            .unwrap();
+        assert_eq!((limit.max_running, limit.running), (5, 2));
+        assert_eq!(count(&claim().await.unwrap(), "transcode"), 1);
+
+        let deleted = TaskService::delete_kind_limit(&prepare.pool, "transcode")
+            .await
+            .unwrap();
+        assert!(deleted);
+
+        utils::test::cleanup(prepare).await;
+    }
+
+    #[tokio::test]
+    async fn claims_of_other_kinds_dont_wait_for_limited_kinds() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::tasks(&prepare.pool).await;
+
+        let queue = &prepare.name;
+        for kind in ["transcode", "thumbnail"] {
+            Task::builder()
+                .kind(kind)
+                .args(serde_json::Value::Null)
+                .queue(queue)
+                .build()
+                .unwrap()
+                .insert(&prepare.pool)
+                .await
+                .unwrap();
+        }
+        TaskService::add_kind_limit(&prepare.pool, "transcode", 2)
+            .await
+            .unwrap();
+
+        // holds the lock on the transcode counter until it commits
+        let mut tx = prepare.pool.begin().await.unwrap();
+        let claimed = TaskService::get_tasks(&mut *tx, queue, 10, &["thumbnail".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(claimed.len(), 1);
+
+        let pool = PgPool::connect(&prepare.connection_string.to_string())
+            .await
+            .unwrap();
+        let excluded = vec!["transcode".to_string()];
+        let claim = TaskService::get_tasks(&pool, queue, 10, &excluded);
+        let claimed = tokio::time::timeout(std::time::Duration::from_secs(2), claim)
+            .await
+            .expect("claim waited for the transcode counter")
+            .unwrap();
+        assert_eq!(claimed.len(), 1);
+        assert_eq!(claimed[0].kind, "thumbnail");
+
+        tx.commit().await.unwrap();
+        pool.close().await;
+
+        utils::test::cleanup(prepare).await;
+    }
+
+    #[tokio::test]
+    async fn encrypts_args_and_rewraps_rotated_keys() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::tasks(&prepare.pool).await;
+
+        let old_key = crate::task::EncryptionKey::generate().unwrap();
+        let new_key = crate::task::EncryptionKey::generate().unwrap();
+        let encryption = ArgsEncryption::new(crate::task::Keyring::new("k1", old_key.clone()));
+
+        let args = serde_json::json!({ "tenant": "acme", "api_token": "secret" });
+        let id = Task::builder()
+            .kind("sync_crm")
+            .args(args.clone())
+            .encrypt(&encryption)
+            .plain_fields(&["tenant"])
+            .queue(&prepare.name)
+            .build()
+            .unwrap()
+            .insert(&prepare.pool)
+            .await
+            .unwrap();
+
+        let stored: Uuid =
+            sqlx::query_scalar("select id from chang.tasks where args->>'tenant' = 'acme'")
+                .fetch_one(&prepare.pool)
+                .await
+                .unwrap();
+        assert_eq!(stored, id);
+
+        let task = TaskService::get_task(&prepare.pool, &id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!task.args.to_string().contains("secret"));
+
+        let mut ctx = crate::task::Context::new();
+        ctx.put(task);
+        ctx.put(encryption);
+        let current = crate::task::CurrentTask::from_context(&ctx).unwrap();
+        assert_eq!(current.0, args);
+
+        let rotated = ArgsEncryption::new(
+            crate::task::Keyring::new("k1", old_key).rotate("k2", new_key.clone()),
+        );
+        let updated = TaskService::rewrap_args(&prepare.pool, &rotated, 10)
+            .await
+            .unwrap();
+        assert_eq!(updated, 1);
+
+        let task = TaskService::get_task(&prepare.pool, &id)
+            .await
+            .unwrap()
+            .unwrap();
+        let new_only = ArgsEncryption::new(crate::task::Keyring::new("k2", new_key));
+        assert_eq!(
+            new_only
+                .decrypt(task.args.clone(), &ArgsBinding::of(&task))
+                .unwrap(),
+            args
+        );
+
+        utils::test::cleanup(prepare).await;
+    }
+
+    #[tokio::test]
+    async fn offloads_large_args() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::tasks(&prepare.pool).await;
+
+        let args = serde_json::json!({ "tenant": "acme", "html": "<p>chang</p>".repeat(100) });
+        let build = |queue: &str| {
+            Task::builder()
+                .kind("render")
+                .args(args.clone())
+                .plain_fields(&["tenant"])
+                .offload_above(256)
+                .scheduled_at(&Utc::now())
+                .queue(queue)
+                .build()
+                .unwrap()
+        };
+
+        let id = build(&prepare.name).insert(&prepare.pool).await.unwrap();
+        let ids = TaskService::batch_insert(&prepare.pool, &[build(&prepare.name)])
+            .await
+            .unwrap();
+
+        for id in [id, ids[0]] {
+            let task = TaskService::get_task(&prepare.pool, &id)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(task.args["tenant"], "acme");
+            assert!(task.args.get("html").is_none());
+
+            let loaded = PayloadService::resolve(&prepare.pool, task.args)
+                .await
+                .unwrap();
+            assert_eq!(loaded, args);
+        }
+
+        let deleted = TaskService::delete_orphaned_payloads(&prepare.pool)
+            .await
+            .unwrap();
+        assert_eq!(deleted, 0);
+
+        sqlx::query("delete from chang.tasks where id = $1")
+            .bind(id)
+            .execute(&prepare.pool)
+            .await
+            .unwrap();
+        let deleted = TaskService::delete_orphaned_payloads(&prepare.pool)
+            .await
+            .unwrap();
+        assert_eq!(deleted, 1);
+
+        utils::test::cleanup(prepare).await;
+    }
+
+    #[tokio::test]
+    async fn copies_tasks_in_chunks() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::tasks(&prepare.pool).await;
+
+        let queue = prepare.name.clone();
+        let tasks = (0..250).map(|index| {
+            Task::builder()
+                .kind("backfill")
+                .args(serde_json::json!({ "index": index, "body": "x".repeat(index) }))
+                .tags(&["backfill".to_string()])
+                .offload_above(200)
+                .queue(&queue)
+                .build()
+                .unwrap()
+        });
+
+        let mut reported = vec![];
+        let progress = TaskService::copy_insert(
+            &prepare.pool,
+            futures::stream::iter(tasks),
+            100,
+            |progress| reported.push(progress.rows),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            progress,
+            CopyProgress {
+                rows: 250,
+                chunks: 3
+            }
+        );
+        assert_eq!(reported, vec![100, 200, 250]);
+
+        let tasks = TaskService::get_tasks_by_kind(&prepare.pool, "backfill", &prepare.name, 300)
+            .await
+            .unwrap();
+        assert_eq!(tasks.len(), 250);
+
+        let task = tasks.iter().find(|task| task.args["index"] == 10).unwrap();
+        assert_eq!(task.tags, Some(vec!["backfill".to_string()]));
+        assert_eq!(task.state, TaskState::Available);
+        assert_eq!(task.max_attempts, 3);
+
+        let offloaded = tasks
+            .iter()
+            .find(|task| Payload::reference(&task.args).is_some())
+            .unwrap();
+        let args = PayloadService::resolve(&prepare.pool, offloaded.args.clone())
+            .await
+            .unwrap();
+        assert!(args["index"].as_u64().unwrap() > 150);
+
+        utils::test::cleanup(prepare).await;
+    }
+
+    async fn insert_keyed_task(
+        prepare: &utils::test::Prepare,
+        key: &str,
+        scheduled_at: &DateTime<Utc>,
+    ) -> Uuid {
+        Task::builder()
+            .kind("keyed")
+            .args(serde_json::Value::Null)
+            .fairness_key(key)
+            .scheduled_at(scheduled_at)
+            .queue(&prepare.name)
+            .build()
+            .unwrap()
+            .insert(&prepare.pool)
+            .await
+            .unwrap()
     }
 }
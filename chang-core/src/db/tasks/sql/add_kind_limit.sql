@@ -0,0 +1,19 @@
+/*
+ * $1 text - kind
+ * $2 integer - max_running
+ *
+ * Returns nothing when the kind already has a limit.
+ */
+insert into chang.kind_limits(kind, max_running, running)
+select $1
+     , $2
+     , count(*)::integer
+  from chang.tasks
+ where kind = $1
+   and state = 'running'
+    on conflict (kind) do nothing
+returning kind
+        , max_running
+        , running
+        , created_at
+        , updated_at
@@ -0,0 +1,34 @@
+/*
+ * $1 text - queue
+ * $2 text - name
+ * $3 text - kind
+ * $4 text - schedule
+ * $5 jsonb - args
+ * $6 smallint - priority, null uses the task default
+ * $7 smallint - max_attempts, null uses the task default
+ * $8 text[] - tags
+ * $9 bigint - timeout_ms
+ * $10 chang.misfire_policy - misfire_policy
+ * $11 smallint - args_version
+ *
+ * Returns nothing when the queue already has a schedule with the name.
+ */
+insert into chang.periodic_tasks(queue, name, kind, schedule, args, priority, max_attempts, tags, timeout_ms, misfire_policy, args_version)
+values ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+    on conflict (queue, name) do nothing
+returning id
+        , queue
+        , name
+        , kind
+        , schedule
+        , args
+        , priority
+        , max_attempts
+        , tags
+        , timeout_ms
+        , misfire_policy as "misfire_policy: MisfirePolicy"
+        , args_version
+        , scheduled_until
+        , paused
+        , created_at
+        , updated_at
@@ -0,0 +1 @@
+select pg_advisory_unlock(hashtextextended($1, 0)) as "unlocked!"
@@ -1,11 +1,22 @@
 /*
- * $1 json - Array of logs 
+ * $1 json - Array of tasks
+ * $2 json - Array of offloaded args
+ *
+ * Tasks of a periodic job that already exist for the same scheduled_at are
+ * skipped, so the returned ids can be fewer than the given tasks. Their
+ * offloaded args are left to TaskService::delete_orphaned_payloads.
  */
  
-insert into chang.tasks(max_attempts, scheduled_at, priority, args, attempted_by, kind, queue, tags, depends_on, dependend_id)
+with payloads as (
+	insert into chang.payloads(id, source, payload, size)
+	select id, 'task', payload, size
+	  from jsonb_to_recordset($2) as payloads(id uuid, payload jsonb, size integer)
+)
+insert into chang.tasks(id, max_attempts, scheduled_at, priority, args, attempted_by, kind, queue, tags, depends_on, dependend_id, fairness_key, timeout_ms, periodic_name, args_version, expires_at)
 select *
   from jsonb_to_recordset($1) as tasks
-          ( max_attempts smallint 
+          ( id uuid
+          , max_attempts smallint 
           , scheduled_at timestamptz
           , priority smallint
           , args jsonb
@@ -15,5 +26,11 @@ select *
           , tags varchar(255)[]
           , depends_on uuid
           , dependend_id uuid
+          , fairness_key text
+          , timeout_ms bigint
+          , periodic_name text
+          , args_version smallint
+          , expires_at timestamptz
           )
+    on conflict do nothing
 returning id
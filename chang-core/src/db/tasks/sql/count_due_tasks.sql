@@ -0,0 +1,18 @@
+/*
+ * $1 string - queue
+ * $2 string - kind
+ * $3 u16 - limit, counting stops there
+ *
+ * The tasks of the kind a claim could take now, without claiming them.
+*/
+select count(*) as "due!"
+  from (
+	select 1
+	  from chang.tasks all_tasks
+	 where all_tasks.queue = $1
+	   and all_tasks.kind = $2
+	   and all_tasks.scheduled_at <= now()
+	   and (all_tasks.state = 'available' or all_tasks.state = 'retryable')
+	   and (all_tasks.expires_at is null or all_tasks.expires_at > now())
+	 limit $3
+  ) due_tasks
@@ -0,0 +1,3 @@
+delete from chang.kind_limits
+ where kind = $1
+returning kind
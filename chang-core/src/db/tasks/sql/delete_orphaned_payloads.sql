@@ -0,0 +1,11 @@
+/*
+ * Payloads of tasks that were deleted or never inserted, e.g. a skipped
+ * duplicate of a periodic task.
+ */
+delete from chang.payloads as payloads
+ where payloads.source = 'task'
+   and not exists (
+         select 1
+           from chang.tasks as tasks
+          where tasks.payload_id = payloads.id
+       )
@@ -0,0 +1,32 @@
+/*
+ * $1 text - queue
+ * $2 text - name
+ */
+with periodic_task as (
+	delete from chang.periodic_tasks
+	 where queue = $1
+	   and name = $2
+	returning queue, name
+), pending_runs as (
+	select tasks.id, tasks.state
+	  from chang.tasks tasks
+	  join periodic_task
+	    on periodic_task.queue = tasks.queue
+	   and periodic_task.name = tasks.periodic_name
+	 where (tasks.state = 'available' or tasks.state = 'scheduled')
+	   for update of tasks
+), cancelled_history as (
+	insert into chang.task_history(task_id, from_state, to_state, comment)
+	select id as task_id
+	     , pending_runs.state as from_state
+	     , 'cancelled'::chang.tasks_state as to_state
+	     , 'periodic task deleted' as comment
+	  from pending_runs
+	returning task_id
+), cancel_runs as (
+	update chang.tasks
+	   set state = 'cancelled'
+	 where id in (select task_id from cancelled_history)
+)
+select 1 as "deleted!"
+  from periodic_task
\ No newline at end of file
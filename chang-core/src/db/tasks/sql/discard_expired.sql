@@ -0,0 +1,5 @@
+/*
+ * $1 string - queue
+*/
+select count(*) as "discarded!"
+  from chang.discard_expired($1)
@@ -1,23 +1,39 @@
-
+/*
+ * $1 uuid - task id
+ * $2 text - error
+ * $3 jsonb - error details
+ * $4 bool - permanent, discards the task even if it has attempts left
+ * $5 bigint - retry delay in ms, null retries on the next claim
+ */
 with insert_error as (
-	insert into chang.task_error(task_id, error)
+	insert into chang.task_error(task_id, error, details)
 	select $1 as task_id
 	     , $2 as error
+	     , $3 as details
 	returning task_id
 ), insert_history as (
-	insert into chang.task_history(task_id, from_state, to_state)
+	insert into chang.task_history(task_id, from_state, to_state, comment)
 	select id as task_id
 	     , 'running'::chang.tasks_state as from_state 
 	     , case
-	          when attempt < max_attempts
+	          when attempt < max_attempts and not $4
 	          then 'retryable'::chang.tasks_state
 	          else 'discarded'::chang.tasks_state
 	       end as to_state
+	     , case
+	          when $4 then 'permanent error'
+	          else ''
+	       end as comment
 	  from chang.tasks
 	 where id in (select * from insert_error)
 	returning task_id, to_state
 )
 update chang.tasks
    set state = insert_history.to_state
+     , scheduled_at = case
+                        when $5::bigint is not null and insert_history.to_state = 'retryable'
+                        then now() + $5::bigint * interval '1 millisecond'
+                        else scheduled_at
+                      end
   from insert_history
  where id = insert_history.task_id
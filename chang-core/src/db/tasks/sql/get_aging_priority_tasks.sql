@@ -0,0 +1,125 @@
+/*
+ * $1 string - queue
+ * $2 u16 - limit
+ * $3 f64 - aging rate, priority points gained per second of waiting
+ * $4 string[] - kinds claimed elsewhere, e.g. by batch handlers
+*/
+with recursive discarded as (
+	select chang.discard_expired($1) as id
+), priorities as (
+	-- the distinct priorities of waiting tasks, read from the index one at a
+	-- time instead of sorting every waiting task
+	select max(priority) as priority
+	  from chang.tasks
+	 where queue = $1
+	   and state in ('available', 'retryable')
+	   and scheduled_at <= now()
+	 union all
+	select (
+		select max(priority)
+		  from chang.tasks
+		 where queue = $1
+		   and state in ('available', 'retryable')
+		   and scheduled_at <= now()
+		   and priority < priorities.priority
+	)
+	  from priorities
+	 where priorities.priority is not null
+), candidates as (
+	-- within a priority the oldest task has aged the most, so the first $2
+	-- tasks of every priority hold the first $2 overall
+	select candidate.id
+	  from priorities
+	 cross join lateral (
+	 	select id, priority, scheduled_at
+	 	  from chang.tasks all_tasks
+	 	 where all_tasks.queue = $1
+	 	   and all_tasks.priority = priorities.priority
+	 	   and all_tasks.state in ('available', 'retryable')
+	 	   and all_tasks.scheduled_at <= now()
+	 	   and (all_tasks.expires_at is null or all_tasks.expires_at > now())
+	 	   and not (all_tasks.kind = any($4::text[]))
+	 	   and not chang.kind_at_limit(all_tasks.kind)
+	 	   and case
+	 	         when depends_on is null then true
+	 	         else not exists (
+	 	         	select *
+	 	         	  from chang.tasks
+	 	         	 where dependend_id = all_tasks.depends_on
+	 	         	   and (
+	 	         	   	     state = 'running'
+	 	         	      or state = 'scheduled'
+	 	         	      or state = 'available'
+	 	         	      or state = 'retryable'
+	 	         	   )
+	 	         )
+	 	       end
+	 	 order by scheduled_at asc
+	 	        , id asc
+	 	 limit $2
+	 ) candidate
+	 where priorities.priority is not null
+	 order by candidate.priority + extract(epoch from (now() - candidate.scheduled_at)) * $3::float8 desc
+	        , candidate.scheduled_at asc
+	        , candidate.id asc
+	 limit $2
+), available_tasks as (
+	select id, state, kind, scheduled_at, priority
+	  from chang.tasks
+	 where id in (select id from candidates)
+	   and id not in (select id from discarded)
+	   and state in ('available', 'retryable')
+	 order by priority + extract(epoch from (now() - scheduled_at)) * $3::float8 desc
+	        , scheduled_at asc
+	        , id asc
+	   for update skip locked
+), kind_limits as materialized (
+	select kind, free
+	  from chang.lock_kind_limits(array(select kind from available_tasks))
+), capped_tasks as (
+	select ranked_tasks.id, ranked_tasks.state
+	  from (
+	  	select available_tasks.*
+	  	     , row_number() over (
+	  	     	   partition by available_tasks.kind
+	  	     	   order by priority + extract(epoch from (now() - scheduled_at)) * $3::float8 desc, scheduled_at asc, id asc
+	  	       ) as kind_rank
+	  	  from available_tasks
+	  ) ranked_tasks
+	  left join kind_limits on kind_limits.kind = ranked_tasks.kind
+	 where kind_limits.kind is null
+	    or ranked_tasks.kind_rank <= kind_limits.free
+), insert_history as (
+	insert into chang.task_history(task_id, from_state, to_state)
+	select id as task_id
+	     , capped_tasks.state as from_state 
+	     , 'running' as to_state
+	  from capped_tasks
+	returning task_id as id
+)
+update chang.tasks
+   set state = 'running'
+     , attempt = attempt + 1
+ where chang.tasks.id in (select * from insert_history)
+ returning id
+         , state as "state: TaskState"
+         , attempt
+         , scheduled_at
+         , max_attempts
+         , attempted_by
+         , tags
+         , kind
+         , args
+         , priority
+         , queue
+         , depends_on
+         , dependend_id
+         , fairness_key
+         , timeout_ms
+         , periodic_name
+         , progress
+         , progress_message
+         , progress_data
+         , args_version
+         , expires_at
+         , dedupe_key
\ No newline at end of file
@@ -0,0 +1,85 @@
+/*
+ * $1 string - queue
+ * $2 string - kind
+ * $3 u16 - limit
+*/
+with discarded as (
+	select chang.discard_expired($1, $2) as id
+), available_tasks as (
+ 	select id, state, kind, scheduled_at
+ 	  from chang.tasks all_tasks
+ 	 where all_tasks.queue = $1
+ 	   and all_tasks.id not in (select id from discarded)
+ 	   and all_tasks.scheduled_at <= now()
+ 	   and (all_tasks.state = 'available' or all_tasks.state = 'retryable')
+ 	   and (all_tasks.expires_at is null or all_tasks.expires_at > now())
+ 	   and all_tasks.kind = $2
+ 	   and not chang.kind_at_limit(all_tasks.kind)
+ 	   and case
+ 	         when depends_on is null then true
+ 	         else not exists (
+ 	         	select *
+ 	         	  from chang.tasks
+ 	         	 where dependend_id = all_tasks.depends_on
+ 	         	   and ( 
+ 	         	   	     state = 'running'
+ 	         	      or state = 'scheduled'
+ 	         	      or state = 'available'
+ 	         	      or state = 'retryable'
+ 	         	   )
+ 	         )
+ 	       end
+ 	 order by scheduled_at asc
+            , id asc
+ 	 limit $3
+ 	 for update skip locked
+), kind_limits as materialized (
+	select kind, free
+	  from chang.lock_kind_limits(array(select kind from available_tasks))
+), capped_tasks as (
+	select ranked_tasks.id, ranked_tasks.state
+	  from (
+	  	select available_tasks.*
+	  	     , row_number() over (
+	  	     	   partition by available_tasks.kind
+	  	     	   order by scheduled_at asc, id asc
+	  	       ) as kind_rank
+	  	  from available_tasks
+	  ) ranked_tasks
+	  left join kind_limits on kind_limits.kind = ranked_tasks.kind
+	 where kind_limits.kind is null
+	    or ranked_tasks.kind_rank <= kind_limits.free
+), insert_history as (
+	insert into chang.task_history(task_id, from_state, to_state)
+	select id as task_id
+	     , capped_tasks.state as from_state 
+	     , 'running'::chang.tasks_state as to_state
+	  from capped_tasks
+	returning task_id as id
+)
+update chang.tasks
+   set state = 'running'
+     , attempt = attempt + 1
+ where chang.tasks.id in (select * from insert_history)
+ returning id
+         , state as "state: TaskState"
+         , attempt
+         , scheduled_at
+         , max_attempts
+         , attempted_by
+         , tags
+         , kind
+         , args
+         , priority
+         , queue
+         , depends_on
+         , dependend_id
+         , fairness_key
+         , timeout_ms
+         , periodic_name
+         , progress
+         , progress_message
+         , progress_data
+         , args_version
+         , expires_at
+         , dedupe_key
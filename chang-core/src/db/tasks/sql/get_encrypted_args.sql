@@ -0,0 +1,17 @@
+/*
+ * $1 text - current key id
+ * $2 bigint - limit
+ *
+ * Tasks that may still run whose args are sealed with another key.
+ */
+select id
+     , queue
+     , kind
+     , dedupe_key
+     , args as "args!"
+  from chang.tasks
+ where args ? '$encrypted'
+   and args #>> '{$encrypted,key_id}' <> $1
+   and state in ('available', 'retryable', 'scheduled', 'running')
+ order by id
+ limit $2
@@ -0,0 +1,110 @@
+/*
+ * $1 string - queue
+ * $2 u16 - limit
+ * $3 u16 - max running tasks per fairness key, null for no limit
+ * $4 string[] - kinds claimed elsewhere, e.g. by batch handlers
+ * $5 string[] - keys to claim from, see get_fairness_keys.sql
+*/
+with running_tasks as (
+	select coalesce('key:' || fairness_key, 'none') as token
+	     , count(*) as running
+	  from chang.tasks
+	 where queue = $1
+	   and state = 'running'
+	   and coalesce('key:' || fairness_key, 'none') = any($5::text[])
+	 group by 1
+), ranked_tasks as (
+	-- the oldest tasks of every key, at most as many as the key can start
+ 	select candidate.id
+ 	     , candidate.key_rank
+ 	  from unnest($5::text[]) as keys(token)
+ 	  left join running_tasks on running_tasks.token = keys.token
+ 	 cross join lateral (
+ 	 	select id
+ 	 	     , row_number() over (order by scheduled_at asc, id asc) as key_rank
+ 	 	  from chang.tasks all_tasks
+ 	 	 where all_tasks.queue = $1
+ 	 	   and coalesce('key:' || all_tasks.fairness_key, 'none') = keys.token
+ 	 	   and all_tasks.state in ('available', 'retryable')
+ 	 	   and all_tasks.scheduled_at <= now()
+ 	 	   and (all_tasks.expires_at is null or all_tasks.expires_at > now())
+ 	 	   and not (all_tasks.kind = any($4::text[]))
+ 	 	   and not chang.kind_at_limit(all_tasks.kind)
+ 	 	   and case
+ 	 	         when depends_on is null then true
+ 	 	         else not exists (
+ 	 	         	select *
+ 	 	         	  from chang.tasks
+ 	 	         	 where dependend_id = all_tasks.depends_on
+ 	 	         	   and (
+ 	 	         	   	     state = 'running'
+ 	 	         	      or state = 'scheduled'
+ 	 	         	      or state = 'available'
+ 	 	         	      or state = 'retryable'
+ 	 	         	   )
+ 	 	         )
+ 	 	       end
+ 	 	 order by scheduled_at asc
+ 	 	        , id asc
+ 	 	 limit greatest(least($2, coalesce($3::bigint - coalesce(running_tasks.running, 0), $2)), 0)
+ 	 ) candidate
+), available_tasks as (
+ 	select all_tasks.id, all_tasks.state, all_tasks.kind, all_tasks.scheduled_at
+ 	  from chang.tasks all_tasks
+ 	  join ranked_tasks on ranked_tasks.id = all_tasks.id
+ 	 where all_tasks.state in ('available', 'retryable')
+ 	 order by ranked_tasks.key_rank asc
+ 	        , all_tasks.scheduled_at asc
+ 	        , all_tasks.id asc
+ 	 limit $2
+ 	 for update of all_tasks skip locked
+), kind_limits as materialized (
+	select kind, free
+	  from chang.lock_kind_limits(array(select kind from available_tasks))
+), capped_tasks as (
+	select ranked_tasks.id, ranked_tasks.state
+	  from (
+	  	select available_tasks.*
+	  	     , row_number() over (
+	  	     	   partition by available_tasks.kind
+	  	     	   order by scheduled_at asc, id asc
+	  	       ) as kind_rank
+	  	  from available_tasks
+	  ) ranked_tasks
+	  left join kind_limits on kind_limits.kind = ranked_tasks.kind
+	 where kind_limits.kind is null
+	    or ranked_tasks.kind_rank <= kind_limits.free
+), insert_history as (
+	insert into chang.task_history(task_id, from_state, to_state)
+	select id as task_id
+	     , capped_tasks.state as from_state 
+	     , 'running'::chang.tasks_state as to_state
+	  from capped_tasks
+	returning task_id as id
+)
+update chang.tasks
+   set state = 'running'
+     , attempt = attempt + 1
+ where chang.tasks.id in (select * from insert_history)
+ returning id
+         , state as "state: TaskState"
+         , attempt
+         , scheduled_at
+         , max_attempts
+         , attempted_by
+         , tags
+         , kind
+         , args
+         , priority
+         , queue
+         , depends_on
+         , dependend_id
+         , fairness_key
+         , timeout_ms
+         , periodic_name
+         , progress
+         , progress_message
+         , progress_data
+         , args_version
+         , expires_at
+         , dedupe_key
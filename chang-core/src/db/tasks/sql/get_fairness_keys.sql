@@ -0,0 +1,56 @@
+/*
+ * $1 string - queue
+ * $2 u16 - limit, at most this many keys get a task
+ * $3 u16 - max running tasks per fairness key, null for no limit
+ * $4 string[] - kinds claimed elsewhere, e.g. by batch handlers
+ *
+ * The keys whose oldest waiting task waited the longest, a key is
+ * coalesce('key:' || fairness_key, 'none') so tasks without one share a key.
+ * Keys that run their limit already are left out.
+*/
+with recursive waiting_keys as (
+	-- the distinct keys of waiting tasks, read from the index one at a time
+	-- instead of going through every waiting task
+	select min(coalesce('key:' || fairness_key, 'none')) as token
+	  from chang.tasks
+	 where queue = $1
+	   and state in ('available', 'retryable')
+	 union all
+	select (
+		select min(coalesce('key:' || fairness_key, 'none'))
+		  from chang.tasks
+		 where queue = $1
+		   and state in ('available', 'retryable')
+		   and coalesce('key:' || fairness_key, 'none') > waiting_keys.token
+	)
+	  from waiting_keys
+	 where waiting_keys.token is not null
+), running_tasks as (
+	select coalesce('key:' || fairness_key, 'none') as token
+	     , count(*) as running
+	  from chang.tasks
+	 where queue = $1
+	   and state = 'running'
+	 group by 1
+)
+select waiting_keys.token as "token!"
+  from waiting_keys
+ cross join lateral (
+ 	select scheduled_at, id
+ 	  from chang.tasks all_tasks
+ 	 where all_tasks.queue = $1
+ 	   and coalesce('key:' || all_tasks.fairness_key, 'none') = waiting_keys.token
+ 	   and all_tasks.state in ('available', 'retryable')
+ 	   and all_tasks.scheduled_at <= now()
+ 	   and (all_tasks.expires_at is null or all_tasks.expires_at > now())
+ 	   and not (all_tasks.kind = any($4::text[]))
+ 	 order by scheduled_at asc
+ 	        , id asc
+ 	 limit 1
+ ) head
+  left join running_tasks on running_tasks.token = waiting_keys.token
+ where waiting_keys.token is not null
+   and ($3::bigint is null or coalesce(running_tasks.running, 0) < $3::bigint)
+ order by head.scheduled_at asc
+        , head.id asc
+ limit $2
@@ -0,0 +1,7 @@
+select kind
+     , max_running
+     , running
+     , created_at
+     , updated_at
+  from chang.kind_limits
+ order by kind
@@ -0,0 +1,19 @@
+select id
+     , queue
+     , name
+     , kind
+     , schedule
+     , args
+     , priority
+     , max_attempts
+     , tags
+     , timeout_ms
+     , misfire_policy as "misfire_policy: MisfirePolicy"
+     , args_version
+     , scheduled_until
+     , paused
+     , created_at
+     , updated_at
+  from chang.periodic_tasks
+ where queue = $1
+   and name = $2
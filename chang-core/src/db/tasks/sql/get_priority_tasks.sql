@@ -1,13 +1,20 @@
 /*
  * $1 string - queue
  * $2 u16 - limit
+ * $3 string[] - kinds claimed elsewhere, e.g. by batch handlers
 */
-with available_tasks as (
- 	select id, state
+with discarded as (
+	select chang.discard_expired($1) as id
+), available_tasks as (
+ 	select id, state, kind, scheduled_at, priority
  	  from chang.tasks all_tasks
  	 where all_tasks.queue = $1
+ 	   and all_tasks.id not in (select id from discarded)
  	   and all_tasks.scheduled_at <= now()
  	   and (all_tasks.state = 'available' or all_tasks.state = 'retryable')
+ 	   and (all_tasks.expires_at is null or all_tasks.expires_at > now())
+ 	   and not (all_tasks.kind = any($3::text[]))
+ 	   and not chang.kind_at_limit(all_tasks.kind)
  	   and case
  	         when depends_on is null then true
  	         else not exists (
@@ -27,12 +34,28 @@ with available_tasks as (
             , id asc
  	 limit $2
  	 for update skip locked
+), kind_limits as materialized (
+	select kind, free
+	  from chang.lock_kind_limits(array(select kind from available_tasks))
+), capped_tasks as (
+	select ranked_tasks.id, ranked_tasks.state
+	  from (
+	  	select available_tasks.*
+	  	     , row_number() over (
+	  	     	   partition by available_tasks.kind
+	  	     	   order by priority desc, scheduled_at asc, id asc
+	  	       ) as kind_rank
+	  	  from available_tasks
+	  ) ranked_tasks
+	  left join kind_limits on kind_limits.kind = ranked_tasks.kind
+	 where kind_limits.kind is null
+	    or ranked_tasks.kind_rank <= kind_limits.free
 ), insert_history as (
 	insert into chang.task_history(task_id, from_state, to_state)
 	select id as task_id
-	     , available_tasks.state as from_state 
+	     , capped_tasks.state as from_state 
 	     , 'running' as to_state
-	  from available_tasks
+	  from capped_tasks
 	returning task_id as id
 )
 update chang.tasks
@@ -51,4 +74,13 @@ update chang.tasks
          , priority
          , queue
          , depends_on
-         , dependend_id
\ No newline at end of file
+         , dependend_id
+         , fairness_key
+         , timeout_ms
+         , periodic_name
+         , progress
+         , progress_message
+         , progress_data
+         , args_version
+         , expires_at
+         , dedupe_key
\ No newline at end of file
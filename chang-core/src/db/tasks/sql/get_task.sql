@@ -12,5 +12,14 @@ select id
      , queue
      , depends_on
      , dependend_id
+     , fairness_key
+     , timeout_ms
+     , periodic_name
+     , progress
+     , progress_message
+     , progress_data
+     , args_version
+     , expires_at
+     , dedupe_key
   from chang.tasks
  where id = $1
@@ -0,0 +1,12 @@
+/*
+ * $1 uuid - task id
+ */
+select id
+     , task_id
+     , error
+     , details
+     , created_at
+  from chang.task_error
+ where task_id = $1
+ order by created_at asc
+        , id asc
@@ -0,0 +1,13 @@
+/*
+ * $1 uuid - task id
+ */
+select id
+     , task_id
+     , from_state as "from_state: TaskState"
+     , to_state as "to_state: TaskState"
+     , comment
+     , created_at
+  from chang.task_history
+ where task_id = $1
+ order by created_at asc
+        , id asc
@@ -0,0 +1,7 @@
+select exists (
+	select 1
+	  from chang.tasks
+	 where kind = $1
+	   and queue = $2
+	   and state in ('available', 'retryable', 'running', 'scheduled')
+) as "exists!"
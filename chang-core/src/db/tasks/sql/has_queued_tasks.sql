@@ -0,0 +1,8 @@
+select exists (
+	select 1
+	  from chang.tasks
+	 where kind = $1
+	   and queue = $2
+	   and id <> $3
+	   and state in ('available', 'retryable', 'scheduled')
+) as "exists!"
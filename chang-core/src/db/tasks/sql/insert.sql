@@ -1,8 +1,41 @@
-
-insert into chang.tasks(max_attempts, scheduled_at, priority, args, attempted_by, kind, queue, tags, depends_on, dependend_id)
+/*
+ * $15 uuid, $16 jsonb, $17 integer - offloaded args, null if args are inline
+ * $19 text - dedupe key, null to always insert
+ * $20 text - 'debounce' or 'throttle'
+ * $21 bigint - debounce delay or throttle window in ms
+ * $22 text - how args merge into a waiting task: 'replace', 'keep' or 'merge'
+ * $23 uuid - task id
+ *
+ * A waiting task with the same dedupe key takes the args instead of a new
+ * task being inserted, its id is returned. A debounce pushes its
+ * scheduled_at back by the delay, a throttled task is scheduled a window
+ * after the previous run of the key and a waiting task is pushed back to
+ * it. Replaced args bring their version along, merging args of another
+ * version fails.
+ */
+with payload as (
+	insert into chang.payloads(id, source, payload, size)
+	select $15::uuid, 'task', $16::jsonb, $17::integer
+	 where $15::uuid is not null
+)
+insert into chang.tasks(id, max_attempts, scheduled_at, priority, args, attempted_by, kind, queue, tags, depends_on, dependend_id, fairness_key, timeout_ms, periodic_name, args_version, expires_at, dedupe_key)
 values (
-	$1 -- max_attempts
-  , coalesce($2, now()) -- scheduled_at
+	$23 -- id
+  , $1 -- max_attempts
+  , case $20::text
+      when 'debounce' then now() + $21::bigint * interval '1 millisecond'
+      when 'throttle' then greatest(
+             coalesce($2, now())
+           , (select max(scheduled_at)
+                from chang.tasks
+               where queue = coalesce($7, 'default')
+                 and kind = $6
+                 and dedupe_key = $19::text
+                 and state not in ('cancelled', 'available', 'scheduled')
+             ) + $21::bigint * interval '1 millisecond'
+           )
+      else coalesce($2, now())
+    end -- scheduled_at
   , $3 -- priority
   , $4 -- args
   , $5 -- attempted_by
@@ -11,5 +44,30 @@ values (
   , $8 -- tags
   , $9 -- depends_on
   , $10 -- dependend_id
+  , $11 -- fairness_key
+  , $12 -- timeout_ms
+  , $13 -- periodic_name
+  , $14 -- args_version
+  , $18 -- expires_at
+  , $19 -- dedupe_key
   )
-returning id
\ No newline at end of file
+    on conflict (queue, kind, dedupe_key)
+ where dedupe_key is not null
+   and state in ('available', 'scheduled')
+    do update
+   set scheduled_at = case $20::text
+                        when 'debounce' then excluded.scheduled_at
+                        when 'throttle' then greatest(chang.tasks.scheduled_at, excluded.scheduled_at)
+                        else chang.tasks.scheduled_at
+                      end
+     , args = case $22::text
+                when 'keep' then chang.tasks.args
+                -- shallow merge of objects, concatenation of arrays
+                when 'merge' then chang.merge_args(chang.tasks.args, chang.tasks.args_version, excluded.args, excluded.args_version)
+                else excluded.args
+              end
+     , args_version = case $22::text
+                        when 'replace' then excluded.args_version
+                        else chang.tasks.args_version
+                      end
+returning id
@@ -0,0 +1,16 @@
+/*
+ * $1 string - queue
+ * $2 string[] - keys, see get_fairness_keys.sql
+*/
+-- taken in order, so claims that lock the same keys can't deadlock
+select count(*) as "locked!"
+  from (
+	select pg_advisory_xact_lock(
+	         hashtextextended('chang.fairness_key:' || $1 || ':' || token, 0)
+	       )
+	  from (
+		select distinct token
+		  from unnest($2::text[]) as keys(token)
+		 order by token
+	  ) keys
+  ) locks
@@ -0,0 +1,7 @@
+/*
+ * $1 uuid - task id
+ * $2 jsonb - args
+ */
+update chang.tasks
+   set args = $2
+ where id = $1
@@ -0,0 +1,22 @@
+/*
+ * $1 text - kind
+ * $2 integer - max_running
+ *
+ * Recounts the running tasks of the kind as well.
+ */
+insert into chang.kind_limits(kind, max_running, running)
+select $1
+     , $2
+     , count(*)::integer
+  from chang.tasks
+ where kind = $1
+   and state = 'running'
+    on conflict (kind) do update
+   set max_running = excluded.max_running
+     , running = excluded.running
+     , updated_at = now()
+returning kind
+        , max_running
+        , running
+        , created_at
+        , updated_at
@@ -0,0 +1,58 @@
+/*
+ * $1 text - queue
+ * $2 text - name
+ * $3 bool - paused
+ */
+with periodic_task as (
+	update chang.periodic_tasks
+	   set paused = $3
+	     -- the runs enqueued ahead are cancelled on pause, so resuming
+	     -- schedules from now and slots missed while paused don't count as
+	     -- misfires
+	     , scheduled_until = case
+	                           when $3 then least(scheduled_until, now())
+	                           else greatest(scheduled_until, now())
+	                         end
+	     , updated_at = now()
+	 where queue = $1
+	   and name = $2
+	returning *
+), pending_runs as (
+	select tasks.id, tasks.state
+	  from chang.tasks tasks
+	  join periodic_task
+	    on periodic_task.queue = tasks.queue
+	   and periodic_task.name = tasks.periodic_name
+	 where (tasks.state = 'available' or tasks.state = 'scheduled')
+	   and $3
+	   for update of tasks
+), cancelled_history as (
+	insert into chang.task_history(task_id, from_state, to_state, comment)
+	select id as task_id
+	     , pending_runs.state as from_state
+	     , 'cancelled'::chang.tasks_state as to_state
+	     , 'periodic task paused' as comment
+	  from pending_runs
+	returning task_id
+), cancel_runs as (
+	update chang.tasks
+	   set state = 'cancelled'
+	 where id in (select task_id from cancelled_history)
+)
+select id
+     , queue
+     , name
+     , kind
+     , schedule
+     , args
+     , priority
+     , max_attempts
+     , tags
+     , timeout_ms
+     , misfire_policy as "misfire_policy: MisfirePolicy"
+     , args_version
+     , scheduled_until
+     , paused
+     , created_at
+     , updated_at
+  from periodic_task
\ No newline at end of file
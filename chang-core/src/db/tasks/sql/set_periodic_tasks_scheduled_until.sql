@@ -0,0 +1,9 @@
+/*
+ * $1 text - queue
+ * $2 text[] - names
+ * $3 timestamptz - end of the scheduled window
+ */
+update chang.periodic_tasks
+   set scheduled_until = greatest(scheduled_until, $3)
+ where queue = $1
+   and name = any($2)
@@ -0,0 +1,10 @@
+/*
+ * $1 uuid - task id
+ * $2 timestamptz - new scheduled_at
+ * $3 json - new args, null keeps the current args
+ *
+ * See chang.snooze, no row if the task isn't running.
+ */
+select snoozed as "id!"
+  from chang.snooze($1, $2, $3) as snoozed
+ where snoozed is not null
@@ -0,0 +1,57 @@
+with periodic_task as (
+	update chang.periodic_tasks
+	   set kind = $3
+	     , schedule = $4
+	     , args = $5
+	     , priority = $6
+	     , max_attempts = $7
+	     , tags = $8
+	     , timeout_ms = $9
+	     , misfire_policy = $10
+	     , args_version = $11
+	     -- runs enqueued ahead with the old job are cancelled below and
+	     -- scheduled again from now
+	     , scheduled_until = least(scheduled_until, now())
+	     , updated_at = now()
+	 where queue = $1
+	   and name = $2
+	returning *
+), pending_runs as (
+	select tasks.id, tasks.state
+	  from chang.tasks tasks
+	  join periodic_task
+	    on periodic_task.queue = tasks.queue
+	   and periodic_task.name = tasks.periodic_name
+	 where (tasks.state = 'available' or tasks.state = 'scheduled')
+	   and tasks.scheduled_at > now()
+	   for update of tasks
+), cancelled_history as (
+	insert into chang.task_history(task_id, from_state, to_state, comment)
+	select id as task_id
+	     , pending_runs.state as from_state
+	     , 'cancelled'::chang.tasks_state as to_state
+	     , 'periodic task updated' as comment
+	  from pending_runs
+	returning task_id
+), cancel_runs as (
+	update chang.tasks
+	   set state = 'cancelled'
+	 where id in (select task_id from cancelled_history)
+)
+select id
+     , queue
+     , name
+     , kind
+     , schedule
+     , args
+     , priority
+     , max_attempts
+     , tags
+     , timeout_ms
+     , misfire_policy as "misfire_policy: MisfirePolicy"
+     , args_version
+     , scheduled_until
+     , paused
+     , created_at
+     , updated_at
+  from periodic_task
\ No newline at end of file
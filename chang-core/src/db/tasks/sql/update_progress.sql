@@ -0,0 +1,53 @@
+/*
+ * $1 uuid - task id
+ * $2 real - progress in percent
+ * $3 text - message
+ * $4 jsonb - structured progress
+ *
+ * Notifies `chang_task_progress` with the update. A notification takes less
+ * than 8000 bytes, so a payload too large leaves out the structured progress,
+ * then cuts the message short and at last leaves it out as well. Progress
+ * outside of 0 to 100 fails the chang_task_progress_range check.
+*/
+with updated as (
+	update chang.tasks
+	   set progress = $2
+	     , progress_message = $3
+	     , progress_data = $4
+	 where id = $1
+	   and state = 'running'
+	returning id
+	        , queue
+	        , kind
+	        , progress
+	        , progress_message
+	        , progress_data
+), payload as (
+	select jsonb_build_object(
+	           'id', id,
+	           'queue', queue,
+	           'kind', kind,
+	           'progress', progress,
+	           'message', progress_message,
+	           'data', progress_data
+	       ) as payload
+	  from updated
+), notified as (
+	select pg_notify('chang_task_progress', fitting.payload::text)
+	  from payload
+	 cross join lateral (
+	 	select candidate.payload
+	 	  from (
+	 	  	values (1, payload.payload)
+	 	  	     , (2, payload.payload - 'data')
+	 	  	     , (3, jsonb_set(payload.payload - 'data', '{message}', coalesce(to_jsonb(left(payload.payload->>'message', 1000)), 'null')))
+	 	  	     , (4, payload.payload - 'data' - 'message')
+	 	  ) as candidate(preference, payload)
+	 	 where octet_length(candidate.payload::text) < 8000
+	 	    or candidate.preference = 4
+	 	 order by candidate.preference
+	 	 limit 1
+	 ) fitting
+)
+select count(*) as "updated!"
+  from notified
@@ -10,6 +10,15 @@ pub enum Error {
     #[error(transparent)]
     Serde(#[from] serde_json::Error),
 
+    #[error(transparent)]
+    TaskBuild(#[from] crate::task::TaskBuildError),
+
+    #[error(transparent)]
+    Encryption(#[from] crate::task::EncryptionError),
+
+    #[error("debounced and throttled tasks can only be inserted one at a time")]
+    BulkDedupe,
+
     #[error("")]
     Other(String),
 }
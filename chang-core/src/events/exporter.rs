@@ -14,12 +14,23 @@ pub trait EventExporter: Send + Sync {
 
 pub struct ChangEventExporter {
     db: PgPool,
+    offload_above: Option<usize>,
 }
 
 impl ChangEventExporter {
     pub fn new(pool: &PgPool) -> ChangEventExporter {
         let db = pool.clone();
-        ChangEventExporter { db }
+        ChangEventExporter {
+            db,
+            offload_above: None,
+        }
+    }
+
+    /// Stores bodies that serialize to more than `bytes` in `chang.payloads`,
+    /// see [`EventData::offload`].
+    pub fn offload_above(mut self, bytes: usize) -> Self {
+        self.offload_above = Some(bytes);
+        self
     }
 }
 
@@ -27,7 +38,10 @@ impl ChangEventExporter {
 impl EventExporter for ChangEventExporter {
     async fn export(&self, batch: Vec<EventRecord>) -> Result<()> {
         if !self.db.is_closed() {
-            let data = EventData::from(batch);
+            let mut data = EventData::from(batch);
+            if let Some(threshold) = self.offload_above {
+                data = data.offload(threshold);
+            }
             EventsService::batch_insert(&self.db, data).await?;
             Ok(())
         } else {
@@ -39,6 +53,7 @@ impl EventExporter for ChangEventExporter {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::db::payloads::PayloadService;
     use crate::{db::migration, error::Error, utils};
 
     #[tokio::test]
@@ -60,6 +75,39 @@ mod tests {
         utils::test::cleanup(prepare).await;
     }
 
+    #[tokio::test]
+    async fn offloads_large_bodies() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::events(&prepare.pool).await;
+
+        let exporter = ChangEventExporter::new(&prepare.pool).offload_above(1024);
+
+        let body = serde_json::json!({ "items": vec!["chang"; 500] });
+        let mut records = utils::test::events::get_records();
+        records.push(EventRecord::new("large", body.clone()));
+
+        exporter
+            .export(records)
+            .await
+            .expect("failed to insert event records");
+
+        let stored: serde_json::Value =
+            sqlx::query_scalar("select body from chang.events where kind = 'large'")
+                .fetch_one(&prepare.pool)
+                .await
+                .unwrap();
+        assert!(stored.get("items").is_none());
+
+        let loaded = PayloadService::resolve(&prepare.pool, stored)
+            .await
+            .unwrap();
+        assert_eq!(loaded, body);
+
+        utils::test::cleanup(prepare).await;
+    }
+
     #[tokio::test]
     async fn pool_closed() {
         let prepare = utils::test::prepare().await;
@@ -81,7 +129,7 @@ mod tests {
         let e = error.unwrap_err();
         assert!(matches!(
             e,
-            Error::Other(x) if x == "exporter is shut down".to_string()
+            Error::Other(x) if x == "exporter is shut down"
         ));
     }
 }
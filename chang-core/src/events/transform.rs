@@ -2,9 +2,30 @@ use chrono::{DateTime, Utc};
 use serde::Serialize;
 use uuid::Uuid;
 
+use crate::db::payloads::Payload;
+
 #[derive(Serialize, Debug, PartialEq)]
 pub struct EventData {
     pub events: Vec<EventRecord>,
+    /// Offloaded bodies, see [`EventData::offload`].
+    #[serde(skip)]
+    pub payloads: Vec<Payload>,
+}
+
+impl EventData {
+    /// Moves bodies that serialize to more than `threshold` bytes to
+    /// `chang.payloads`, the events keep a reference that
+    /// [`crate::db::payloads::PayloadService::resolve`] loads.
+    pub fn offload(mut self, threshold: usize) -> Self {
+        for event in self.events.iter_mut() {
+            let body = std::mem::take(&mut event.body);
+            let (body, payload) = Payload::offload(body, threshold, &[]);
+            event.body = body;
+            self.payloads.extend(payload);
+        }
+
+        self
+    }
 }
 
 #[derive(Serialize, Clone, Debug, PartialEq)]
@@ -28,7 +49,10 @@ impl EventRecord {
 
 impl From<Vec<EventRecord>> for EventData {
     fn from(events: Vec<EventRecord>) -> Self {
-        EventData { events }
+        EventData {
+            events,
+            payloads: vec![],
+        }
     }
 }
 
@@ -43,6 +67,7 @@ mod test {
 
         let expected = EventData {
             events: records.clone(),
+            payloads: vec![],
         };
 
         let event_data = EventData::from(records);
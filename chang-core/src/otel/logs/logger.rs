@@ -4,6 +4,8 @@ use opentelemetry::logs::{AnyValue, LogRecordBuilder, Logger, LoggerProvider, Se
 use opentelemetry::{Key, OrderMap};
 use std::borrow::Cow;
 
+use crate::task::TaskLogContext;
+
 pub struct ChangLogBridge<P, L>
 where
     P: LoggerProvider<Logger = L> + Send + Sync,
@@ -19,16 +21,20 @@ where
     L: Logger + Send + Sync,
 {
     fn enabled(&self, _metadata: &Metadata) -> bool {
-        return self.logger.event_enabled(
+        self.logger.event_enabled(
             map_severity_to_otel_severity(_metadata.level()),
             _metadata.target(),
-        );
+        )
     }
 
     fn log(&self, record: &Record) {
         let mut attributes = Attributes::default();
         let _ = record.key_values().visit(&mut attributes);
 
+        if let Some(task) = TaskLogContext::current() {
+            attributes.add_task(&task);
+        }
+
         if self.enabled(record.metadata()) {
             self.logger.emit(
                 LogRecordBuilder::new()
@@ -101,6 +107,17 @@ impl Attributes {
     pub fn attributes(self) -> Vec<(Key, AnyValue)> {
         self.0
     }
+
+    fn add_task(&mut self, task: &TaskLogContext) {
+        self.0.extend([
+            (Key::from("task.id"), AnyValue::from(task.id.to_string())),
+            (Key::from("task.kind"), AnyValue::from(task.kind.clone())),
+            (
+                Key::from("task.attempt"),
+                AnyValue::Int(task.attempt.into()),
+            ),
+        ]);
+    }
 }
 
 impl<'kvs> Visitor<'kvs> for Attributes {
@@ -132,9 +149,80 @@ fn to_flattend_object(
 ) {
     match value {
         serde_json::Value::Object(o) => o.into_iter().for_each(|(o_key, value)| {
-            let k = base_key.to_string() + "." + &o_key;
+            let k = base_key.to_string() + "." + o_key;
             to_flattend_object(&k, value, acc);
         }),
         _ => acc.push((base_key.to_string(), value.to_owned())),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use opentelemetry::logs::LogRecord;
+    use opentelemetry::InstrumentationLibrary;
+    use std::sync::{Arc, Mutex};
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::otel::common::AttributeSet;
+
+    #[derive(Clone, Default)]
+    struct CapturingLogger(Arc<Mutex<Vec<LogRecord>>>);
+
+    impl Logger for CapturingLogger {
+        fn emit(&self, record: LogRecord) {
+            self.0.lock().unwrap().push(record);
+        }
+
+        fn event_enabled(&self, _level: Severity, _target: &str) -> bool {
+            true
+        }
+    }
+
+    impl LoggerProvider for CapturingLogger {
+        type Logger = CapturingLogger;
+
+        fn library_logger(&self, _library: Arc<InstrumentationLibrary>) -> Self::Logger {
+            self.clone()
+        }
+    }
+
+    #[tokio::test]
+    async fn tags_records_with_the_current_task() {
+        let provider = CapturingLogger::default();
+        let bridge = ChangLogBridge::new(&provider);
+        let log = |message: &str| {
+            log::Log::log(
+                &bridge,
+                &Record::builder()
+                    .args(format_args!("{}", message))
+                    .level(Level::Info)
+                    .build(),
+            )
+        };
+
+        let task = TaskLogContext {
+            id: Uuid::new_v4(),
+            kind: "import".to_string(),
+            attempt: 2,
+        };
+        task.clone().scope(async { log("inside") }).await;
+        log("outside");
+
+        let records = provider.0.lock().unwrap();
+        let attributes = records
+            .iter()
+            .map(|record| AttributeSet::from(record.attributes.clone().unwrap_or_default()).0)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            serde_json::to_value(&attributes[0]).unwrap(),
+            serde_json::json!({
+                "task.id": task.id.to_string(),
+                "task.kind": "import",
+                "task.attempt": 2,
+            })
+        );
+        assert!(attributes[1].is_empty());
+    }
+}
@@ -0,0 +1,75 @@
+use sqlx::pool::PoolConnection;
+use sqlx::{PgPool, Postgres};
+
+use crate::task::TaskService;
+
+/// A Postgres session level advisory lock, held on a connection taken out of
+/// the pool for as long as the lock lives.
+///
+/// Only one holder of a name exists across every process sharing the
+/// database, which makes it usable for leader election: whoever acquires the
+/// lock leads until it releases it or its connection drops.
+///
+/// The lock is released by [`AdvisoryLock::release`], by dropping it, which
+/// closes the connection, or by Postgres once the connection drops.
+pub struct AdvisoryLock {
+    name: String,
+    conn: Option<PoolConnection<Postgres>>,
+}
+
+impl AdvisoryLock {
+    /// Returns `None` if someone else holds the lock.
+    pub async fn try_acquire(db: &PgPool, name: &str) -> sqlx::Result<Option<AdvisoryLock>> {
+        let mut conn = db.acquire().await?;
+
+        if !TaskService::try_advisory_lock(&mut *conn, name).await? {
+            return Ok(None);
+        }
+
+        Ok(Some(AdvisoryLock {
+            name: name.to_string(),
+            conn: Some(conn),
+        }))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Checks that the connection holding the lock is still alive.
+    pub async fn is_held(&mut self) -> bool {
+        match self.conn.as_mut() {
+            Some(conn) => sqlx::Connection::ping(&mut **conn).await.is_ok(),
+            None => false,
+        }
+    }
+
+    /// Unlocks and returns the connection to the pool.
+    pub async fn release(mut self) -> sqlx::Result<()> {
+        let Some(mut conn) = self.conn.take() else {
+            return Ok(());
+        };
+
+        if let Err(err) = TaskService::advisory_unlock(&mut *conn, &self.name).await {
+            // the lock must not go back into the pool with the connection
+            drop(conn.detach());
+            return Err(err);
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for AdvisoryLock {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            // closing the connection ends the session and with it the lock
+            drop(conn.detach());
+        }
+    }
+}
+
+/// The lock name guarding an exclusive kind, on every queue.
+pub fn exclusive_lock_name(kind: &str) -> String {
+    format!("chang:exclusive:{}", kind)
+}
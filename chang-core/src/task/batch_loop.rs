@@ -0,0 +1,408 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use chrono::Utc;
+use log::{error, info};
+use std::error::Error;
+use std::fmt::Debug;
+use std::sync::Arc;
+use tokio::time::{self, Instant};
+use tokio::{self, select};
+use tokio_util::sync::CancellationToken;
+
+use crate::task::failure::Failure;
+use crate::task::lazy_args::LazyArgs;
+use crate::task::outcome::{Outcome, TaskOutcome};
+use crate::task::panic::catch_panic;
+use crate::task::run_task::{fail_task, finish_task};
+use crate::task::store::{Store, TaskStore};
+use crate::task::traits::BatchTaskHandler;
+use crate::task::upcast::Upcasters;
+use crate::task::{FromTaskContext, Task, TaskQueue};
+use crate::utils::context::{Context, CurrentTask};
+
+pub struct BatchRoute<E> {
+    pub handler: Box<dyn BatchTaskHandler<E> + Send + Sync>,
+    pub size: i64,
+    pub window: Duration,
+}
+
+pub type BatchRouter<E> = HashMap<String, BatchRoute<E>>;
+
+#[allow(clippy::too_many_arguments)]
+pub async fn start<E>(
+    label: &str,
+    cancel_token: &CancellationToken,
+    store: &Arc<dyn TaskStore>,
+    queue: &TaskQueue,
+    kind: &str,
+    route: &BatchRoute<E>,
+    context: &Context,
+) where
+    E: Into<Box<dyn Error + Send + Sync>> + std::fmt::Display + Debug + 'static,
+{
+    let mut interval = time::interval(Duration::from_millis(queue.interval));
+
+    loop {
+        if cancel_token.is_cancelled() || store.is_closed() {
+            break;
+        }
+
+        let tasks = collect(label, cancel_token, store, queue, kind, route).await;
+
+        if tasks.is_empty() {
+            interval.tick().await;
+            continue;
+        }
+
+        run_batch(store, tasks, route, context, label).await;
+
+        select! {
+            _ = cancel_token.cancelled() => {
+                break;
+            }
+
+            _ = store.closed() => {
+                break;
+            }
+
+            _ = interval.tick() => {
+                continue;
+            }
+        }
+    }
+}
+
+/// Waits until a batch of `kind` is full or the collection window, which
+/// opens once a task is due, has passed, and then claims the batch. Tasks are
+/// only counted while the window is open, so none of them runs, and counts
+/// against its timeout, stale recovery or kind limit, before the handler gets
+/// it.
+async fn collect<E>(
+    label: &str,
+    cancel_token: &CancellationToken,
+    store: &Arc<dyn TaskStore>,
+    queue: &TaskQueue,
+    kind: &str,
+    route: &BatchRoute<E>,
+) -> Vec<Task> {
+    let mut deadline: Option<Instant> = None;
+    let poll = Duration::from_millis(queue.interval);
+
+    loop {
+        let due = match store.count_due(&queue.name, kind, route.size).await {
+            Ok(due) => due,
+            Err(err) => {
+                error!("[{}] task error: failed to count tasks {:?}", label, err);
+                return vec![];
+            }
+        };
+
+        if due == 0 {
+            return vec![];
+        }
+
+        let deadline = *deadline.get_or_insert_with(|| Instant::now() + route.window);
+        let now = Instant::now();
+
+        if due >= route.size || now >= deadline || cancel_token.is_cancelled() {
+            break;
+        }
+
+        time::sleep(poll.min(deadline - now)).await;
+    }
+
+    match store.claim_kind(&queue.name, kind, route.size).await {
+        Ok(tasks) => tasks,
+        Err(err) => {
+            error!("[{}] task error: failed to fetch tasks {:?}", label, err);
+            vec![]
+        }
+    }
+}
+
+pub async fn run_batch<E>(
+    store: &Arc<dyn TaskStore>,
+    tasks: Vec<Task>,
+    route: &BatchRoute<E>,
+    context: &Context,
+    label: &str,
+) -> Vec<TaskOutcome>
+where
+    E: Into<Box<dyn Error + Send + Sync>> + std::fmt::Display + Debug + 'static,
+{
+    let mut ctx = Context::from(context);
+    ctx.put(Store(store.clone()));
+    if let Some(pool) = store.pool() {
+        ctx.put(pool.clone());
+    }
+
+    let mut finished = Vec::with_capacity(tasks.len());
+    let mut resolved = Vec::with_capacity(tasks.len());
+    for mut task in tasks {
+        match resolve_args(store, &ctx, &mut task).await {
+            Ok(()) => resolved.push(task),
+            Err(err) => {
+                let failure = Failure::from_error(err);
+                error!(
+                    "[{}] task({}) failed to resolve args: {}",
+                    label, task.id, failure.error
+                );
+                fail_task(&**store, &task.id, label, &failure).await;
+                let outcome = Outcome::Failed {
+                    error: failure.error,
+                };
+                finished.push(TaskOutcome::new(&task, outcome));
+            }
+        }
+    }
+    let tasks = resolved;
+
+    if tasks.is_empty() {
+        return finished;
+    }
+
+    let claimed = tasks
+        .iter()
+        .map(|task| TaskOutcome::new(task, Outcome::Completed))
+        .collect::<Vec<TaskOutcome>>();
+
+    info!("[{}] run batch of {} tasks", label, claimed.len());
+
+    let start = Utc::now();
+    let result = match catch_panic(route.handler.call(ctx, tasks)).await {
+        Ok(result) => result.map_err(Failure::from_error),
+        Err(panic) => Err(panic.failure()),
+    };
+    match result {
+        Err(failure) => {
+            error!("[{}] batch failed to run: {:?}", label, failure.error);
+            for run in claimed {
+                fail_task(&**store, &run.id, label, &failure).await;
+                let outcome = Outcome::Failed {
+                    error: failure.error.clone(),
+                };
+                finished.push(TaskOutcome { outcome, ..run });
+            }
+        }
+        Ok(mut outcomes) => {
+            for run in claimed {
+                let outcome = match outcomes.remove(&run.id) {
+                    Some(result) => {
+                        finish_task(&**store, &run.id, &run.kind, label, start, result).await
+                    }
+                    None => {
+                        let error = format!("batch handler returned no outcome for {}", run.id);
+                        error!("[{}] task error: {}", label, error);
+                        fail_task(&**store, &run.id, label, &Failure::new(&error)).await;
+                        Outcome::Failed { error }
+                    }
+                };
+                finished.push(TaskOutcome { outcome, ..run });
+            }
+        }
+    };
+
+    finished
+}
+
+/// Loads, decrypts and upcasts the args of `task` like
+/// [`CurrentTask::from_context`] does for a single task, a batch handler
+/// gets the args in place of the stored ones.
+async fn resolve_args(
+    store: &Arc<dyn TaskStore>,
+    ctx: &Context,
+    task: &mut Task,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let lazy_args = LazyArgs::new(&task.args, store);
+    lazy_args.load().await?;
+
+    let mut task_ctx = Context::from(ctx);
+    task_ctx.put(task.clone());
+    task_ctx.put(lazy_args);
+    let CurrentTask(args) = CurrentTask::from_context(&task_ctx)?;
+
+    let upcasters = ctx.get::<Upcasters>().cloned().unwrap_or_default();
+    let (args, version) = upcasters.upcast_latest(&task.kind, args, task.args_version);
+    task.args = args;
+    task.args_version = version;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::anyhow;
+
+    use super::*;
+    use crate::db::migration;
+    use crate::task::{
+        ArgsEncryption, BatchOutcome, EncryptionKey, Keyring, MemoryTaskStore, PgTaskStore,
+        TaskService, TaskState,
+    };
+    use crate::utils;
+
+    #[tokio::test]
+    async fn resolves_args_before_the_handler() {
+        let store: Arc<dyn TaskStore> = Arc::new(MemoryTaskStore::new());
+        let encryption = ArgsEncryption::new(Keyring::new("k1", EncryptionKey::new([1; 32])));
+
+        let rows = (0..100).collect::<Vec<_>>();
+        let builders = [
+            Task::builder()
+                .args(serde_json::json!({ "name": "chang", "rows": rows }))
+                .offload_above(64)
+                .args_version(2),
+            Task::builder()
+                .args(serde_json::json!({ "name": "chang" }))
+                .encrypt(&encryption)
+                .args_version(2),
+            Task::builder()
+                .args(serde_json::json!({ "title": "chang" }))
+                .args_version(1),
+        ];
+        for builder in builders {
+            let task = builder.kind("batch_task").build().unwrap();
+            store.insert(task).await.unwrap();
+        }
+
+        let route: BatchRoute<anyhow::Error> = BatchRoute {
+            handler: Box::new(|_ctx: Context, tasks: Vec<Task>| async move {
+                let mut outcomes: BatchOutcome<anyhow::Error> = HashMap::new();
+                for task in tasks {
+                    let result = match task.args["name"].as_str() {
+                        Some("chang") if task.args_version == 2 => Ok(TaskState::Completed),
+                        _ => Err(anyhow!("unexpected args {}", task.args)),
+                    };
+                    outcomes.insert(task.id, result);
+                }
+                Ok(outcomes)
+            }),
+            size: 10,
+            window: Duration::from_millis(0),
+        };
+
+        let mut upcasters = Upcasters::default();
+        upcasters.add("batch_task", 1, |mut args| {
+            args["name"] = args["title"].take();
+            args
+        });
+        let mut context = Context::new();
+        context.put(encryption);
+        context.put(upcasters);
+
+        let tasks = store.claim_kind("default", "batch_task", 10).await.unwrap();
+        assert_eq!(tasks.len(), 3);
+
+        let finished = run_batch(&store, tasks, &route, &context, "memory").await;
+        assert_eq!(finished.len(), 3);
+        for run in finished {
+            assert_eq!(run.outcome, Outcome::Completed);
+        }
+    }
+
+    #[tokio::test]
+    async fn claims_the_batch_once_the_window_passed() {
+        let store: Arc<dyn TaskStore> = Arc::new(MemoryTaskStore::new());
+        let insert = || {
+            let store = store.clone();
+            async move {
+                let task = Task::builder()
+                    .kind("batch_task")
+                    .args(serde_json::Value::Null)
+                    .build()
+                    .unwrap();
+                store.insert(task).await.unwrap()
+            }
+        };
+
+        let route: BatchRoute<anyhow::Error> = BatchRoute {
+            handler: Box::new(|_ctx: Context, _tasks: Vec<Task>| async move { Ok(HashMap::new()) }),
+            size: 10,
+            window: Duration::from_millis(300),
+        };
+        let queue = TaskQueue::builder().interval(20).build();
+        let token = CancellationToken::new();
+
+        let first = insert().await;
+        let collected = tokio::join!(
+            collect("memory", &token, &store, &queue, "batch_task", &route),
+            async {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                // the window is open, but nothing was claimed yet
+                let task = store.get_task(&first).await.unwrap().unwrap();
+                assert_eq!(task.state, TaskState::Available);
+                insert().await
+            }
+        );
+
+        let (tasks, second) = collected;
+        let mut ids = tasks.iter().map(|task| task.id).collect::<Vec<_>>();
+        ids.sort();
+        let mut expected = vec![first, second];
+        expected.sort();
+        assert_eq!(ids, expected);
+        assert!(tasks.iter().all(|task| task.state == TaskState::Running));
+    }
+
+    #[tokio::test]
+    async fn reports_outcome_per_task() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::tasks(&prepare.pool).await;
+
+        for index in 0..3 {
+            Task::builder()
+                .kind("batch_task")
+                .args(serde_json::json!({ "index": index }))
+                .queue(&prepare.name)
+                .build()
+                .unwrap()
+                .insert(&prepare.pool)
+                .await
+                .unwrap();
+        }
+
+        let route: BatchRoute<anyhow::Error> = BatchRoute {
+            handler: Box::new(|_ctx: Context, tasks: Vec<Task>| async move {
+                let mut outcomes: BatchOutcome<anyhow::Error> = HashMap::new();
+                for task in tasks {
+                    let result = match task.args["index"].as_i64() {
+                        Some(0) => Err(anyhow!("index 0 failed")),
+                        Some(1) => Ok(TaskState::Completed),
+                        _ => continue,
+                    };
+                    outcomes.insert(task.id, result);
+                }
+                Ok(outcomes)
+            }),
+            size: 10,
+            window: Duration::from_millis(0),
+        };
+
+        let tasks = TaskService::get_batch_tasks(&prepare.pool, &prepare.name, "batch_task", 10)
+            .await
+            .unwrap();
+        assert_eq!(tasks.len(), 3);
+
+        let ids = tasks.iter().map(|task| task.id).collect::<Vec<_>>();
+
+        let store: Arc<dyn TaskStore> = Arc::new(PgTaskStore::new(&prepare.pool));
+        run_batch(&store, tasks, &route, &Context::new(), &prepare.name).await;
+
+        let tasks = TaskService::get_all(&prepare.pool, &ids).await.unwrap();
+        let state_of = |index: i64| {
+            tasks
+                .iter()
+                .find(|task| task.args["index"].as_i64() == Some(index))
+                .map(|task| task.state.clone())
+        };
+
+        assert_eq!(state_of(0), Some(TaskState::Retryable));
+        assert_eq!(state_of(1), Some(TaskState::Completed));
+        assert_eq!(state_of(2), Some(TaskState::Retryable));
+
+        utils::test::cleanup(prepare).await;
+    }
+}
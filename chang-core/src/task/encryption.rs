@@ -0,0 +1,480 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use uuid::Uuid;
+
+use super::FromTaskContext;
+use crate::db::tasks::Task;
+use crate::task::DEFAULT_QUEUE;
+use crate::utils::context::Context;
+
+/// The args field holding the encrypted part of the args.
+pub const ENCRYPTED_FIELD: &str = "$encrypted";
+
+/// A 256 bit AES-GCM key.
+#[derive(Clone, PartialEq, Eq)]
+pub struct EncryptionKey([u8; 32]);
+
+impl EncryptionKey {
+    pub fn new(bytes: [u8; 32]) -> Self {
+        EncryptionKey(bytes)
+    }
+
+    pub fn from_base64(key: &str) -> Result<Self, EncryptionError> {
+        let bytes = STANDARD
+            .decode(key)
+            .map_err(|_| EncryptionError::InvalidKey)?;
+        let bytes: [u8; 32] = bytes.try_into().map_err(|_| EncryptionError::InvalidKey)?;
+        Ok(EncryptionKey(bytes))
+    }
+
+    pub fn generate() -> Result<Self, EncryptionError> {
+        let mut bytes = [0u8; 32];
+        SystemRandom::new()
+            .fill(&mut bytes)
+            .map_err(|_| EncryptionError::Crypto)?;
+        Ok(EncryptionKey(bytes))
+    }
+
+    fn seal(&self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        let key = self.aead_key()?;
+        let mut nonce = [0u8; NONCE_LEN];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| EncryptionError::Crypto)?;
+
+        let mut in_out = plaintext.to_vec();
+        key.seal_in_place_append_tag(
+            Nonce::assume_unique_for_key(nonce),
+            Aad::from(aad),
+            &mut in_out,
+        )
+        .map_err(|_| EncryptionError::Crypto)?;
+
+        Ok([nonce.as_slice(), &in_out].concat())
+    }
+
+    fn open(&self, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>, EncryptionError> {
+        if sealed.len() < NONCE_LEN {
+            return Err(EncryptionError::Malformed);
+        }
+
+        let key = self.aead_key()?;
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).map_err(|_| EncryptionError::Crypto)?;
+
+        let mut in_out = ciphertext.to_vec();
+        let plaintext = key
+            .open_in_place(nonce, Aad::from(aad), &mut in_out)
+            .map_err(|_| EncryptionError::Crypto)?;
+
+        Ok(plaintext.to_vec())
+    }
+
+    fn aead_key(&self) -> Result<LessSafeKey, EncryptionError> {
+        let key =
+            UnboundKey::new(&AES_256_GCM, &self.0).map_err(|_| EncryptionError::InvalidKey)?;
+        Ok(LessSafeKey::new(key))
+    }
+}
+
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(..)")
+    }
+}
+
+/// Hands out the keys that wrap the per-task data keys. Implement it to
+/// read keys from a secret store, [`Keyring`] holds them in memory.
+pub trait KeyProvider: Send + Sync {
+    /// The key new args are encrypted with.
+    fn current_key_id(&self) -> String;
+
+    fn key(&self, key_id: &str) -> Option<EncryptionKey>;
+}
+
+/// Keys by id. Rotate by adding a new current key and keeping the previous
+/// ones until [`crate::task::TaskService::rewrap_args`] moved every task
+/// over.
+#[derive(Clone, Debug)]
+pub struct Keyring {
+    current: String,
+    keys: HashMap<String, EncryptionKey>,
+}
+
+impl Keyring {
+    pub fn new(key_id: &str, key: EncryptionKey) -> Self {
+        Keyring {
+            current: key_id.to_string(),
+            keys: HashMap::from([(key_id.to_string(), key)]),
+        }
+    }
+
+    /// Adds a key that is only used to decrypt.
+    pub fn with_key(mut self, key_id: &str, key: EncryptionKey) -> Self {
+        self.keys.insert(key_id.to_string(), key);
+        self
+    }
+
+    /// Encrypts with `key_id` from now on, the previous keys are kept.
+    pub fn rotate(mut self, key_id: &str, key: EncryptionKey) -> Self {
+        self.keys.insert(key_id.to_string(), key);
+        self.current = key_id.to_string();
+        self
+    }
+}
+
+impl KeyProvider for Keyring {
+    fn current_key_id(&self) -> String {
+        self.current.clone()
+    }
+
+    fn key(&self, key_id: &str) -> Option<EncryptionKey> {
+        self.keys.get(key_id).cloned()
+    }
+}
+
+/// The task encrypted args belong to. It's authenticated with the args, so
+/// args copied into the row of another task fail to decrypt.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ArgsBinding(String);
+
+impl ArgsBinding {
+    /// Deduped tasks are bound to their key instead of their id, so args
+    /// merged into the waiting task of the key still decrypt.
+    pub fn new(queue: &str, kind: &str, task_id: &Uuid, dedupe_key: Option<&str>) -> Self {
+        let task = match dedupe_key {
+            Some(key) => format!("dedupe:{}", key),
+            None => format!("id:{}", task_id),
+        };
+        ArgsBinding(format!("chang:args:{}:{}:{}", queue, kind, task))
+    }
+
+    pub fn of(task: &Task) -> Self {
+        ArgsBinding::new(
+            task.queue.as_deref().unwrap_or(DEFAULT_QUEUE),
+            &task.kind,
+            &task.id,
+            task.dedupe_key.as_deref(),
+        )
+    }
+
+    fn key_aad(&self, key_id: &str) -> Vec<u8> {
+        format!("{}:{}", self.0, key_id).into_bytes()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Envelope {
+    key_id: String,
+    /// The data key, sealed with the provider's key.
+    key: String,
+    data: String,
+}
+
+/// Envelope encryption for task args. Every task gets a random data key that
+/// encrypts its args, the data key itself is stored sealed with a key of the
+/// [`KeyProvider`].
+///
+/// Encrypted args are an object with the sealed args in [`ENCRYPTED_FIELD`]
+/// and, next to it, the fields that were kept in plain text so they can be
+/// queried.
+#[derive(Clone)]
+pub struct ArgsEncryption {
+    provider: Arc<dyn KeyProvider>,
+}
+
+impl ArgsEncryption {
+    pub fn new(provider: impl KeyProvider + 'static) -> Self {
+        ArgsEncryption {
+            provider: Arc::new(provider),
+        }
+    }
+
+    pub fn is_encrypted(args: &serde_json::Value) -> bool {
+        args.get(ENCRYPTED_FIELD).is_some()
+    }
+
+    /// Encrypts `args` for the task of `binding` except for the top level
+    /// `plain_fields`, which need `args` to be an object.
+    pub fn encrypt(
+        &self,
+        args: serde_json::Value,
+        plain_fields: &[String],
+        binding: &ArgsBinding,
+    ) -> Result<serde_json::Value, EncryptionError> {
+        let (secret, mut plain) = match args {
+            serde_json::Value::Object(mut fields) if !plain_fields.is_empty() => {
+                let plain = plain_fields
+                    .iter()
+                    .filter_map(|name| fields.remove_entry(name))
+                    .collect::<serde_json::Map<_, _>>();
+                (serde_json::Value::Object(fields), plain)
+            }
+            _ if !plain_fields.is_empty() => return Err(EncryptionError::NotAnObject),
+            args => (args, serde_json::Map::new()),
+        };
+
+        let key_id = self.provider.current_key_id();
+        let key = self.key(&key_id)?;
+        let data_key = EncryptionKey::generate()?;
+
+        let envelope = Envelope {
+            key: STANDARD.encode(key.seal(&data_key.0, &binding.key_aad(&key_id))?),
+            data: STANDARD
+                .encode(data_key.seal(&serde_json::to_vec(&secret)?, binding.0.as_bytes())?),
+            key_id,
+        };
+
+        plain.insert(ENCRYPTED_FIELD.to_string(), serde_json::to_value(envelope)?);
+        Ok(serde_json::Value::Object(plain))
+    }
+
+    /// Encrypts `args` the way `template` was, keeping the same fields in
+    /// plain text. Returns `args` as they are if `template` isn't encrypted.
+    pub fn encrypt_like(
+        &self,
+        template: &serde_json::Value,
+        args: serde_json::Value,
+        binding: &ArgsBinding,
+    ) -> Result<serde_json::Value, EncryptionError> {
+        let Some(fields) = template
+            .as_object()
+            .filter(|_| Self::is_encrypted(template))
+        else {
+            return Ok(args);
+        };
+
+        let plain_fields = fields
+            .keys()
+            .filter(|name| *name != ENCRYPTED_FIELD)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        self.encrypt(args, &plain_fields, binding)
+    }
+
+    /// Returns args that aren't encrypted as they are.
+    pub fn decrypt(
+        &self,
+        args: serde_json::Value,
+        binding: &ArgsBinding,
+    ) -> Result<serde_json::Value, EncryptionError> {
+        let serde_json::Value::Object(mut plain) = args else {
+            return Ok(args);
+        };
+        let Some(envelope) = plain.remove(ENCRYPTED_FIELD) else {
+            return Ok(serde_json::Value::Object(plain));
+        };
+
+        let envelope: Envelope =
+            serde_json::from_value(envelope).map_err(|_| EncryptionError::Malformed)?;
+        let data_key = self.open_data_key(&envelope, binding)?;
+        let data = STANDARD
+            .decode(&envelope.data)
+            .map_err(|_| EncryptionError::Malformed)?;
+        let secret: serde_json::Value =
+            serde_json::from_slice(&data_key.open(&data, binding.0.as_bytes())?)?;
+
+        match secret {
+            serde_json::Value::Object(mut fields) => {
+                fields.extend(plain);
+                Ok(serde_json::Value::Object(fields))
+            }
+            secret => Ok(secret),
+        }
+    }
+
+    /// Seals the data key of encrypted args with the current key, leaving
+    /// the encrypted args untouched. Returns `None` if there is nothing to
+    /// do.
+    pub fn rewrap(
+        &self,
+        args: &serde_json::Value,
+        binding: &ArgsBinding,
+    ) -> Result<Option<serde_json::Value>, EncryptionError> {
+        let Some(envelope) = args.get(ENCRYPTED_FIELD) else {
+            return Ok(None);
+        };
+
+        let mut envelope: Envelope =
+            serde_json::from_value(envelope.clone()).map_err(|_| EncryptionError::Malformed)?;
+        let key_id = self.provider.current_key_id();
+        if envelope.key_id == key_id {
+            return Ok(None);
+        }
+
+        let data_key = self.open_data_key(&envelope, binding)?;
+        let key = self.key(&key_id)?;
+        envelope.key = STANDARD.encode(key.seal(&data_key.0, &binding.key_aad(&key_id))?);
+        envelope.key_id = key_id;
+
+        let mut args = args.clone();
+        args[ENCRYPTED_FIELD] = serde_json::to_value(envelope)?;
+        Ok(Some(args))
+    }
+
+    pub fn current_key_id(&self) -> String {
+        self.provider.current_key_id()
+    }
+
+    fn open_data_key(
+        &self,
+        envelope: &Envelope,
+        binding: &ArgsBinding,
+    ) -> Result<EncryptionKey, EncryptionError> {
+        let key = self.key(&envelope.key_id)?;
+        let sealed = STANDARD
+            .decode(&envelope.key)
+            .map_err(|_| EncryptionError::Malformed)?;
+        let bytes = key.open(&sealed, &binding.key_aad(&envelope.key_id))?;
+        let bytes: [u8; 32] = bytes.try_into().map_err(|_| EncryptionError::Malformed)?;
+        Ok(EncryptionKey(bytes))
+    }
+
+    fn key(&self, key_id: &str) -> Result<EncryptionKey, EncryptionError> {
+        self.provider
+            .key(key_id)
+            .ok_or_else(|| EncryptionError::UnknownKey {
+                key_id: key_id.to_string(),
+            })
+    }
+}
+
+impl fmt::Debug for ArgsEncryption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ArgsEncryption")
+            .field("current_key_id", &self.provider.current_key_id())
+            .finish()
+    }
+}
+
+impl FromTaskContext for ArgsEncryption {
+    type Error = EncryptionError;
+
+    fn from_context(ctx: &Context) -> Result<Self, Self::Error> {
+        ctx.get::<ArgsEncryption>()
+            .ok_or(EncryptionError::EncryptionNotFound)
+            .cloned()
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum EncryptionError {
+    #[error("ArgsEncryption not found in Context")]
+    EncryptionNotFound,
+
+    #[error("no key with id {key_id}")]
+    UnknownKey { key_id: String },
+
+    #[error("keys have to be 32 bytes")]
+    InvalidKey,
+
+    #[error("only object args can keep plain fields")]
+    NotAnObject,
+
+    #[error("malformed encrypted args")]
+    Malformed,
+
+    #[error("failed to encrypt or decrypt args")]
+    Crypto,
+
+    #[error(transparent)]
+    Serde(#[from] serde_json::Error),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn key(byte: u8) -> EncryptionKey {
+        EncryptionKey::new([byte; 32])
+    }
+
+    fn binding(task_id: &Uuid) -> ArgsBinding {
+        ArgsBinding::new(DEFAULT_QUEUE, "send_email", task_id, None)
+    }
+
+    #[test]
+    fn keeps_plain_fields_queryable() {
+        let encryption = ArgsEncryption::new(Keyring::new("k1", key(1)));
+        let args = serde_json::json!({ "tenant": "acme", "token": "secret" });
+        let binding = binding(&Uuid::new_v4());
+
+        let encrypted = encryption
+            .encrypt(args.clone(), &["tenant".to_string()], &binding)
+            .unwrap();
+
+        assert_eq!(encrypted["tenant"], "acme");
+        assert!(encrypted.get("token").is_none());
+        assert!(!encrypted.to_string().contains("secret"));
+        assert_eq!(encryption.decrypt(encrypted, &binding).unwrap(), args);
+
+        let scalar = encryption
+            .encrypt(serde_json::json!("secret"), &[], &binding)
+            .unwrap();
+        assert_eq!(
+            encryption.decrypt(scalar, &binding).unwrap(),
+            serde_json::json!("secret")
+        );
+    }
+
+    #[test]
+    fn decrypts_with_rotated_keys() {
+        let old = ArgsEncryption::new(Keyring::new("k1", key(1)));
+        let args = serde_json::json!({ "token": "secret" });
+        let binding = binding(&Uuid::new_v4());
+        let encrypted = old.encrypt(args.clone(), &[], &binding).unwrap();
+
+        let rotated = ArgsEncryption::new(Keyring::new("k1", key(1)).rotate("k2", key(2)));
+        assert_eq!(rotated.decrypt(encrypted.clone(), &binding).unwrap(), args);
+
+        let rewrapped = rotated.rewrap(&encrypted, &binding).unwrap().unwrap();
+        assert_eq!(rewrapped[ENCRYPTED_FIELD]["key_id"], "k2");
+        assert_eq!(
+            rewrapped[ENCRYPTED_FIELD]["data"],
+            encrypted[ENCRYPTED_FIELD]["data"]
+        );
+        assert!(rotated.rewrap(&rewrapped, &binding).unwrap().is_none());
+
+        let new_only = ArgsEncryption::new(Keyring::new("k2", key(2)));
+        assert_eq!(new_only.decrypt(rewrapped, &binding).unwrap(), args);
+        assert!(matches!(
+            new_only.decrypt(encrypted, &binding),
+            Err(EncryptionError::UnknownKey { .. })
+        ));
+    }
+
+    #[test]
+    fn binds_args_to_their_task() {
+        let encryption = ArgsEncryption::new(Keyring::new("k1", key(1)));
+        let args = serde_json::json!({ "token": "secret" });
+        let task_id = Uuid::new_v4();
+        let encrypted = encryption
+            .encrypt(args.clone(), &[], &binding(&task_id))
+            .unwrap();
+
+        for other in [
+            binding(&Uuid::new_v4()),
+            ArgsBinding::new("other", "send_email", &task_id, None),
+            ArgsBinding::new(DEFAULT_QUEUE, "charge_card", &task_id, None),
+        ] {
+            assert!(matches!(
+                encryption.decrypt(encrypted.clone(), &other),
+                Err(EncryptionError::Crypto)
+            ));
+        }
+
+        let debounced = ArgsBinding::new(DEFAULT_QUEUE, "send_email", &task_id, Some("user:1"));
+        let encrypted = encryption.encrypt(args.clone(), &[], &debounced).unwrap();
+        let waiting =
+            ArgsBinding::new(DEFAULT_QUEUE, "send_email", &Uuid::new_v4(), Some("user:1"));
+        assert_eq!(encryption.decrypt(encrypted, &waiting).unwrap(), args);
+    }
+}
@@ -0,0 +1,248 @@
+use std::time::Duration;
+
+use log::error;
+use std::error::Error;
+use std::fmt::Debug;
+use std::sync::Arc;
+use tokio::time;
+use tokio::{self, select};
+use tokio_util::sync::CancellationToken;
+
+use crate::task::advisory_lock::{exclusive_lock_name, AdvisoryLock};
+use crate::task::run_task::{run_task, TaskRouter};
+use crate::task::store::TaskStore;
+use crate::task::{TaskQueue, TaskService};
+use crate::utils::context::Context;
+
+/// Runs tasks of an exclusive `kind` one at a time. With a Postgres store the
+/// advisory lock for the kind is taken before a task is claimed, so no other
+/// exclusive loop claims one while it's held, and the kind is limited to one
+/// running task, so no other claim takes one while a task runs.
+pub async fn start<E>(
+    label: &str,
+    cancel_token: &CancellationToken,
+    store: &Arc<dyn TaskStore>,
+    queue: &TaskQueue,
+    kind: &str,
+    router: &TaskRouter<E>,
+    context: &Context,
+) where
+    E: Into<Box<dyn Error + Send + Sync>> + std::fmt::Display + Debug + 'static,
+{
+    let mut interval = time::interval(Duration::from_millis(queue.interval));
+
+    limit_kind(label, store, kind).await;
+
+    loop {
+        if cancel_token.is_cancelled() || store.is_closed() {
+            break;
+        }
+
+        let ran = run_next(label, store, queue, kind, router, context).await;
+
+        if !ran {
+            interval.tick().await;
+            continue;
+        }
+
+        select! {
+            _ = cancel_token.cancelled() => {
+                break;
+            }
+
+            _ = store.closed() => {
+                break;
+            }
+
+            _ = interval.tick() => {
+                continue;
+            }
+        }
+    }
+}
+
+/// Limits `kind` to one running task, which every claim checks, so runners
+/// that don't mark the kind exclusive don't claim it while a task runs.
+async fn limit_kind(label: &str, store: &Arc<dyn TaskStore>, kind: &str) {
+    let Some(db) = store.pool() else {
+        return;
+    };
+
+    if let Err(err) = TaskService::set_kind_limit(db, kind, 1).await {
+        error!("[{}] failed to limit kind({}) {:?}", label, kind, err);
+    }
+}
+
+/// Claims and runs one task of `kind` while holding its lock. Returns `false`
+/// if the lock is taken or there is nothing to run.
+pub async fn run_next<E>(
+    label: &str,
+    store: &Arc<dyn TaskStore>,
+    queue: &TaskQueue,
+    kind: &str,
+    router: &TaskRouter<E>,
+    context: &Context,
+) -> bool
+where
+    E: Into<Box<dyn Error + Send + Sync>> + std::fmt::Display + Debug + 'static,
+{
+    let lock_name = exclusive_lock_name(kind);
+    // without Postgres there are no other runners to exclude
+    let lock = match store.pool() {
+        None => None,
+        Some(db) => match AdvisoryLock::try_acquire(db, &lock_name).await {
+            Ok(Some(lock)) => Some(lock),
+            Ok(None) => return false,
+            Err(err) => {
+                error!(
+                    "[{}] task error: failed to lock {} {:?}",
+                    label, lock_name, err
+                );
+                return false;
+            }
+        },
+    };
+
+    let task = match store.claim_kind(&queue.name, kind, 1).await {
+        Ok(tasks) => tasks.into_iter().next(),
+        Err(err) => {
+            error!("[{}] task error: failed to fetch tasks {:?}", label, err);
+            None
+        }
+    };
+
+    let ran = task.is_some();
+    if let Some(task) = task {
+        run_task::<E>(store, task, router, context, label).await;
+    }
+
+    if let Some(lock) = lock {
+        if let Err(err) = lock.release().await {
+            error!("[{}] failed to release {} {:?}", label, lock_name, err);
+        }
+    }
+
+    ran
+}
+
+#[cfg(test)]
+mod test {
+    use sqlx::postgres::PgPoolOptions;
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::db::migration;
+    use crate::task::{PgTaskStore, Task, TaskService, TaskState};
+    use crate::utils;
+
+    #[tokio::test]
+    async fn skips_kind_while_locked() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::tasks(&prepare.pool).await;
+
+        // the lock holds on to a connection of its own
+        let db = PgPoolOptions::new()
+            .max_connections(3)
+            .connect(&prepare.connection_string.to_string())
+            .await
+            .unwrap();
+
+        let queue = TaskQueue::builder().name(&prepare.name).build();
+        let id = Task::builder()
+            .kind("reconcile")
+            .args(serde_json::Value::Null)
+            .queue(&prepare.name)
+            .build()
+            .unwrap()
+            .insert(&db)
+            .await
+            .unwrap();
+
+        let mut router: TaskRouter<anyhow::Error> = HashMap::new();
+        router.insert(
+            "reconcile".to_string(),
+            Box::new(|_ctx: Context| async { Ok(TaskState::Completed) }),
+        );
+        let context = Context::new();
+
+        let store: Arc<dyn TaskStore> = Arc::new(PgTaskStore::new(&db));
+
+        let lock_name = exclusive_lock_name("reconcile");
+        let mut leader = AdvisoryLock::try_acquire(&db, &lock_name)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(AdvisoryLock::try_acquire(&db, &lock_name)
+            .await
+            .unwrap()
+            .is_none());
+
+        let ran = run_next(
+            &prepare.name,
+            &store,
+            &queue,
+            "reconcile",
+            &router,
+            &context,
+        )
+        .await;
+        assert!(!ran, "claimed a task of a locked kind");
+        assert!(leader.is_held().await);
+
+        leader.release().await.unwrap();
+
+        let ran = run_next(
+            &prepare.name,
+            &store,
+            &queue,
+            "reconcile",
+            &router,
+            &context,
+        )
+        .await;
+        assert!(ran);
+
+        let task = TaskService::get_task(&db, &id).await.unwrap().unwrap();
+        assert_eq!(task.state, TaskState::Completed);
+
+        db.close().await;
+        utils::test::cleanup(prepare).await;
+    }
+
+    #[tokio::test]
+    async fn other_claims_skip_a_running_exclusive_kind() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::tasks(&prepare.pool).await;
+
+        for _ in 0..2 {
+            Task::builder()
+                .kind("reconcile")
+                .args(serde_json::Value::Null)
+                .queue(&prepare.name)
+                .build()
+                .unwrap()
+                .insert(&prepare.pool)
+                .await
+                .unwrap();
+        }
+
+        let store: Arc<dyn TaskStore> = Arc::new(PgTaskStore::new(&prepare.pool));
+        limit_kind(&prepare.name, &store, "reconcile").await;
+
+        // a runner that doesn't mark the kind exclusive
+        let claim = || TaskService::get_tasks(&prepare.pool, &prepare.name, 10, &[]);
+        assert_eq!(claim().await.unwrap().len(), 1);
+        assert!(claim().await.unwrap().is_empty());
+        assert!(store
+            .claim_kind(&prepare.name, "reconcile", 1)
+            .await
+            .unwrap()
+            .is_empty());
+
+        utils::test::cleanup(prepare).await;
+    }
+}
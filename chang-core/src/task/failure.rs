@@ -0,0 +1,174 @@
+use serde::Serialize;
+use std::any::Any;
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+
+type BoxError = Box<dyn Error + Send + Sync>;
+
+/// Discards the task without retrying it, for errors another attempt can't
+/// fix like invalid args or a missing resource. Found anywhere in the chain
+/// of the handler's error, so it can be wrapped into an `anyhow::Error`.
+pub struct Permanent(pub BoxError);
+
+impl Permanent {
+    pub fn new(error: impl Into<BoxError>) -> Self {
+        Permanent(error.into())
+    }
+}
+
+impl fmt::Display for Permanent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+impl fmt::Debug for Permanent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.0, f)
+    }
+}
+
+impl Error for Permanent {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.0.source()
+    }
+}
+
+/// A transient error that retries the task after `delay` instead of on the
+/// next claim, `details` are stored with the error in `chang.task_error`.
+/// The task is still discarded once it used up its attempts.
+pub struct Retry {
+    pub error: BoxError,
+    pub delay: Option<Duration>,
+    pub details: Option<serde_json::Value>,
+}
+
+impl Retry {
+    pub fn new(error: impl Into<BoxError>) -> Self {
+        Retry {
+            error: error.into(),
+            delay: None,
+            details: None,
+        }
+    }
+
+    pub fn after(mut self, delay: Duration) -> Self {
+        self.delay = Some(delay);
+        self
+    }
+
+    /// Details that don't serialize are left out.
+    pub fn details(mut self, details: impl Serialize) -> Self {
+        self.details = serde_json::to_value(details).ok();
+        self
+    }
+}
+
+impl fmt::Display for Retry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.error, f)
+    }
+}
+
+impl fmt::Debug for Retry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.error, f)
+    }
+}
+
+impl Error for Retry {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.error.source()
+    }
+}
+
+/// How a failed run is stored, see [`crate::task::TaskStore::fail`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Failure {
+    pub error: String,
+    /// Discards the task even if it has attempts left.
+    pub permanent: bool,
+    /// Delays the retry, which otherwise happens on the next claim.
+    pub retry_after: Option<Duration>,
+    pub details: Option<serde_json::Value>,
+}
+
+impl Failure {
+    /// A transient failure without details.
+    pub fn new(error: &str) -> Self {
+        Failure {
+            error: error.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Classifies a handler error by the first [`Permanent`] or [`Retry`] in
+    /// its chain of sources, other errors are transient.
+    pub fn from_error<E>(err: E) -> Self
+    where
+        E: Into<BoxError> + fmt::Debug + 'static,
+    {
+        let error = format!("{:?}", err);
+
+        // converting to a box hides the error anyhow wraps, its chain doesn't
+        if let Some(err) = (&err as &dyn Any).downcast_ref::<anyhow::Error>() {
+            return Failure::classify(&error, err.chain());
+        }
+
+        let err: BoxError = err.into();
+        let top: &(dyn Error + 'static) = &*err;
+        let chain =
+            std::iter::successors(Some(top), |err: &&(dyn Error + 'static)| (*err).source());
+        Failure::classify(&error, chain)
+    }
+
+    fn classify<'a>(error: &str, chain: impl Iterator<Item = &'a (dyn Error + 'static)>) -> Self {
+        let mut failure = Failure::new(error);
+
+        for source in chain {
+            if source.is::<Permanent>() {
+                failure.permanent = true;
+                break;
+            }
+            if let Some(retry) = source.downcast_ref::<Retry>() {
+                failure.retry_after = retry.delay;
+                failure.details.clone_from(&retry.details);
+                break;
+            }
+        }
+
+        failure
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn classifies_wrapped_errors() {
+        let permanent = anyhow::Error::from(Permanent::new("user not found"));
+        assert!(Failure::from_error(permanent).permanent);
+
+        let permanent =
+            anyhow::Error::from(Permanent::new("user not found")).context("sync failed");
+        assert!(Failure::from_error(permanent).permanent);
+
+        let retry: BoxError = Retry::new("rate limited")
+            .after(Duration::from_secs(30))
+            .details(serde_json::json!({ "status": 429 }))
+            .into();
+        let failure = Failure::from_error(retry);
+        assert!(!failure.permanent);
+        assert_eq!(failure.error, "\"rate limited\"");
+        assert_eq!(failure.retry_after, Some(Duration::from_secs(30)));
+        assert_eq!(failure.details, Some(serde_json::json!({ "status": 429 })));
+
+        let other: BoxError = "connection reset".into();
+        assert_eq!(
+            Failure::from_error(other),
+            Failure::new("\"connection reset\"")
+        );
+    }
+}
@@ -0,0 +1,90 @@
+use std::fmt;
+use std::sync::Arc;
+use tokio::sync::OnceCell;
+use uuid::Uuid;
+
+use super::store::TaskStore;
+use super::FromTaskContext;
+use crate::db::payloads::Payload;
+use crate::utils::context::Context;
+
+/// Args that were offloaded with [`crate::task::TaskBuilder::offload_above`].
+/// The claim leaves them in the store, they are read with [`LazyArgs::load`]
+/// before the handler runs, after that [`crate::task::CurrentTask`] and
+/// derived tasks extract them like inline args. Shared by the clones of the
+/// context, so the args are loaded once.
+#[derive(Clone)]
+pub struct LazyArgs {
+    payload_id: Option<Uuid>,
+    store: Arc<dyn TaskStore>,
+    loaded: Arc<OnceCell<serde_json::Value>>,
+}
+
+impl LazyArgs {
+    pub fn new(args: &serde_json::Value, store: &Arc<dyn TaskStore>) -> Self {
+        LazyArgs {
+            payload_id: Payload::reference(args),
+            store: store.clone(),
+            loaded: Arc::new(OnceCell::new()),
+        }
+    }
+
+    pub fn is_offloaded(&self) -> bool {
+        self.payload_id.is_some()
+    }
+
+    /// The offloaded args once they were loaded.
+    pub fn loaded(&self) -> Option<&serde_json::Value> {
+        self.loaded.get()
+    }
+
+    /// Loads the args, does nothing if they aren't offloaded or were
+    /// already loaded.
+    pub async fn load(&self) -> Result<(), LazyArgsError> {
+        let Some(payload_id) = self.payload_id else {
+            return Ok(());
+        };
+
+        self.loaded
+            .get_or_try_init(|| async {
+                self.store
+                    .load_payload(&payload_id)
+                    .await?
+                    .ok_or(LazyArgsError::PayloadNotFound(payload_id))
+            })
+            .await?;
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for LazyArgs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LazyArgs")
+            .field("payload_id", &self.payload_id)
+            .field("loaded", &self.loaded.initialized())
+            .finish()
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum LazyArgsError {
+    #[error("LazyArgs not found in Context")]
+    LazyArgsNotFound,
+
+    #[error("payload {0} not found")]
+    PayloadNotFound(Uuid),
+
+    #[error(transparent)]
+    Db(#[from] sqlx::Error),
+}
+
+impl FromTaskContext for LazyArgs {
+    type Error = LazyArgsError;
+
+    fn from_context(ctx: &Context) -> Result<Self, Self::Error> {
+        ctx.get::<LazyArgs>()
+            .ok_or(LazyArgsError::LazyArgsNotFound)
+            .cloned()
+    }
+}
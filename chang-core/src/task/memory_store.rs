@@ -0,0 +1,768 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use uuid::Uuid;
+
+use super::failure::Failure;
+use super::store::TaskStore;
+use crate::db::tasks::{DedupeMode, MergeArgs, NewTask, Task, TaskState};
+use crate::task::queue::{SchedulingStrategy, TaskQueue};
+use crate::task::DEFAULT_QUEUE;
+
+/// Keeps tasks in memory and moves them through the same states as the
+/// Postgres queries: claims respect `scheduled_at`, dependencies and the
+/// queue's strategy, attempts are counted on claim and a failed task is
+/// discarded once it used them up, as is a task past its `expires_at`. Kind
+/// limits aren't enforced.
+///
+/// Tasks are due against the system clock until [`MemoryTaskStore::set_now`]
+/// pins the store's clock, which then only moves on
+/// [`MemoryTaskStore::advance`].
+#[derive(Debug, Default)]
+pub struct MemoryTaskStore {
+    state: Mutex<MemoryState>,
+    clock: Mutex<Option<DateTime<Utc>>>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryState {
+    /// In insertion order, which breaks ties between equal `scheduled_at`.
+    tasks: Vec<Task>,
+    errors: HashMap<Uuid, Vec<String>>,
+    payloads: HashMap<Uuid, serde_json::Value>,
+}
+
+impl MemoryTaskStore {
+    pub fn new() -> Self {
+        MemoryTaskStore::default()
+    }
+
+    /// All tasks in the order they were inserted.
+    pub fn tasks(&self) -> Vec<Task> {
+        self.lock().tasks.clone()
+    }
+
+    /// The errors the task failed with, oldest first.
+    pub fn errors(&self, task_id: &Uuid) -> Vec<String> {
+        self.lock().errors.get(task_id).cloned().unwrap_or_default()
+    }
+
+    pub fn set_now(&self, now: DateTime<Utc>) {
+        *self.clock.lock().expect("failed to lock clock") = Some(now);
+    }
+
+    /// Moves the clock forward, pinning it first if it isn't yet.
+    pub fn advance(&self, duration: std::time::Duration) {
+        let mut clock = self.clock.lock().expect("failed to lock clock");
+        let now = clock.unwrap_or_else(Utc::now);
+        *clock = Some(now + duration);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MemoryState> {
+        self.state.lock().expect("failed to lock memory store")
+    }
+}
+
+impl MemoryState {
+    fn task(&self, task_id: &Uuid) -> sqlx::Result<&Task> {
+        self.tasks
+            .iter()
+            .find(|task| task.id == *task_id)
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    fn task_mut(&mut self, task_id: &Uuid) -> sqlx::Result<&mut Task> {
+        self.tasks
+            .iter_mut()
+            .find(|task| task.id == *task_id)
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    /// Discards the waiting tasks of `queue` that expired at `now`.
+    fn expire(&mut self, queue: &str, now: &DateTime<Utc>) {
+        for task in self.tasks.iter_mut() {
+            if task.queue.as_deref() == Some(queue)
+                && task.expires_at.is_some_and(|at| at <= *now)
+                && matches!(task.state, TaskState::Available | TaskState::Retryable)
+            {
+                task.state = TaskState::Discarded;
+            }
+        }
+    }
+
+    /// Indexes of the tasks of `queue` that can run at `now`.
+    fn available(
+        &self,
+        queue: &str,
+        now: &DateTime<Utc>,
+        accept: impl Fn(&Task) -> bool,
+    ) -> Vec<usize> {
+        let pending_dependencies = self
+            .tasks
+            .iter()
+            .filter(|task| {
+                matches!(
+                    task.state,
+                    TaskState::Running
+                        | TaskState::Scheduled
+                        | TaskState::Available
+                        | TaskState::Retryable
+                )
+            })
+            .filter_map(|task| task.dependend_id)
+            .collect::<HashSet<Uuid>>();
+
+        self.tasks
+            .iter()
+            .enumerate()
+            .filter(|(_, task)| {
+                task.queue.as_deref() == Some(queue)
+                    && task.scheduled_at.is_some_and(|at| at <= *now)
+                    && matches!(task.state, TaskState::Available | TaskState::Retryable)
+                    && task
+                        .depends_on
+                        .map(|id| !pending_dependencies.contains(&id))
+                        .unwrap_or(true)
+                    && accept(task)
+            })
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    fn start(&mut self, indexes: &[usize]) -> Vec<Task> {
+        indexes
+            .iter()
+            .map(|index| {
+                let task = &mut self.tasks[*index];
+                task.state = TaskState::Running;
+                task.attempt += 1;
+                task.clone()
+            })
+            .collect()
+    }
+}
+
+/// Holds the dedupe key of the task, see the `chang_task_dedupe_key` index.
+fn is_waiting(task: &Task) -> bool {
+    matches!(task.state, TaskState::Available | TaskState::Scheduled)
+}
+
+/// Merges like `jsonb ||`: objects field by field, everything else into an
+/// array.
+fn merge_args(waiting: serde_json::Value, args: serde_json::Value) -> serde_json::Value {
+    use serde_json::Value;
+
+    match (waiting, args) {
+        (Value::Object(mut waiting), Value::Object(args)) => {
+            waiting.extend(args);
+            Value::Object(waiting)
+        }
+        (waiting, args) => {
+            let as_array = |value| match value {
+                Value::Array(items) => items,
+                value => vec![value],
+            };
+            let mut items = as_array(waiting);
+            items.extend(as_array(args));
+            Value::Array(items)
+        }
+    }
+}
+
+#[async_trait]
+impl TaskStore for MemoryTaskStore {
+    async fn insert(&self, task: NewTask) -> sqlx::Result<Uuid> {
+        let id = task.id;
+        let now = self.now();
+        let queue = task.queue.unwrap_or_else(|| DEFAULT_QUEUE.to_string());
+        let mut scheduled_at = task.scheduled_at.unwrap_or(now);
+
+        let mut state = self.lock();
+        if let Some(payload) = task.payload {
+            state.payloads.insert(payload.id, payload.payload);
+        }
+
+        if let Some(dedupe) = &task.dedupe {
+            let same_key = |other: &Task| {
+                other.queue.as_deref() == Some(&queue)
+                    && other.kind == task.kind
+                    && other.dedupe_key.as_deref() == Some(&dedupe.key)
+            };
+
+            match dedupe.mode {
+                DedupeMode::Debounce { delay } => scheduled_at = now + delay,
+                DedupeMode::Throttle { window } => {
+                    let last = state
+                        .tasks
+                        .iter()
+                        .filter(|other| {
+                            same_key(other)
+                                && other.state != TaskState::Cancelled
+                                && !is_waiting(other)
+                        })
+                        .filter_map(|other| other.scheduled_at)
+                        .max();
+                    if let Some(last) = last {
+                        scheduled_at = scheduled_at.max(last + window);
+                    }
+                }
+            }
+
+            let waiting = state
+                .tasks
+                .iter_mut()
+                .find(|other| same_key(other) && is_waiting(other));
+            if let Some(waiting) = waiting {
+                if dedupe.merge == MergeArgs::Merge && waiting.args_version != task.args_version {
+                    return Err(sqlx::Error::Protocol(format!(
+                        "cannot merge args of version {} into args of version {}",
+                        task.args_version, waiting.args_version
+                    )));
+                }
+                waiting.scheduled_at = match dedupe.mode {
+                    DedupeMode::Debounce { .. } => Some(scheduled_at),
+                    DedupeMode::Throttle { .. } => waiting.scheduled_at.max(Some(scheduled_at)),
+                };
+                waiting.args = match dedupe.merge {
+                    MergeArgs::Replace => {
+                        waiting.args_version = task.args_version;
+                        task.args
+                    }
+                    MergeArgs::Keep => waiting.args.take(),
+                    MergeArgs::Merge => merge_args(waiting.args.take(), task.args),
+                };
+                return Ok(waiting.id);
+            }
+        }
+
+        state.tasks.push(Task {
+            id,
+            state: TaskState::Available,
+            scheduled_at: Some(scheduled_at),
+            attempt: 0,
+            max_attempts: task.max_attempts,
+            attempted_by: Some(task.attempted_by),
+            tags: Some(task.tags),
+            kind: task.kind,
+            args: task.args,
+            priority: task.priority,
+            queue: Some(queue),
+            depends_on: task.depends_on,
+            dependend_id: task.dependend_id,
+            fairness_key: task.fairness_key,
+            timeout_ms: task.timeout_ms,
+            periodic_name: task.periodic_name,
+            progress: None,
+            progress_message: None,
+            progress_data: None,
+            args_version: task.args_version,
+            expires_at: task.expires_at,
+            dedupe_key: task.dedupe.map(|dedupe| dedupe.key),
+        });
+
+        Ok(id)
+    }
+
+    async fn load_payload(&self, payload_id: &Uuid) -> sqlx::Result<Option<serde_json::Value>> {
+        Ok(self.lock().payloads.get(payload_id).cloned())
+    }
+
+    async fn get_task(&self, task_id: &Uuid) -> sqlx::Result<Option<Task>> {
+        let state = self.lock();
+        Ok(state.tasks.iter().find(|task| task.id == *task_id).cloned())
+    }
+
+    async fn claim(&self, queue: &TaskQueue, excluded_kinds: &[String]) -> sqlx::Result<Vec<Task>> {
+        let now = self.now();
+        let mut state = self.lock();
+        let limit = queue.limit.max(0) as usize;
+
+        state.expire(&queue.name, &now);
+        let mut indexes = state.available(&queue.name, &now, |task| {
+            !excluded_kinds.contains(&task.kind)
+        });
+
+        // sorts are stable, so insertion order stays the last tie breaker
+        let tasks = &state.tasks;
+        let scheduled_at = |index: &usize| tasks[*index].scheduled_at;
+        match queue.strategy {
+            SchedulingStrategy::FCFS => indexes.sort_by_key(scheduled_at),
+            SchedulingStrategy::Priority => {
+                indexes.sort_by_key(|index| (Reverse(tasks[*index].priority), scheduled_at(index)))
+            }
+            SchedulingStrategy::PriorityAging { rate } => {
+                let effective = |index: &usize| {
+                    let task = &tasks[*index];
+                    let waited = task
+                        .scheduled_at
+                        .map(|at| (now - at).num_milliseconds() as f64 / 1000.0)
+                        .unwrap_or_default();
+                    task.priority as f64 + waited * rate
+                };
+                indexes.sort_by(|a, b| {
+                    effective(b)
+                        .total_cmp(&effective(a))
+                        .then_with(|| scheduled_at(a).cmp(&scheduled_at(b)))
+                });
+            }
+            SchedulingStrategy::Fair { max_per_key } => {
+                indexes.sort_by_key(scheduled_at);
+
+                let mut running: HashMap<Option<&str>, i64> = HashMap::new();
+                for task in tasks.iter() {
+                    if task.state == TaskState::Running && task.queue == Some(queue.name.clone()) {
+                        *running.entry(task.fairness_key.as_deref()).or_default() += 1;
+                    }
+                }
+
+                let mut ranks: HashMap<Option<&str>, i64> = HashMap::new();
+                let mut ranked = indexes
+                    .into_iter()
+                    .filter_map(|index| {
+                        let key = tasks[index].fairness_key.as_deref();
+                        let rank = ranks.entry(key).or_default();
+                        *rank += 1;
+                        let running = running.get(&key).copied().unwrap_or_default();
+                        let allowed = max_per_key
+                            .map(|max| *rank + running <= max)
+                            .unwrap_or(true);
+                        allowed.then_some((*rank, index))
+                    })
+                    .collect::<Vec<_>>();
+                ranked.sort_by_key(|(rank, _)| *rank);
+                indexes = ranked.into_iter().map(|(_, index)| index).collect();
+            }
+        };
+
+        indexes.truncate(limit);
+        Ok(state.start(&indexes))
+    }
+
+    async fn claim_kind(&self, queue: &str, kind: &str, limit: i64) -> sqlx::Result<Vec<Task>> {
+        let now = self.now();
+        let mut state = self.lock();
+
+        state.expire(queue, &now);
+        let mut indexes = state.available(queue, &now, |task| task.kind == kind);
+        indexes.sort_by_key(|index| state.tasks[*index].scheduled_at);
+        indexes.truncate(limit.max(0) as usize);
+
+        Ok(state.start(&indexes))
+    }
+
+    async fn count_due(&self, queue: &str, kind: &str, limit: i64) -> sqlx::Result<i64> {
+        let now = self.now();
+        let state = self.lock();
+
+        let due = state
+            .tasks
+            .iter()
+            .filter(|task| {
+                task.queue.as_deref() == Some(queue)
+                    && task.kind == kind
+                    && matches!(task.state, TaskState::Available | TaskState::Retryable)
+                    && task.scheduled_at.is_some_and(|at| at <= now)
+                    && task.expires_at.map(|at| at > now).unwrap_or(true)
+            })
+            .count() as i64;
+
+        Ok(due.min(limit.max(0)))
+    }
+
+    async fn complete(&self, task_id: &Uuid) -> sqlx::Result<()> {
+        let mut state = self.lock();
+        state.task_mut(task_id)?.state = TaskState::Completed;
+        Ok(())
+    }
+
+    async fn fail(&self, task_id: &Uuid, failure: &Failure) -> sqlx::Result<()> {
+        let now = self.now();
+        let mut state = self.lock();
+
+        let task = state.task_mut(task_id)?;
+        if task.attempt < task.max_attempts && !failure.permanent {
+            task.state = TaskState::Retryable;
+            if let Some(delay) = failure.retry_after {
+                task.scheduled_at = Some(now + delay);
+            }
+        } else {
+            task.state = TaskState::Discarded;
+        }
+
+        state
+            .errors
+            .entry(*task_id)
+            .or_default()
+            .push(failure.error.clone());
+
+        Ok(())
+    }
+
+    async fn snooze(
+        &self,
+        task_id: &Uuid,
+        scheduled_at: &DateTime<Utc>,
+        args: Option<serde_json::Value>,
+    ) -> sqlx::Result<()> {
+        let mut state = self.lock();
+
+        let task = state.task(task_id)?;
+        if task.state != TaskState::Running {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        // only one task of a key waits, like snooze.sql
+        let duplicate_waits = task.dedupe_key.is_some()
+            && state.tasks.iter().any(|other| {
+                other.queue == task.queue
+                    && other.kind == task.kind
+                    && other.dedupe_key == task.dedupe_key
+                    && is_waiting(other)
+            });
+
+        let task = state.task_mut(task_id)?;
+        task.state = if duplicate_waits {
+            TaskState::Retryable
+        } else {
+            TaskState::Available
+        };
+        task.scheduled_at = Some(*scheduled_at);
+        task.attempt = (task.attempt - 1).max(0);
+        if let Some(args) = args {
+            task.args = args;
+        }
+
+        Ok(())
+    }
+
+    async fn update_progress(
+        &self,
+        task_id: &Uuid,
+        progress: Option<f32>,
+        message: Option<&str>,
+        data: Option<&serde_json::Value>,
+    ) -> sqlx::Result<bool> {
+        // like the chang_task_progress_range check
+        if progress.is_some_and(|progress| !(0.0..=100.0).contains(&progress)) {
+            return Err(sqlx::Error::Protocol(format!(
+                "progress {:?} is not between 0 and 100",
+                progress
+            )));
+        }
+
+        let mut state = self.lock();
+
+        let Some(task) = state
+            .tasks
+            .iter_mut()
+            .find(|task| task.id == *task_id && task.state == TaskState::Running)
+        else {
+            return Ok(false);
+        };
+
+        task.progress = progress;
+        task.progress_message = message.map(String::from);
+        task.progress_data = data.cloned();
+
+        Ok(true)
+    }
+
+    fn now(&self) -> DateTime<Utc> {
+        self.clock
+            .lock()
+            .expect("failed to lock clock")
+            .unwrap_or_else(Utc::now)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::task::Task;
+
+    fn queue(strategy: SchedulingStrategy) -> TaskQueue {
+        TaskQueue::builder()
+            .name(DEFAULT_QUEUE)
+            .strategy(strategy)
+            .build()
+    }
+
+    #[tokio::test]
+    async fn moves_tasks_through_their_states() {
+        let store = MemoryTaskStore::new();
+
+        let parent = Uuid::new_v4();
+        let first = store
+            .insert(
+                Task::builder()
+                    .kind("first")
+                    .args(serde_json::Value::Null)
+                    .dependend_id(&parent)
+                    .max_attempts(2)
+                    .build()
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let second = store
+            .insert(
+                Task::builder()
+                    .kind("second")
+                    .args(serde_json::Value::Null)
+                    .depends_on(&parent)
+                    .build()
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        store
+            .insert(
+                Task::builder()
+                    .kind("later")
+                    .args(serde_json::Value::Null)
+                    .scheduled_at(&(Utc::now() + chrono::Duration::hours(1)))
+                    .build()
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let fcfs = queue(SchedulingStrategy::FCFS);
+
+        // the second task waits for the first, the last one isn't due yet
+        let claimed = store.claim(&fcfs, &[]).await.unwrap();
+        assert_eq!(claimed.len(), 1);
+        assert_eq!(claimed[0].id, first);
+        assert_eq!(claimed[0].attempt, 1);
+
+        store.failed(&first, "boom").await.unwrap();
+        let task = store.get_task(&first).await.unwrap().unwrap();
+        assert_eq!(task.state, TaskState::Retryable);
+        assert!(store
+            .claim(&fcfs, &["first".into()])
+            .await
+            .unwrap()
+            .is_empty());
+
+        let claimed = store.claim(&fcfs, &[]).await.unwrap();
+        assert_eq!(claimed[0].attempt, 2);
+        store.failed(&first, "boom again").await.unwrap();
+
+        let task = store.get_task(&first).await.unwrap().unwrap();
+        assert_eq!(task.state, TaskState::Discarded);
+        assert_eq!(store.errors(&first), vec!["boom", "boom again"]);
+
+        let claimed = store.claim(&fcfs, &[]).await.unwrap();
+        assert_eq!(claimed.len(), 1);
+        assert_eq!(claimed[0].id, second);
+
+        let later = Utc::now() + chrono::Duration::hours(1);
+        store.snooze(&second, &later, None).await.unwrap();
+        let task = store.get_task(&second).await.unwrap().unwrap();
+        assert_eq!(task.state, TaskState::Available);
+        assert_eq!(task.attempt, 0);
+        assert!(store.claim(&fcfs, &[]).await.unwrap().is_empty());
+        assert!(matches!(
+            store.snooze(&first, &later, None).await,
+            Err(sqlx::Error::RowNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn discards_expired_tasks() {
+        let store = MemoryTaskStore::new();
+        let now = Utc::now();
+        store.set_now(now);
+
+        let id = store
+            .insert(
+                Task::builder()
+                    .kind("send_otp")
+                    .args(serde_json::Value::Null)
+                    .scheduled_at(&(now + chrono::Duration::minutes(5)))
+                    .expires_at(&(now + chrono::Duration::minutes(10)))
+                    .build()
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        store.advance(std::time::Duration::from_secs(15 * 60));
+        let fcfs = queue(SchedulingStrategy::FCFS);
+        assert!(store.claim(&fcfs, &[]).await.unwrap().is_empty());
+
+        let task = store.get_task(&id).await.unwrap().unwrap();
+        assert_eq!(task.state, TaskState::Discarded);
+    }
+
+    #[tokio::test]
+    async fn debounces_inserts() {
+        let store = MemoryTaskStore::new();
+        let now = Utc::now();
+        store.set_now(now);
+
+        let insert = |args: serde_json::Value| {
+            store.insert(
+                Task::builder()
+                    .kind("reindex_user")
+                    .args(args)
+                    .debounce("user-1", std::time::Duration::from_secs(60))
+                    .merge_args(MergeArgs::Merge)
+                    .build()
+                    .unwrap(),
+            )
+        };
+
+        let first = insert(serde_json::json!(["name"])).await.unwrap();
+        store.advance(std::time::Duration::from_secs(30));
+        let second = insert(serde_json::json!(["email"])).await.unwrap();
+        assert_eq!(first, second);
+
+        let task = store.get_task(&first).await.unwrap().unwrap();
+        assert_eq!(task.args, serde_json::json!(["name", "email"]));
+        assert_eq!(task.scheduled_at, Some(now + chrono::Duration::seconds(90)));
+    }
+
+    #[tokio::test]
+    async fn dedupe_keeps_args_and_version_together() {
+        let store = MemoryTaskStore::new();
+
+        let insert = |version: i16, merge: MergeArgs| {
+            store.insert(
+                Task::builder()
+                    .kind("reindex_user")
+                    .args(serde_json::json!({ "version": version }))
+                    .args_version(version)
+                    .debounce("user-1", std::time::Duration::from_secs(60))
+                    .merge_args(merge)
+                    .build()
+                    .unwrap(),
+            )
+        };
+
+        let id = insert(1, MergeArgs::Replace).await.unwrap();
+        insert(2, MergeArgs::Replace).await.unwrap();
+        insert(3, MergeArgs::Keep).await.unwrap();
+        assert!(insert(1, MergeArgs::Merge).await.is_err());
+
+        let task = store.get_task(&id).await.unwrap().unwrap();
+        assert_eq!(task.args, serde_json::json!({ "version": 2 }));
+        assert_eq!(task.args_version, 2);
+    }
+
+    #[tokio::test]
+    async fn throttle_pushes_back_a_waiting_task() {
+        let store = MemoryTaskStore::new();
+        let now = Utc::now();
+        store.set_now(now);
+
+        let insert = |args: serde_json::Value, throttle: bool| {
+            let builder = Task::builder().kind("sync_account").args(args);
+            let builder = if throttle {
+                builder.throttle("account-1", std::time::Duration::from_secs(3600))
+            } else {
+                builder.debounce("account-1", std::time::Duration::ZERO)
+            };
+            store.insert(builder.build().unwrap())
+        };
+
+        let first = insert(serde_json::json!(1), true).await.unwrap();
+        let fcfs = queue(SchedulingStrategy::FCFS);
+        assert_eq!(store.claim(&fcfs, &[]).await.unwrap()[0].id, first);
+
+        let debounced = insert(serde_json::json!(2), false).await.unwrap();
+        let throttled = insert(serde_json::json!(3), true).await.unwrap();
+        assert_eq!(debounced, throttled);
+
+        let task = store.get_task(&throttled).await.unwrap().unwrap();
+        assert_eq!(task.scheduled_at, Some(now + chrono::Duration::hours(1)));
+    }
+
+    #[tokio::test]
+    async fn snoozed_duplicate_retries_while_a_sibling_waits() {
+        let store = MemoryTaskStore::new();
+
+        let insert = |args: serde_json::Value| {
+            store.insert(
+                Task::builder()
+                    .kind("reindex_user")
+                    .args(args)
+                    .debounce("user-1", std::time::Duration::ZERO)
+                    .build()
+                    .unwrap(),
+            )
+        };
+
+        let running = insert(serde_json::json!(1)).await.unwrap();
+        let fcfs = queue(SchedulingStrategy::FCFS);
+        assert_eq!(store.claim(&fcfs, &[]).await.unwrap()[0].id, running);
+        let waiting = insert(serde_json::json!(2)).await.unwrap();
+        assert_ne!(running, waiting);
+
+        store.snooze(&running, &store.now(), None).await.unwrap();
+        let task = store.get_task(&running).await.unwrap().unwrap();
+        assert_eq!(task.state, TaskState::Retryable);
+        assert_eq!(insert(serde_json::json!(3)).await.unwrap(), waiting);
+    }
+
+    #[tokio::test]
+    async fn claims_the_full_priority_range() {
+        let store = MemoryTaskStore::new();
+
+        for priority in [i16::MIN, i16::MAX] {
+            store
+                .insert(
+                    Task::builder()
+                        .kind("ordered")
+                        .args(serde_json::Value::Null)
+                        .priority(priority)
+                        .build()
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+        }
+
+        let by_priority = queue(SchedulingStrategy::Priority);
+        let claimed = store.claim(&by_priority, &[]).await.unwrap();
+        let priorities = claimed.iter().map(|task| task.priority).collect::<Vec<_>>();
+        assert_eq!(priorities, vec![i16::MAX, i16::MIN]);
+    }
+
+    #[tokio::test]
+    async fn claims_in_strategy_order() {
+        let store = MemoryTaskStore::new();
+
+        for (priority, key) in [(0, "a"), (0, "a"), (5, "a"), (1, "b")] {
+            store
+                .insert(
+                    Task::builder()
+                        .kind("ordered")
+                        .args(serde_json::json!({ "priority": priority, "key": key }))
+                        .priority(priority)
+                        .fairness_key(key)
+                        .build()
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+        }
+
+        let mut by_priority = queue(SchedulingStrategy::Priority);
+        by_priority.limit = 1;
+        let claimed = store.claim(&by_priority, &[]).await.unwrap();
+        assert_eq!(claimed[0].priority, 5);
+
+        let fair = queue(SchedulingStrategy::Fair {
+            max_per_key: Some(2),
+        });
+        let claimed = store.claim(&fair, &[]).await.unwrap();
+        let keys = claimed
+            .iter()
+            .map(|task| task.fairness_key.clone().unwrap())
+            .collect::<Vec<_>>();
+        // "a" already runs one task, so it gets one more
+        assert_eq!(keys, vec!["a", "b"]);
+    }
+}
@@ -1,15 +1,58 @@
+mod advisory_lock;
+mod batch_loop;
+mod encryption;
+mod exclusive_loop;
+mod failure;
+mod lazy_args;
+mod memory_store;
+mod outcome;
+mod panic;
 mod periodic_tasks;
+mod progress;
 mod queue;
 mod run_task;
+mod snooze;
+mod store;
+mod task_log;
 mod task_loop;
 mod task_runner;
+mod timeline;
 mod traits;
+mod upcast;
 
 pub use crate::db::tasks::{
-    try_from, NewTask, Task, TaskBuildError, TaskBuilder, TaskKind, TaskService, TaskState,
+    try_from, Dedupe, DedupeMode, KindLimit, MergeArgs, NewTask, PeriodicTask, Task,
+    TaskBuildError, TaskBuilder, TaskErrorRecord, TaskHistory, TaskKind, TaskService, TaskState,
 };
 pub use crate::utils::context::{AnyClone, Context, CurrentTask};
-pub use periodic_tasks::schedule::{schedule_periodic_task, ChangSchedulePeriodicTask};
+pub use advisory_lock::AdvisoryLock;
+pub use encryption::{
+    ArgsBinding, ArgsEncryption, EncryptionError, EncryptionKey, KeyProvider, Keyring,
+    ENCRYPTED_FIELD,
+};
+pub use failure::{Failure, Permanent, Retry};
+pub use lazy_args::{LazyArgs, LazyArgsError};
+pub use memory_store::MemoryTaskStore;
+pub use outcome::{DrainReport, Outcome, TaskOutcome};
+pub use panic::{catch_panic, HandlerPanic};
+pub use periodic_tasks::schedule::{
+    schedule_periodic_job, schedule_periodic_task, ChangSchedulePeriodicTask, PeriodicHorizon,
+};
+pub use periodic_tasks::{
+    MisfirePolicy, PeriodicJob, PeriodicJobBuildError, PeriodicJobBuilder, PeriodicSchedule,
+    PeriodicScheduleError,
+};
+pub use progress::{
+    listen_progress, Progress, ProgressError, ProgressEvent, ProgressUpdate, PROGRESS_CHANNEL,
+};
 pub use queue::{SchedulingStrategy, TaskQueue};
+pub use snooze::{Snooze, SnoozeError, SnoozeRequest};
+pub use store::{PgTaskStore, Store, StoreError, TaskStore};
+pub use task_log::TaskLogContext;
 pub use task_runner::{Db, DbError, TaskRunner, TasksBuilder, TasksBuilderInner, DEFAULT_QUEUE};
-pub use traits::{CurrentTaskError, FromTaskContext, TaskContextError, TaskError, TaskHandler};
+pub use timeline::{AttemptTimeline, TaskTimeline};
+pub use traits::{
+    BatchOutcome, BatchTaskHandler, CurrentTaskError, FromTaskContext, TaskContextError, TaskError,
+    TaskHandler,
+};
+pub use upcast::{task_args, UpcastError, Upcasters};
@@ -0,0 +1,75 @@
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use crate::db::tasks::Task;
+
+/// What became of a single run of a task.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Outcome {
+    Completed,
+    /// The handler returned an error, timed out or wasn't registered.
+    Failed {
+        error: String,
+    },
+    Snoozed {
+        until: DateTime<Utc>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TaskOutcome {
+    pub id: Uuid,
+    pub kind: String,
+    pub attempt: i16,
+    pub outcome: Outcome,
+}
+
+impl TaskOutcome {
+    pub fn new(task: &Task, outcome: Outcome) -> Self {
+        TaskOutcome {
+            id: task.id,
+            kind: task.kind.clone(),
+            attempt: task.attempt,
+            outcome,
+        }
+    }
+}
+
+/// The runs of [`crate::task::TaskRunner::drain`], in the order they
+/// finished.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DrainReport {
+    pub runs: Vec<TaskOutcome>,
+}
+
+impl DrainReport {
+    /// Every run of the task, a retried task has one per attempt.
+    pub fn runs_of<'a>(&'a self, task_id: &'a Uuid) -> impl Iterator<Item = &'a TaskOutcome> {
+        self.runs.iter().filter(move |run| run.id == *task_id)
+    }
+
+    /// The outcome of the task's last run.
+    pub fn outcome(&self, task_id: &Uuid) -> Option<&Outcome> {
+        self.runs
+            .iter()
+            .rev()
+            .find(|run| run.id == *task_id)
+            .map(|run| &run.outcome)
+    }
+
+    pub fn completed(&self) -> impl Iterator<Item = &TaskOutcome> {
+        self.runs
+            .iter()
+            .filter(|run| run.outcome == Outcome::Completed)
+    }
+
+    pub fn failed(&self) -> impl Iterator<Item = &TaskOutcome> {
+        self.runs
+            .iter()
+            .filter(|run| matches!(run.outcome, Outcome::Failed { .. }))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.runs.is_empty()
+    }
+}
@@ -0,0 +1,128 @@
+use futures::FutureExt;
+use std::any::Any;
+use std::backtrace::Backtrace;
+use std::cell::{Cell, RefCell};
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Once;
+
+use crate::task::failure::Failure;
+
+thread_local! {
+    // set by the hook on the panicking thread, which also runs catch_unwind
+    static BACKTRACE: RefCell<Option<String>> = const { RefCell::new(None) };
+    // panics of the host app outside of handlers aren't captured
+    static IN_HANDLER: Cell<bool> = const { Cell::new(false) };
+}
+
+static HOOK: Once = Once::new();
+
+/// A panic of a handler, caught so the runner keeps its slot and the run
+/// counts as a failed attempt.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HandlerPanic {
+    pub message: String,
+    pub backtrace: Option<String>,
+}
+
+impl HandlerPanic {
+    fn new(payload: Box<dyn Any + Send>) -> Self {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "Box<dyn Any>".to_string());
+        let backtrace = BACKTRACE.with(|backtrace| backtrace.borrow_mut().take());
+
+        HandlerPanic { message, backtrace }
+    }
+
+    /// A transient failure with the backtrace in its details.
+    pub fn failure(&self) -> Failure {
+        Failure {
+            details: self
+                .backtrace
+                .as_ref()
+                .map(|backtrace| serde_json::json!({ "backtrace": backtrace })),
+            ..Failure::new(&format!("task panicked: {}", self.message))
+        }
+    }
+}
+
+/// Keeps the previous hook, so panics are still printed.
+fn install_hook() {
+    HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if IN_HANDLER.with(Cell::get) {
+                let backtrace = Backtrace::force_capture().to_string();
+                BACKTRACE.with(|slot| *slot.borrow_mut() = Some(backtrace));
+            }
+            previous(info);
+        }));
+    });
+}
+
+/// Marks the thread as polling a handler until it's dropped, which also
+/// happens while a panic unwinds.
+struct HandlerPoll {
+    outer: bool,
+}
+
+impl HandlerPoll {
+    fn enter() -> Self {
+        // a backtrace of a panic the handler caught itself is stale
+        BACKTRACE.with(|slot| slot.borrow_mut().take());
+        HandlerPoll {
+            outer: IN_HANDLER.with(|polling| polling.replace(true)),
+        }
+    }
+}
+
+impl Drop for HandlerPoll {
+    fn drop(&mut self) {
+        IN_HANDLER.with(|polling| polling.set(self.outer));
+    }
+}
+
+/// Runs `future` and turns a panic while polling it into a [`HandlerPanic`].
+pub async fn catch_panic<F: Future>(future: F) -> Result<F::Output, HandlerPanic> {
+    install_hook();
+    let mut future = std::pin::pin!(future);
+    let polled = futures::future::poll_fn(|cx| {
+        let _poll = HandlerPoll::enter();
+        future.as_mut().poll(cx)
+    });
+
+    AssertUnwindSafe(polled)
+        .catch_unwind()
+        .await
+        .map_err(HandlerPanic::new)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn catches_panics_with_their_backtrace() {
+        let panicked = catch_panic(async { panic!("index {} out of bounds", 3) })
+            .await
+            .unwrap_err();
+        assert_eq!(panicked.message, "index 3 out of bounds");
+        assert!(panicked.backtrace.is_some());
+
+        let failure = panicked.failure();
+        assert_eq!(failure.error, "task panicked: index 3 out of bounds");
+        assert!(!failure.permanent);
+
+        assert_eq!(catch_panic(async { 1 }).await, Ok(1));
+    }
+
+    #[test]
+    fn ignores_panics_outside_of_handlers() {
+        install_hook();
+        assert!(panic::catch_unwind(|| panic!("not a handler")).is_err());
+        assert!(BACKTRACE.with(|slot| slot.borrow().is_none()));
+    }
+}
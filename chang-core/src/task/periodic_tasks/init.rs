@@ -1,14 +1,40 @@
-use chrono::{DateTime, Timelike, Utc};
+use chrono::{DateTime, Utc};
 use log::info;
 use sqlx::PgPool;
-use std::{collections::HashMap, time::Duration};
+use std::collections::HashMap;
 
+use super::schedule::{schedule_periodic_job, start_of_horizon, PeriodicHorizon};
+use super::PeriodicJob;
 use crate::task::{ChangSchedulePeriodicTask, Task, TaskBuildError, TaskKind, TaskService};
 
-pub type PeriodicJobs = HashMap<String, String>;
+pub type PeriodicJobs = HashMap<String, PeriodicJob>;
 
 #[derive(thiserror::Error, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum InitPeriodicJobsError {
+    #[error("failed to store periodic task({name:?}): {queue:?}")]
+    AddPeriodicTask {
+        name: String,
+        queue: String,
+        #[source]
+        error: sqlx::Error,
+    },
+
+    #[error("failed to schedule periodic task({name:?}): {queue:?}")]
+    ScheduleJob {
+        name: String,
+        queue: String,
+        #[source]
+        error: crate::error::Error,
+    },
+
+    #[error("failed to fetch periodic tasks: {queue:?}")]
+    GetPeriodicTasks {
+        queue: String,
+        #[source]
+        error: sqlx::Error,
+    },
+
     #[error("failed to fetch current task({kind:?}): {queue:?}")]
     GetCurrentTask {
         kind: String,
@@ -30,25 +56,58 @@ pub enum InitPeriodicJobsError {
         kind: String,
         queue: String,
         #[source]
-        error: sqlx::Error,
+        error: crate::error::Error,
     },
 }
 
+/// Stores the schedules registered in code in `chang.periodic_tasks` and
+/// inserts the scheduler task if the queue has any schedules. Schedules that
+/// already exist in the table are left untouched, so changes made at runtime
+/// survive a restart. New schedules are enqueued right away instead of
+/// waiting for the next scheduler run.
 pub async fn init(
     periodic_jobs: &PeriodicJobs,
     db: &PgPool,
     queue: &str,
     now: &DateTime<Utc>,
+    horizon: &PeriodicHorizon,
 ) -> Result<(), InitPeriodicJobsError> {
     info!("Init {}", ChangSchedulePeriodicTask::kind());
 
-    if periodic_jobs.is_empty() {
+    for job in periodic_jobs.values() {
+        let added = TaskService::add_periodic_task(db, queue, job)
+            .await
+            .map_err(|error| InitPeriodicJobsError::AddPeriodicTask {
+                name: job.name.clone(),
+                queue: queue.to_string(),
+                error,
+            })?;
+
+        if added.is_some() {
+            schedule_periodic_job(db, queue, job, now, horizon)
+                .await
+                .map_err(|error| InitPeriodicJobsError::ScheduleJob {
+                    name: job.name.clone(),
+                    queue: queue.to_string(),
+                    error,
+                })?;
+        }
+    }
+
+    let periodic_tasks = TaskService::get_periodic_tasks(db, queue)
+        .await
+        .map_err(|error| InitPeriodicJobsError::GetPeriodicTasks {
+            queue: queue.to_string(),
+            error,
+        })?;
+
+    if periodic_tasks.is_empty() {
         return Ok(());
     }
 
     let kind = ChangSchedulePeriodicTask::kind();
 
-    let current_task = TaskService::get_tasks_by_kind(db, &kind, &queue, 1)
+    let has_scheduler = TaskService::has_pending_tasks(db, &kind, queue)
         .await
         .map_err(|error| InitPeriodicJobsError::GetCurrentTask {
             kind: kind.clone(),
@@ -56,20 +115,22 @@ pub async fn init(
             error,
         })?;
 
-    if current_task.first().is_some() {
+    if has_scheduler {
         info!("{} exists, abort insert", ChangSchedulePeriodicTask::kind());
         return Ok(());
     }
 
-    let seconds = now.minute() * 60;
-    let start_of_hour = *now - Duration::from_secs(seconds.into());
+    // Runners starting at the same time insert the same slot, the unique
+    // periodic slot index lets only one of them through.
+    let scheduled_at = start_of_horizon(now, horizon);
 
     let task = Task::builder()
         .kind(&kind)
         .args(serde_json::Value::Null)
-        .scheduled_at(&start_of_hour)
-        .priority(std::i16::MAX)
-        .queue(&queue)
+        .scheduled_at(&scheduled_at)
+        .priority(i16::MAX)
+        .periodic_name(&kind)
+        .queue(queue)
         .build()
         .map_err(|error| InitPeriodicJobsError::BuildTask {
             kind: kind.clone(),
@@ -77,7 +138,7 @@ pub async fn init(
             error,
         })?;
 
-    task.insert(db)
+    TaskService::batch_insert(db, &[task])
         .await
         .map_err(|error| InitPeriodicJobsError::InsertTask {
             kind,
@@ -105,9 +166,19 @@ mod test {
         let expected_scheduled_at = "2014-11-28T11:00:00Z".parse::<DateTime<Utc>>().unwrap();
         let now = "2014-11-28T11:23:00Z".parse::<DateTime<Utc>>().unwrap();
         let mut periodic_jobs: PeriodicJobs = HashMap::new();
-        periodic_jobs.insert("0 */5 * * * * *".into(), "test_can_insert_task".into());
+        periodic_jobs.insert(
+            "test_can_insert_task".into(),
+            job("test_can_insert_task", "0 */5 * * * * *"),
+        );
 
-        let result = init(&periodic_jobs, &prepare.pool, &prepare.name, &now).await;
+        let result = init(
+            &periodic_jobs,
+            &prepare.pool,
+            &prepare.name,
+            &now,
+            &PeriodicHorizon::default(),
+        )
+        .await;
         assert!(result.is_ok(), "failed to insert task");
 
         let tasks = TaskService::get_tasks_by_kind(
@@ -119,7 +190,7 @@ mod test {
         .await
         .unwrap();
 
-        assert!(tasks.first().is_some(), "failed to fetch task");
+        assert!(!tasks.is_empty(), "failed to fetch task");
 
         let task = tasks.first().unwrap();
 
@@ -140,7 +211,14 @@ mod test {
         let now = "2014-11-28T11:23:00Z".parse::<DateTime<Utc>>().unwrap();
         let periodic_jobs: PeriodicJobs = HashMap::new();
 
-        let result = init(&periodic_jobs, &prepare.pool, &prepare.name, &now).await;
+        let result = init(
+            &periodic_jobs,
+            &prepare.pool,
+            &prepare.name,
+            &now,
+            &PeriodicHorizon::default(),
+        )
+        .await;
         assert!(result.is_ok(), "failed to insert task");
 
         let tasks = TaskService::get_tasks_by_kind(
@@ -153,13 +231,167 @@ mod test {
         .unwrap();
 
         assert!(
-            tasks.first().is_none(),
+            tasks.is_empty(),
             "inserted \"chang_schedule_periodic_task\""
         );
 
         utils::test::cleanup(prepare).await;
     }
 
+    #[tokio::test]
+    async fn keeps_schedules_changed_at_runtime() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::tasks(&prepare.pool).await;
+
+        let now = "2014-11-28T11:23:00Z".parse::<DateTime<Utc>>().unwrap();
+        let runtime_job = job("report", "0 9 * * *");
+
+        TaskService::add_periodic_task(&prepare.pool, &prepare.name, &runtime_job)
+            .await
+            .unwrap();
+
+        let mut periodic_jobs: PeriodicJobs = HashMap::new();
+        periodic_jobs.insert("report".into(), job("report", "0 * * * *"));
+        periodic_jobs.insert("cleanup".into(), job("cleanup", "30 * * * *"));
+
+        let result = init(
+            &periodic_jobs,
+            &prepare.pool,
+            &prepare.name,
+            &now,
+            &PeriodicHorizon::default(),
+        )
+        .await;
+        assert!(result.is_ok(), "failed to init periodic jobs");
+
+        let periodic_tasks = TaskService::get_periodic_tasks(&prepare.pool, &prepare.name)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|periodic_task| (periodic_task.name.clone(), periodic_task.job().unwrap()))
+            .collect::<HashMap<String, PeriodicJob>>();
+
+        assert_eq!(periodic_tasks.len(), 2);
+        assert_eq!(periodic_tasks.get("report"), Some(&runtime_job));
+        assert_eq!(periodic_tasks.get("cleanup"), periodic_jobs.get("cleanup"));
+
+        utils::test::cleanup(prepare).await;
+    }
+
+    #[tokio::test]
+    async fn concurrent_init_inserts_one_scheduler() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::tasks(&prepare.pool).await;
+
+        let now = "2014-11-28T11:23:42.123Z".parse::<DateTime<Utc>>().unwrap();
+        let mut periodic_jobs: PeriodicJobs = HashMap::new();
+        periodic_jobs.insert("report".into(), job("report", "0 * * * *"));
+
+        let hour = PeriodicHorizon::default();
+        let (first, second) = tokio::join!(
+            init(&periodic_jobs, &prepare.pool, &prepare.name, &now, &hour),
+            init(&periodic_jobs, &prepare.pool, &prepare.name, &now, &hour),
+        );
+        assert!(
+            first.is_ok() && second.is_ok(),
+            "failed to init periodic jobs"
+        );
+
+        let tasks = TaskService::get_tasks_by_kind(
+            &prepare.pool,
+            &ChangSchedulePeriodicTask::kind(),
+            &prepare.name,
+            10,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(tasks.len(), 1);
+
+        utils::test::cleanup(prepare).await;
+    }
+
+    #[tokio::test]
+    async fn skips_periodic_slots_that_exist() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::tasks(&prepare.pool).await;
+
+        let scheduled_at = "2014-11-28T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let report = job("report", "0 * * * *");
+        let task = report
+            .task_builder(&scheduled_at, &prepare.name)
+            .build()
+            .unwrap();
+
+        let ids = TaskService::batch_insert(&prepare.pool, std::slice::from_ref(&task))
+            .await
+            .unwrap();
+        assert_eq!(ids.len(), 1);
+
+        let ids = TaskService::batch_insert(&prepare.pool, &[task])
+            .await
+            .unwrap();
+        assert!(ids.is_empty());
+
+        utils::test::cleanup(prepare).await;
+    }
+
+    #[tokio::test]
+    async fn schedules_new_jobs_right_away() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::tasks(&prepare.pool).await;
+
+        let now = "2014-11-28T11:05:00Z".parse::<DateTime<Utc>>().unwrap();
+        let hour = PeriodicHorizon::default();
+
+        let mut periodic_jobs: PeriodicJobs = HashMap::new();
+        periodic_jobs.insert("report".into(), job("report", "0 * * * *"));
+        init(&periodic_jobs, &prepare.pool, &prepare.name, &now, &hour)
+            .await
+            .unwrap();
+
+        // the scheduler for 12:00 exists, the new job doesn't wait for it
+        periodic_jobs.insert("cleanup".into(), job("cleanup", "*/15 * * * *"));
+        init(&periodic_jobs, &prepare.pool, &prepare.name, &now, &hour)
+            .await
+            .unwrap();
+
+        let tasks = TaskService::get_tasks_by_kind(&prepare.pool, "cleanup", &prepare.name, 20)
+            .await
+            .unwrap();
+        let first = tasks.iter().filter_map(|task| task.scheduled_at).min();
+
+        // 11:15 up to the end of the next hour
+        assert_eq!(tasks.len(), 8);
+        assert_eq!(
+            first,
+            Some("2014-11-28T11:15:00Z".parse::<DateTime<Utc>>().unwrap())
+        );
+
+        let reports = TaskService::get_tasks_by_kind(&prepare.pool, "report", &prepare.name, 20)
+            .await
+            .unwrap();
+        assert_eq!(reports.len(), 2, "scheduled an existing job twice");
+
+        utils::test::cleanup(prepare).await;
+    }
+
+    fn job(kind: &str, schedule: &str) -> PeriodicJob {
+        PeriodicJob::builder()
+            .kind(kind)
+            .schedule(schedule.parse().unwrap())
+            .build()
+            .unwrap()
+    }
+
     #[tokio::test]
     async fn wont_insert_task_exists() {
         let prepare = utils::test::prepare().await;
@@ -182,7 +414,14 @@ mod test {
             .await
             .unwrap();
 
-        let result = init(&periodic_jobs, &prepare.pool, &prepare.name, &now).await;
+        let result = init(
+            &periodic_jobs,
+            &prepare.pool,
+            &prepare.name,
+            &now,
+            &PeriodicHorizon::default(),
+        )
+        .await;
         assert!(result.is_ok(), "failed to insert task");
 
         let tasks = TaskService::get_tasks_by_kind(
@@ -194,7 +433,7 @@ mod test {
         .await
         .unwrap();
 
-        assert!(tasks.first().is_some(), "failed to fetch task");
+        assert!(!tasks.is_empty(), "failed to fetch task");
 
         let task = tasks.first().unwrap();
         assert_ne!(task.scheduled_at, Some(expected_scheduled_at));
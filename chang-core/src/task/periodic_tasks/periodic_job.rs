@@ -0,0 +1,221 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::time::Duration;
+
+use super::PeriodicSchedule;
+use crate::task::{Task, TaskBuilder, TaskKind};
+
+/// What the scheduler does with slots that passed while no runner was up.
+#[derive(sqlx::Type, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[sqlx(type_name = "chang.misfire_policy")]
+#[sqlx(rename_all = "snake_case")]
+pub enum MisfirePolicy {
+    /// Drops the missed slots.
+    #[default]
+    Skip,
+    /// Enqueues the latest missed slot.
+    RunOnce,
+    /// Enqueues every missed slot.
+    RunAll,
+}
+
+/// A schedule entry for a periodic task. Jobs are identified by `name`, so
+/// several jobs can share a kind, e.g. one report per region with different
+/// args.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeriodicJob {
+    pub name: String,
+    pub kind: String,
+    pub schedule: PeriodicSchedule,
+    pub args: serde_json::Value,
+    pub priority: Option<i16>,
+    pub max_attempts: Option<i16>,
+    pub tags: Vec<String>,
+    pub timeout: Option<Duration>,
+    pub misfire_policy: MisfirePolicy,
+    pub args_version: i16,
+}
+
+impl PeriodicJob {
+    pub fn builder() -> PeriodicJobBuilder {
+        PeriodicJobBuilder::default()
+    }
+
+    /// Task builder for the run at `scheduled_at`.
+    pub fn task_builder(&self, scheduled_at: &DateTime<Utc>, queue: &str) -> TaskBuilder {
+        let mut builder = Task::builder()
+            .kind(&self.kind)
+            .args(self.args.clone())
+            .tags(&self.tags)
+            .periodic_name(&self.name)
+            .scheduled_at(scheduled_at)
+            .queue(queue)
+            .args_version(self.args_version);
+
+        if let Some(priority) = self.priority {
+            builder.set_priority(priority);
+        }
+
+        if let Some(max_attempts) = self.max_attempts {
+            builder.set_max_attempts(max_attempts);
+        }
+
+        if let Some(timeout) = self.timeout {
+            builder.set_timeout(timeout);
+        }
+
+        builder
+    }
+
+    /// Run times in `from..=to`.
+    pub fn slots<'a>(
+        &'a self,
+        from: &DateTime<Utc>,
+        to: &DateTime<Utc>,
+    ) -> impl Iterator<Item = DateTime<Utc>> + 'a {
+        let from = *from;
+        let to = *to;
+
+        self.schedule
+            .after(&(from - chrono::Duration::seconds(1)))
+            .skip_while(move |scheduled_at| *scheduled_at < from)
+            .take_while(move |scheduled_at| *scheduled_at <= to)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PeriodicJobBuildError {
+    #[error("kind missing")]
+    KindMissing,
+
+    #[error("schedule missing")]
+    ScheduleMissing,
+}
+
+#[derive(Clone, Default)]
+struct PeriodicJobBuilderInner {
+    name: Option<String>,
+    kind: Option<String>,
+    schedule: Option<PeriodicSchedule>,
+    args: Option<serde_json::Value>,
+    priority: Option<i16>,
+    max_attempts: Option<i16>,
+    tags: Vec<String>,
+    timeout: Option<Duration>,
+    misfire_policy: MisfirePolicy,
+    args_version: Option<i16>,
+}
+
+#[derive(Clone, Default)]
+pub struct PeriodicJobBuilder {
+    inner: PeriodicJobBuilderInner,
+}
+
+impl PeriodicJobBuilder {
+    /// Defaults to the kind.
+    pub fn name(mut self, name: &str) -> Self {
+        self.inner.name = Some(name.into());
+        self
+    }
+
+    pub fn kind(mut self, kind: &str) -> Self {
+        self.inner.kind = Some(kind.into());
+        self
+    }
+
+    pub fn schedule(mut self, schedule: PeriodicSchedule) -> Self {
+        self.inner.schedule = Some(schedule);
+        self
+    }
+
+    pub fn args(mut self, args: serde_json::Value) -> Self {
+        self.inner.args = Some(args);
+        self
+    }
+
+    pub fn task<T: TaskKind + Serialize>(mut self, task: T) -> Result<Self, serde_json::Error> {
+        self.set_task(task)?;
+        Ok(self)
+    }
+
+    pub fn set_task<T: TaskKind + Serialize>(&mut self, task: T) -> Result<(), serde_json::Error> {
+        self.inner.kind = Some(T::kind());
+        self.inner.args = Some(serde_json::to_value(task)?);
+        self.inner.args_version = Some(T::version());
+        Ok(())
+    }
+
+    /// Defaults to 1, [`PeriodicJobBuilder::task`] uses the version of the
+    /// task kind.
+    pub fn args_version(mut self, version: i16) -> Self {
+        self.inner.args_version = Some(version);
+        self
+    }
+
+    pub fn priority(mut self, priority: i16) -> Self {
+        self.set_priority(priority);
+        self
+    }
+
+    pub fn set_priority(&mut self, priority: i16) {
+        self.inner.priority = Some(priority);
+    }
+
+    pub fn max_attempts(mut self, max_attempts: i16) -> Self {
+        self.set_max_attempts(max_attempts);
+        self
+    }
+
+    pub fn set_max_attempts(&mut self, max_attempts: i16) {
+        self.inner.max_attempts = Some(max_attempts);
+    }
+
+    pub fn tags(mut self, tags: &[String]) -> Self {
+        self.set_tags(tags);
+        self
+    }
+
+    pub fn set_tags(&mut self, tags: &[String]) {
+        self.inner.tags = tags.to_vec();
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.set_timeout(timeout);
+        self
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.inner.timeout = Some(timeout);
+    }
+
+    /// Defaults to [`MisfirePolicy::Skip`].
+    pub fn misfire_policy(mut self, misfire_policy: MisfirePolicy) -> Self {
+        self.set_misfire_policy(misfire_policy);
+        self
+    }
+
+    pub fn set_misfire_policy(&mut self, misfire_policy: MisfirePolicy) {
+        self.inner.misfire_policy = misfire_policy;
+    }
+
+    pub fn build(self) -> Result<PeriodicJob, PeriodicJobBuildError> {
+        let inner = self.inner;
+        let kind = inner.kind.ok_or(PeriodicJobBuildError::KindMissing)?;
+        let schedule = inner
+            .schedule
+            .ok_or(PeriodicJobBuildError::ScheduleMissing)?;
+
+        Ok(PeriodicJob {
+            name: inner.name.unwrap_or_else(|| kind.clone()),
+            kind,
+            schedule,
+            args: inner.args.unwrap_or(serde_json::Value::Null),
+            priority: inner.priority,
+            max_attempts: inner.max_attempts,
+            tags: inner.tags,
+            timeout: inner.timeout,
+            misfire_policy: inner.misfire_policy,
+            args_version: inner.args_version.unwrap_or(1),
+        })
+    }
+}
@@ -0,0 +1,513 @@
+use chrono::{DateTime, Duration, NaiveDateTime, Offset, TimeZone, Utc};
+use chrono_tz::Tz;
+use std::convert::Infallible;
+use std::fmt;
+use std::str::FromStr;
+
+/// A cron schedule evaluated in an IANA time zone.
+///
+/// Accepts the standard 5-field crontab form (`min hour dom mon dow`, with
+/// Sunday as `0` or `7`) as well as the 6/7-field form with seconds used by
+/// the `cron` crate. A leading `CRON_TZ=<zone>` or `TZ=<zone>` sets the time
+/// zone, which defaults to UTC.
+///
+/// Local times skipped by a DST change run as if the clock hadn't moved, e.g.
+/// 02:30 becomes 03:30, and repeated local times only run once.
+///
+/// `@every <interval>` runs at a fixed interval such as `90s` or `1h30m`
+/// (units `s`, `m`, `h` and `d`). Runs are counted from the Unix epoch, so
+/// every runner computes the same times, or from the timestamp given with
+/// `@every <interval> from <RFC 3339 timestamp>`.
+#[derive(Clone, Debug)]
+pub struct PeriodicSchedule {
+    expression: String,
+    kind: ScheduleKind,
+    timezone: Tz,
+}
+
+#[derive(Clone, Debug)]
+enum ScheduleKind {
+    Cron(Box<cron::Schedule>),
+    Interval {
+        every: Duration,
+        anchor: DateTime<Utc>,
+    },
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum PeriodicScheduleError {
+    #[error("invalid schedule {expression:?}: {source}")]
+    Expression {
+        expression: String,
+        #[source]
+        source: cron::error::Error,
+    },
+
+    #[error("invalid interval {expression:?}: {reason}")]
+    Interval { expression: String, reason: String },
+
+    #[error("unknown time zone {0:?}")]
+    TimeZone(String),
+}
+
+impl From<Infallible> for PeriodicScheduleError {
+    fn from(value: Infallible) -> Self {
+        match value {}
+    }
+}
+
+impl PeriodicSchedule {
+    pub fn parse(expression: &str) -> Result<Self, PeriodicScheduleError> {
+        let expression = expression.trim();
+        let (timezone, cron_expression) = match expression.split_once(char::is_whitespace) {
+            Some((prefix, rest)) if prefix.starts_with("CRON_TZ=") || prefix.starts_with("TZ=") => {
+                let (_, zone) = prefix.split_once('=').unwrap_or_default();
+                (parse_timezone(zone)?, rest.trim())
+            }
+            _ => (Tz::UTC, expression),
+        };
+
+        let kind = match cron_expression.strip_prefix("@every") {
+            Some(interval) => {
+                parse_interval(interval).map_err(|reason| PeriodicScheduleError::Interval {
+                    expression: expression.to_string(),
+                    reason,
+                })?
+            }
+            None => cron::Schedule::from_str(&to_cron_expression(cron_expression))
+                .map(|cron| ScheduleKind::Cron(Box::new(cron)))
+                .map_err(|source| PeriodicScheduleError::Expression {
+                    expression: expression.to_string(),
+                    source,
+                })?,
+        };
+
+        Ok(PeriodicSchedule {
+            expression: cron_expression.to_string(),
+            kind,
+            timezone,
+        })
+    }
+
+    pub fn with_timezone(mut self, timezone: Tz) -> Self {
+        self.timezone = timezone;
+        self
+    }
+
+    pub fn timezone(&self) -> Tz {
+        self.timezone
+    }
+
+    /// Upcoming run times strictly after `start`.
+    pub fn after<'a>(&'a self, start: &DateTime<Utc>) -> impl Iterator<Item = DateTime<Utc>> + 'a {
+        let start = *start;
+
+        match &self.kind {
+            ScheduleKind::Cron(cron) => {
+                let timezone = self.timezone;
+                // `cron` evaluates wall-clock time; UTC is used as a clock
+                // without DST so the conversion to the actual zone can happen
+                // afterwards.
+                let wall_clock =
+                    Utc.from_utc_datetime(&start.with_timezone(&timezone).naive_local());
+                let mut last: Option<DateTime<Utc>> = None;
+
+                let times = cron
+                    .after(&wall_clock)
+                    .map(move |local| from_wall_clock(&timezone, &local.naive_utc()))
+                    .filter(move |scheduled_at| {
+                        let is_next = *scheduled_at > start
+                            && last.map(|l| *scheduled_at > l).unwrap_or(true);
+                        if is_next {
+                            last = Some(*scheduled_at);
+                        }
+                        is_next
+                    });
+
+                Box::new(times) as Box<dyn Iterator<Item = DateTime<Utc>> + 'a>
+            }
+            ScheduleKind::Interval { every, anchor } => {
+                let every = *every;
+                let first = if start < *anchor {
+                    Some(*anchor)
+                } else {
+                    let every_ms = every.num_milliseconds();
+                    let elapsed = (start - *anchor).num_milliseconds() / every_ms + 1;
+                    elapsed
+                        .checked_mul(every_ms)
+                        .and_then(Duration::try_milliseconds)
+                        .and_then(|since| anchor.checked_add_signed(since))
+                };
+
+                Box::new(std::iter::successors(first, move |scheduled_at| {
+                    scheduled_at.checked_add_signed(every)
+                }))
+            }
+        }
+    }
+}
+
+impl fmt::Display for PeriodicSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.timezone == Tz::UTC {
+            f.write_str(&self.expression)
+        } else {
+            write!(f, "CRON_TZ={} {}", self.timezone.name(), self.expression)
+        }
+    }
+}
+
+impl FromStr for PeriodicSchedule {
+    type Err = PeriodicScheduleError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        PeriodicSchedule::parse(s)
+    }
+}
+
+impl TryFrom<&str> for PeriodicSchedule {
+    type Error = PeriodicScheduleError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        PeriodicSchedule::parse(value)
+    }
+}
+
+impl TryFrom<String> for PeriodicSchedule {
+    type Error = PeriodicScheduleError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        PeriodicSchedule::parse(&value)
+    }
+}
+
+impl PartialEq for PeriodicSchedule {
+    fn eq(&self, other: &Self) -> bool {
+        self.expression == other.expression && self.timezone == other.timezone
+    }
+}
+
+fn parse_timezone(zone: &str) -> Result<Tz, PeriodicScheduleError> {
+    zone.parse::<Tz>()
+        .map_err(|_| PeriodicScheduleError::TimeZone(zone.to_string()))
+}
+
+/// Parses `<interval>[ from <timestamp>]`, the part after `@every`.
+fn parse_interval(value: &str) -> Result<ScheduleKind, String> {
+    let (interval, anchor) = match value.split_once(" from ") {
+        Some((interval, anchor)) => (interval.trim(), Some(anchor.trim())),
+        None => (value.trim(), None),
+    };
+
+    let mut every = Duration::zero();
+    let mut digits = String::new();
+    for char in interval.chars() {
+        if char.is_ascii_digit() {
+            digits.push(char);
+            continue;
+        }
+
+        let amount = digits
+            .parse::<i64>()
+            .map_err(|_| format!("expected a number before {:?}", char))?;
+        digits.clear();
+
+        let part = match char {
+            's' => Duration::try_seconds(amount),
+            'm' => Duration::try_minutes(amount),
+            'h' => Duration::try_hours(amount),
+            'd' => Duration::try_days(amount),
+            unit => return Err(format!("unknown unit {:?}", unit)),
+        };
+        every = part
+            .and_then(|part| every.checked_add(&part))
+            .ok_or_else(|| format!("interval {:?} is too long", interval))?;
+    }
+
+    if !digits.is_empty() {
+        return Err(format!("missing unit after {:?}", digits));
+    }
+
+    if every <= Duration::zero() {
+        return Err("interval has to be positive".to_string());
+    }
+
+    let anchor = match anchor {
+        Some(anchor) => anchor
+            .parse::<DateTime<Utc>>()
+            .map_err(|err| format!("invalid timestamp {:?}: {}", anchor, err))?,
+        None => Utc.timestamp_opt(0, 0).unwrap(),
+    };
+
+    Ok(ScheduleKind::Interval { every, anchor })
+}
+
+fn from_wall_clock(timezone: &Tz, local: &NaiveDateTime) -> DateTime<Utc> {
+    match timezone.from_local_datetime(local).earliest() {
+        Some(scheduled_at) => scheduled_at.with_timezone(&Utc),
+        None => {
+            // The local time falls into a DST gap, use the offset from before
+            // the gap, which moves the run forward by the length of the gap.
+            let offset = timezone
+                .offset_from_utc_datetime(&(*local - Duration::days(1)))
+                .fix();
+            Utc.from_utc_datetime(&(*local - offset))
+        }
+    }
+}
+
+/// Converts the 5-field crontab form into the `cron` crate's form, which
+/// has a leading seconds field and numbers the days of the week from 1.
+fn to_cron_expression(expression: &str) -> String {
+    let fields = expression.split_whitespace().collect::<Vec<&str>>();
+
+    if fields.len() != 5 {
+        return expression.to_string();
+    }
+
+    format!(
+        "0 {} {} {} {} {}",
+        fields[0],
+        fields[1],
+        fields[2],
+        fields[3],
+        to_cron_days_of_week(fields[4])
+    )
+}
+
+fn to_cron_days_of_week(field: &str) -> String {
+    field
+        .split(',')
+        .map(|item| {
+            let (range, step) = match item.split_once('/') {
+                Some((range, step)) => (range, Some(step)),
+                None => (item, None),
+            };
+
+            let step_by = match step.map(str::parse::<usize>) {
+                None => Some(1),
+                Some(Ok(step)) if step > 0 => Some(step),
+                Some(_) => None,
+            };
+
+            let range = match range.split_once('-') {
+                Some((from, to)) => match (from.parse::<u8>(), to.parse::<u8>(), step_by) {
+                    // Sunday as 7 would turn the range around, list its days instead
+                    (Ok(from), Ok(7), Some(step_by)) if from <= 7 => {
+                        let mut days = (from..=7)
+                            .step_by(step_by)
+                            .map(to_cron_day)
+                            .collect::<Vec<u8>>();
+                        days.sort();
+                        days.dedup();
+
+                        return days
+                            .iter()
+                            .map(|day| day.to_string())
+                            .collect::<Vec<String>>()
+                            .join(",");
+                    }
+                    (Ok(from), Ok(to), _) => {
+                        format!("{}-{}", to_cron_day(from), to_cron_day(to))
+                    }
+                    _ => range.to_string(),
+                },
+                None => match range.parse::<u8>() {
+                    Ok(day) => to_cron_day(day).to_string(),
+                    Err(_) => range.to_string(),
+                },
+            };
+
+            match step {
+                Some(step) => format!("{}/{}", range, step),
+                None => range,
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+fn to_cron_day(day: u8) -> u8 {
+    if day >= 7 {
+        1
+    } else {
+        day + 1
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn utc(value: &str) -> DateTime<Utc> {
+        value.parse::<DateTime<Utc>>().unwrap()
+    }
+
+    #[test]
+    fn accepts_standard_crontab() {
+        let schedule = PeriodicSchedule::parse("30 9 * * 1-5").unwrap();
+        let start = utc("2024-01-05T10:00:00Z"); // Friday
+
+        let next = schedule.after(&start).take(2).collect::<Vec<_>>();
+
+        assert_eq!(
+            next,
+            vec![utc("2024-01-08T09:30:00Z"), utc("2024-01-09T09:30:00Z")]
+        );
+    }
+
+    #[test]
+    fn treats_zero_and_seven_as_sunday() {
+        let start = utc("2024-01-01T00:00:00Z"); // Monday
+        let sunday = utc("2024-01-07T12:00:00Z");
+
+        for expression in ["0 12 * * 0", "0 12 * * 7", "0 12 * * SUN"] {
+            let schedule = PeriodicSchedule::parse(expression).unwrap();
+            assert_eq!(
+                schedule.after(&start).next(),
+                Some(sunday),
+                "{}",
+                expression
+            );
+        }
+
+        let schedule = PeriodicSchedule::parse("0 12 * * 6-7").unwrap();
+        let next = schedule.after(&start).take(2).collect::<Vec<_>>();
+        assert_eq!(next, vec![utc("2024-01-06T12:00:00Z"), sunday]);
+    }
+
+    #[test]
+    fn steps_through_ranges_ending_on_sunday() {
+        let start = utc("2024-01-01T00:00:00Z"); // Monday
+
+        let schedule = PeriodicSchedule::parse("0 12 * * 1-7/2").unwrap();
+        let next = schedule.after(&start).take(4).collect::<Vec<_>>();
+        assert_eq!(
+            next,
+            vec![
+                utc("2024-01-01T12:00:00Z"),
+                utc("2024-01-03T12:00:00Z"),
+                utc("2024-01-05T12:00:00Z"),
+                utc("2024-01-07T12:00:00Z"),
+            ]
+        );
+
+        let schedule = PeriodicSchedule::parse("0 12 * * */2").unwrap();
+        let next = schedule.after(&start).take(4).collect::<Vec<_>>();
+        assert_eq!(
+            next,
+            vec![
+                utc("2024-01-02T12:00:00Z"),
+                utc("2024-01-04T12:00:00Z"),
+                utc("2024-01-06T12:00:00Z"),
+                utc("2024-01-07T12:00:00Z"),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        assert!(matches!(
+            PeriodicSchedule::parse("every tuesday"),
+            Err(PeriodicScheduleError::Expression { .. })
+        ));
+
+        assert!(matches!(
+            PeriodicSchedule::parse("CRON_TZ=Mars/Olympus 0 9 * * *"),
+            Err(PeriodicScheduleError::TimeZone(_))
+        ));
+    }
+
+    #[test]
+    fn runs_at_fixed_intervals() {
+        let schedule = PeriodicSchedule::parse("@every 90s").unwrap();
+        let next = schedule
+            .after(&utc("2024-01-01T00:00:00Z"))
+            .take(3)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            next,
+            vec![
+                utc("2024-01-01T00:01:30Z"),
+                utc("2024-01-01T00:03:00Z"),
+                utc("2024-01-01T00:04:30Z")
+            ]
+        );
+
+        let schedule = PeriodicSchedule::parse("@every 1h30m from 2024-01-01T00:10:00Z").unwrap();
+        assert_eq!(
+            schedule.to_string(),
+            "@every 1h30m from 2024-01-01T00:10:00Z"
+        );
+
+        let next = schedule.after(&utc("2023-12-31T00:00:00Z")).next();
+        assert_eq!(next, Some(utc("2024-01-01T00:10:00Z")));
+
+        let next = schedule
+            .after(&utc("2024-01-01T01:40:00Z"))
+            .take(2)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            next,
+            vec![utc("2024-01-01T03:10:00Z"), utc("2024-01-01T04:40:00Z")]
+        );
+
+        for expression in [
+            "@every",
+            "@every 0s",
+            "@every 10",
+            "@every 5w",
+            "@every 1m from x",
+            "@every 999999999999999d",
+            "@every 9223372036854775807s",
+        ] {
+            assert!(
+                matches!(
+                    PeriodicSchedule::parse(expression),
+                    Err(PeriodicScheduleError::Interval { .. })
+                ),
+                "{}",
+                expression
+            );
+        }
+
+        let schedule = PeriodicSchedule::parse("@every 99999999d").unwrap();
+        assert_eq!(schedule.after(&utc("2024-01-01T00:00:00Z")).next(), None);
+    }
+
+    #[test]
+    fn follows_time_zone_across_dst() {
+        let schedule = PeriodicSchedule::parse("CRON_TZ=Europe/Berlin 0 9 * * *").unwrap();
+        let start = utc("2024-03-30T00:00:00Z");
+
+        let next = schedule.after(&start).take(2).collect::<Vec<_>>();
+
+        // 09:00 CET is 08:00 UTC, 09:00 CEST is 07:00 UTC
+        assert_eq!(
+            next,
+            vec![utc("2024-03-30T08:00:00Z"), utc("2024-03-31T07:00:00Z")]
+        );
+    }
+
+    #[test]
+    fn runs_skipped_and_repeated_local_times_once() {
+        let schedule = PeriodicSchedule::parse("30 2 * * *")
+            .unwrap()
+            .with_timezone(chrono_tz::Europe::Berlin);
+
+        // 02:30 doesn't exist on 2024-03-31 in Berlin
+        let next = schedule.after(&utc("2024-03-30T12:00:00Z")).next();
+        assert_eq!(next, Some(utc("2024-03-31T01:30:00Z")));
+
+        // 02:30 happens twice on 2024-10-27 in Berlin
+        let next = schedule
+            .after(&utc("2024-10-26T12:00:00Z"))
+            .take(2)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            next,
+            vec![utc("2024-10-27T00:30:00Z"), utc("2024-10-28T01:30:00Z")]
+        );
+    }
+}
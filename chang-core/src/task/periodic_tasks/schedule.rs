@@ -1,15 +1,16 @@
-use chrono::{DateTime, Timelike, Utc};
-use cron;
-use log::info;
+use chrono::{DateTime, DurationRound, Utc};
+use log::{error, info};
+use sqlx::PgPool;
 use std::iter::Iterator;
-use std::str::FromStr;
 use std::time::Duration;
+use uuid::Uuid;
 
 use crate::task::Task;
 use crate::utils::context::Context;
 
 use crate::task::{
-    periodic_tasks::PeriodicJobs, Db, FromTaskContext, NewTask, TaskKind, TaskService, TaskState,
+    periodic_tasks::{MisfirePolicy, PeriodicJob},
+    Db, FromTaskContext, NewTask, TaskKind, TaskService, TaskState,
 };
 
 pub struct ChangSchedulePeriodicTask;
@@ -20,47 +21,209 @@ impl TaskKind for ChangSchedulePeriodicTask {
     }
 }
 
+/// How far ahead the scheduler enqueues periodic tasks, it runs once per
+/// horizon and plans up to the end of the following one. Defaults to an hour,
+/// set it with [`crate::task::TasksBuilder::periodic_horizon`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PeriodicHorizon(pub Duration);
+
+impl Default for PeriodicHorizon {
+    fn default() -> Self {
+        PeriodicHorizon(Duration::from_secs(60 * 60))
+    }
+}
+
+impl PeriodicHorizon {
+    fn as_chrono(&self) -> chrono::Duration {
+        chrono::Duration::from_std(self.0)
+            .ok()
+            .filter(|horizon| *horizon >= chrono::Duration::seconds(1))
+            .unwrap_or(chrono::Duration::seconds(1))
+    }
+}
+
 pub async fn schedule_periodic_task(ctx: Context) -> anyhow::Result<TaskState> {
     info!("schedule periodic task");
     let db = Db::from_context(&ctx)?;
     let task = Task::from_context(&ctx)?;
-    let periodic_jobs = PeriodicJobs::from_context(&ctx)?;
+    let task_id = task.id;
+    let horizon = ctx.get::<PeriodicHorizon>().copied().unwrap_or_default();
 
     let queue = task.queue.unwrap_or("default".to_string());
-    let schedule = get_schedule(Utc::now());
-    let tasks = get_scheduled_tasks(
-        schedule,
-        &periodic_jobs.entries().collect::<Vec<(&String, &String)>>(),
-        &queue,
-    );
+    let now = Utc::now();
+    let periodic_jobs = get_active_periodic_jobs(&db, &queue).await?;
+    let schedule = get_schedule(now, &horizon);
+
+    let mut tasks: Vec<NewTask> = vec![];
+    for (job, scheduled_until) in periodic_jobs.iter() {
+        // continue where the last run stopped, a job that was never
+        // scheduled starts now
+        let start_time = match scheduled_until {
+            Some(scheduled_until) if *scheduled_until >= now => *scheduled_until,
+            Some(scheduled_until) => {
+                tasks.extend(get_misfired_tasks(job, scheduled_until, &now, &queue));
+                now
+            }
+            None => now,
+        };
+
+        let slot = ScheduleSlot {
+            start_time,
+            end_time: schedule.end_time,
+        };
+        tasks.extend(get_scheduled_tasks(slot, std::slice::from_ref(job), &queue));
+    }
+
+    let names = periodic_jobs
+        .iter()
+        .map(|(job, _)| job.name.clone())
+        .collect::<Vec<String>>();
 
-    info!("insert {} tasks into {} queue", tasks.len(), queue);
+    let mut tx = db.begin().await?;
 
     if !tasks.is_empty() {
-        TaskService::batch_insert(&*db, &tasks).await?;
+        // slots another scheduler already inserted are skipped
+        let ids = TaskService::batch_insert(&mut *tx, &tasks).await?;
+        info!("insert {} tasks into {} queue", ids.len(), queue);
+    }
+
+    TaskService::set_periodic_tasks_scheduled_until(&mut *tx, &queue, &names, &schedule.end_time)
+        .await?;
+    tx.commit().await?;
+
+    // a scheduler that is already waiting, e.g. one aligned to a different
+    // horizon before a restart, takes over
+    if TaskService::has_queued_tasks(&*db, &ChangSchedulePeriodicTask::kind(), &queue, &task_id)
+        .await?
+    {
+        return Ok(TaskState::Completed);
     }
 
-    let scheduled_at = get_next_periodic_task_schedule(Utc::now());
+    let scheduled_at = get_next_periodic_task_schedule(Utc::now(), &horizon);
 
     info!(
         "schedule chang_schedule_periodic_task at {}",
         scheduled_at.to_rfc3339()
     );
-    Task::builder()
+    let next_task = Task::builder()
         .kind(&ChangSchedulePeriodicTask::kind())
         .args(serde_json::Value::Null)
         .scheduled_at(&scheduled_at)
-        .priority(std::i16::MAX)
-        .build()?
-        .insert(&*db)
-        .await?;
+        .priority(i16::MAX)
+        .periodic_name(&ChangSchedulePeriodicTask::kind())
+        .queue(&queue)
+        .build()?;
+    TaskService::batch_insert(&*db, &[next_task]).await?;
 
     Ok(TaskState::Completed)
 }
 
-fn get_next_periodic_task_schedule(now: DateTime<Utc>) -> DateTime<Utc> {
-    let schedule_in = (60 - now.minute()) * 60;
-    now + Duration::from_secs(schedule_in.into()) - Duration::from_secs(now.second().into())
+/// Reads the schedules and how far they have been scheduled from
+/// `chang.periodic_tasks`, skipping paused ones and ones that fail to parse.
+async fn get_active_periodic_jobs(
+    db: &Db,
+    queue: &str,
+) -> sqlx::Result<Vec<(PeriodicJob, Option<DateTime<Utc>>)>> {
+    let periodic_tasks = TaskService::get_periodic_tasks(&**db, queue).await?;
+
+    let jobs = periodic_tasks
+        .into_iter()
+        .filter(|periodic_task| !periodic_task.paused)
+        .filter_map(|periodic_task| match periodic_task.job() {
+            Ok(job) => Some((job, periodic_task.scheduled_until)),
+            Err(err) => {
+                error!(
+                    "invalid schedule for periodic task({}): {:?}",
+                    periodic_task.name, err
+                );
+                None
+            }
+        })
+        .collect();
+
+    Ok(jobs)
+}
+
+/// Tasks for the slots in `scheduled_until..now` that no runner enqueued,
+/// according to the job's [`MisfirePolicy`].
+pub fn get_misfired_tasks(
+    job: &PeriodicJob,
+    scheduled_until: &DateTime<Utc>,
+    now: &DateTime<Utc>,
+    queue: &str,
+) -> Vec<NewTask> {
+    let missed = job
+        .schedule
+        .after(scheduled_until)
+        .take_while(|scheduled_at| scheduled_at < now);
+
+    let missed = match job.misfire_policy {
+        MisfirePolicy::Skip => vec![],
+        MisfirePolicy::RunOnce => missed.last().into_iter().collect(),
+        MisfirePolicy::RunAll => missed.collect::<Vec<DateTime<Utc>>>(),
+    };
+
+    if !missed.is_empty() {
+        info!(
+            "periodic task({}) missed {} slots since {}",
+            job.name,
+            missed.len(),
+            scheduled_until.to_rfc3339()
+        );
+    }
+
+    missed
+        .iter()
+        .map(|scheduled_at| job.task_builder(scheduled_at, queue).build().unwrap())
+        .collect()
+}
+
+/// Enqueues the runs of a job that was just added up to the end of the
+/// current schedule, so it doesn't wait for the next scheduler run.
+pub async fn schedule_periodic_job(
+    db: &PgPool,
+    queue: &str,
+    job: &PeriodicJob,
+    now: &DateTime<Utc>,
+    horizon: &PeriodicHorizon,
+) -> crate::error::Result<Vec<Uuid>> {
+    let schedule = get_schedule(*now, horizon);
+    let slot = ScheduleSlot {
+        start_time: *now,
+        end_time: schedule.end_time,
+    };
+    let tasks = get_scheduled_tasks(slot, std::slice::from_ref(job), queue);
+
+    let mut tx = db.begin().await?;
+    let ids = TaskService::batch_insert(&mut *tx, &tasks).await?;
+    TaskService::set_periodic_tasks_scheduled_until(
+        &mut *tx,
+        queue,
+        std::slice::from_ref(&job.name),
+        &schedule.end_time,
+    )
+    .await?;
+    tx.commit().await?;
+
+    info!(
+        "periodic task({}) scheduled {} tasks until {}",
+        job.name,
+        ids.len(),
+        schedule.end_time.to_rfc3339()
+    );
+
+    Ok(ids)
+}
+
+fn get_next_periodic_task_schedule(now: DateTime<Utc>, horizon: &PeriodicHorizon) -> DateTime<Utc> {
+    start_of_horizon(&now, horizon) + horizon.as_chrono()
+}
+
+/// Truncates to a multiple of the horizon since the Unix epoch, schedulers on
+/// different runners have to land on the exact same `scheduled_at` for the
+/// periodic slot index to apply.
+pub fn start_of_horizon(now: &DateTime<Utc>, horizon: &PeriodicHorizon) -> DateTime<Utc> {
+    now.duration_trunc(horizon.as_chrono()).unwrap_or(*now)
 }
 
 #[derive(Debug, PartialEq)]
@@ -69,51 +232,60 @@ pub struct ScheduleSlot {
     pub end_time: DateTime<Utc>,
 }
 
-pub fn get_schedule(start_time: DateTime<Utc>) -> ScheduleSlot {
-    let schedule_in = 60 - start_time.minute();
-    let start_next_hour = start_time + Duration::from_secs((schedule_in * 60).into());
-    let end_next_hour = start_next_hour + Duration::from_secs(60 * 60);
+pub fn get_schedule(start_time: DateTime<Utc>, horizon: &PeriodicHorizon) -> ScheduleSlot {
+    let start_next = start_of_horizon(&start_time, horizon) + horizon.as_chrono();
+    let end_next = start_next + horizon.as_chrono();
 
     ScheduleSlot {
-        start_time: start_next_hour,
-        end_time: end_next_hour,
+        start_time: start_next,
+        end_time: end_next,
     }
 }
 
 pub fn get_scheduled_tasks(
     schedule_slot: ScheduleSlot,
-    periodic_jobs: &[(&String, &String)],
+    periodic_jobs: &[PeriodicJob],
     queue: &str,
 ) -> Vec<NewTask> {
     periodic_jobs
         .iter()
-        .filter_map(|(kind, schedule)| {
-            cron::Schedule::from_str(schedule).ok().map(|schedule| {
-                schedule
-                    .after(&schedule_slot.start_time)
-                    .take_while(|schedule_at| schedule_at <= &schedule_slot.end_time)
-                    .map(|schedule_at| {
-                        Task::builder()
-                            .kind(kind)
-                            .args(serde_json::value::Value::Null)
-                            .scheduled_at(&schedule_at)
-                            .queue(queue)
-                            .build()
-                            .unwrap()
-                    })
-                    .collect::<Vec<NewTask>>()
-            })
+        .flat_map(|job| {
+            job.schedule
+                .after(&schedule_slot.start_time)
+                .take_while(|schedule_at| schedule_at <= &schedule_slot.end_time)
+                .map(|schedule_at| job.task_builder(&schedule_at, queue).build().unwrap())
+                .collect::<Vec<NewTask>>()
         })
-        .flatten()
         .collect::<Vec<NewTask>>()
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::db::migration;
+    use crate::utils;
 
     use chrono::{DateTime, Utc};
 
+    fn job(kind: &str, schedule: &str) -> PeriodicJob {
+        PeriodicJob::builder()
+            .kind(kind)
+            .schedule(schedule.parse().unwrap())
+            .build()
+            .unwrap()
+    }
+
+    /// Ids are random per build, compare the tasks without them.
+    fn without_ids(tasks: Vec<NewTask>) -> Vec<NewTask> {
+        tasks
+            .into_iter()
+            .map(|task| NewTask {
+                id: uuid::Uuid::nil(),
+                ..task
+            })
+            .collect()
+    }
+
     #[test]
     fn can_get_schedule() {
         let now = "2014-11-28T11:00:00Z".parse::<DateTime<Utc>>().unwrap();
@@ -125,19 +297,41 @@ mod test {
             end_time,
         };
 
-        let schedule = get_schedule(now);
+        let schedule = get_schedule(now, &PeriodicHorizon::default());
 
         assert_eq!(expected, schedule)
     }
 
+    #[test]
+    fn plans_one_horizon_ahead() {
+        let horizon = PeriodicHorizon(Duration::from_secs(60 * 15));
+        let now = "2014-11-28T11:05:30Z".parse::<DateTime<Utc>>().unwrap();
+
+        let schedule = get_schedule(now, &horizon);
+        assert_eq!(
+            schedule,
+            ScheduleSlot {
+                start_time: "2014-11-28T11:15:00Z".parse::<DateTime<Utc>>().unwrap(),
+                end_time: "2014-11-28T11:30:00Z".parse::<DateTime<Utc>>().unwrap(),
+            }
+        );
+        assert_eq!(
+            get_next_periodic_task_schedule(now, &horizon),
+            "2014-11-28T11:15:00Z".parse::<DateTime<Utc>>().unwrap()
+        );
+
+        let tasks = get_scheduled_tasks(schedule, &[job("every-90s", "@every 90s")], "default");
+        assert_eq!(tasks.len(), 10);
+    }
+
     #[test]
     fn can_get_task_schedules() {
         let now = "2014-11-28T11:00:00Z".parse::<DateTime<Utc>>().unwrap();
-        let schedule = get_schedule(now);
+        let schedule = get_schedule(now, &PeriodicHorizon::default());
 
         let tasks = get_scheduled_tasks(
             schedule,
-            &[(&"every-five-minutes".into(), &"0 */5 * * * * *".into())],
+            &[job("every-five-minutes", "0 */5 * * * * *")],
             "default",
         );
 
@@ -150,6 +344,7 @@ mod test {
                 Task::builder()
                     .kind("every-five-minutes")
                     .args(serde_json::value::Value::Null)
+                    .periodic_name("every-five-minutes")
                     .scheduled_at(&(start + (five_minutes * i)))
                     .queue("default")
                     .build()
@@ -158,40 +353,40 @@ mod test {
         }
 
         assert_eq!(expected.len(), tasks.len());
-        assert_eq!(expected, tasks);
+        assert_eq!(without_ids(expected), without_ids(tasks));
     }
 
     #[test]
     fn can_get_task_hourly_schedules() {
         let now = "2014-11-28T11:00:00Z".parse::<DateTime<Utc>>().unwrap();
-        let schedule = get_schedule(now);
+        let schedule = get_schedule(now, &PeriodicHorizon::default());
 
-        let tasks = get_scheduled_tasks(
-            schedule,
-            &[(&"hourly".into(), &"@hourly".into())],
-            "default",
-        );
+        let tasks = get_scheduled_tasks(schedule, &[job("hourly", "@hourly")], "default");
 
         let scheduled_at = "2014-11-28T13:00:00Z".parse::<DateTime<Utc>>().unwrap();
         let expected = Task::builder()
             .kind("hourly")
             .args(serde_json::value::Value::Null)
+            .periodic_name("hourly")
             .scheduled_at(&scheduled_at)
             .queue("default")
             .build()
             .unwrap();
 
-        assert_eq!(expected, tasks.first().unwrap().clone());
+        assert_eq!(
+            without_ids(vec![expected]),
+            without_ids(tasks[..1].to_vec())
+        );
     }
 
     #[test]
     fn can_get_task_every_30_minutes_schedules() {
         let now = "2014-11-28T11:00:00Z".parse::<DateTime<Utc>>().unwrap();
-        let schedule = get_schedule(now);
+        let schedule = get_schedule(now, &PeriodicHorizon::default());
 
         let tasks = get_scheduled_tasks(
             schedule,
-            &[(&"every-30-minutes".into(), &"0 */30 * * * * *".into())],
+            &[job("every-30-minutes", "0 */30 * * * * *")],
             "default",
         );
 
@@ -204,6 +399,7 @@ mod test {
                 Task::builder()
                     .kind("every-30-minutes")
                     .args(serde_json::value::Value::Null)
+                    .periodic_name("every-30-minutes")
                     .scheduled_at(&(start + (thirty_minutes * i)))
                     .queue("default")
                     .build()
@@ -212,19 +408,19 @@ mod test {
         }
 
         assert_eq!(expected.len(), tasks.len());
-        assert_eq!(expected, tasks);
+        assert_eq!(without_ids(expected), without_ids(tasks));
     }
 
     #[test]
     fn can_get_multiple_tasks_schedules() {
         let now = "2014-11-28T11:00:00Z".parse::<DateTime<Utc>>().unwrap();
-        let schedule = get_schedule(now);
+        let schedule = get_schedule(now, &PeriodicHorizon::default());
 
         let tasks = get_scheduled_tasks(
             schedule,
             &[
-                (&"every-30-minutes".into(), &"0 */30 * * * * *".into()),
-                (&"every-five-minutes".into(), &"0 */5 * * * * *".into()),
+                job("every-30-minutes", "0 */30 * * * * *"),
+                job("every-five-minutes", "0 */5 * * * * *"),
             ],
             "default",
         );
@@ -238,6 +434,7 @@ mod test {
                 Task::builder()
                     .kind("every-30-minutes")
                     .args(serde_json::value::Value::Null)
+                    .periodic_name("every-30-minutes")
                     .scheduled_at(&(start + (thirty_minutes * i)))
                     .queue("default")
                     .build()
@@ -252,6 +449,7 @@ mod test {
                 Task::builder()
                     .kind("every-five-minutes")
                     .args(serde_json::value::Value::Null)
+                    .periodic_name("every-five-minutes")
                     .scheduled_at(&(start + (five_minutes * i)))
                     .queue("default")
                     .build()
@@ -260,20 +458,234 @@ mod test {
         }
 
         assert_eq!(expected.len(), tasks.len());
-        assert_eq!(expected, tasks);
+        assert_eq!(without_ids(expected), without_ids(tasks));
+    }
+
+    #[test]
+    fn can_share_kind_between_jobs() {
+        let now = "2014-11-28T11:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let schedule = get_schedule(now, &PeriodicHorizon::default());
+
+        let jobs = ["eu", "us"].map(|region| {
+            PeriodicJob::builder()
+                .name(&format!("report_{}", region))
+                .kind("report")
+                .schedule("@hourly".parse().unwrap())
+                .args(serde_json::json!({ "region": region }))
+                .tags(&[region.to_string()])
+                .timeout(Duration::from_secs(60))
+                .build()
+                .unwrap()
+        });
+
+        let tasks = get_scheduled_tasks(schedule, &jobs, "default");
+
+        let scheduled_at = "2014-11-28T13:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let expected = ["eu", "us"]
+            .map(|region| {
+                Task::builder()
+                    .kind("report")
+                    .args(serde_json::json!({ "region": region }))
+                    .tags(&[region.to_string()])
+                    .timeout(Duration::from_secs(60))
+                    .periodic_name(&format!("report_{}", region))
+                    .scheduled_at(&scheduled_at)
+                    .queue("default")
+                    .build()
+                    .unwrap()
+            })
+            .to_vec();
+
+        assert_eq!(without_ids(expected), without_ids(tasks));
+    }
+
+    #[tokio::test]
+    async fn schedules_active_periodic_tasks_from_table() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::tasks(&prepare.pool).await;
+
+        let report = PeriodicJob::builder()
+            .kind("report")
+            .schedule("0 * * * *".parse().unwrap())
+            .args(serde_json::json!({ "region": "eu" }))
+            .priority(10)
+            .max_attempts(1);
+        for name in ["active", "paused"] {
+            let job = report.clone().name(name).build().unwrap();
+            TaskService::add_periodic_task(&prepare.pool, &prepare.name, &job)
+                .await
+                .unwrap();
+        }
+        TaskService::pause_periodic_task(&prepare.pool, &prepare.name, "paused")
+            .await
+            .unwrap();
+
+        run_scheduler(&prepare).await;
+
+        // the rest of the current hour and the next hour
+        let tasks = TaskService::get_tasks_by_kind(&prepare.pool, "report", &prepare.name, 10)
+            .await
+            .unwrap();
+        assert_eq!(tasks.len(), 2);
+
+        let task = tasks.first().unwrap();
+        assert_eq!(task.args, serde_json::json!({ "region": "eu" }));
+        assert_eq!(task.priority, 10);
+        assert_eq!(task.max_attempts, 1);
+
+        let periodic_task = TaskService::get_periodic_task(&prepare.pool, &prepare.name, "active")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            periodic_task.scheduled_until,
+            tasks.iter().filter_map(|task| task.scheduled_at).max()
+        );
+
+        utils::test::cleanup(prepare).await;
+    }
+
+    #[tokio::test]
+    async fn catches_up_missed_slots() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::tasks(&prepare.pool).await;
+
+        let policies = [
+            ("skip", MisfirePolicy::Skip, 2),
+            ("run_once", MisfirePolicy::RunOnce, 3),
+            ("run_all", MisfirePolicy::RunAll, 5),
+        ];
+
+        let down_since = Utc::now() - chrono::Duration::hours(3);
+        for (name, misfire_policy, _) in policies.iter() {
+            let job = PeriodicJob::builder()
+                .name(name)
+                .kind(name)
+                .schedule("0 * * * *".parse().unwrap())
+                .misfire_policy(*misfire_policy)
+                .build()
+                .unwrap();
+            TaskService::add_periodic_task(&prepare.pool, &prepare.name, &job)
+                .await
+                .unwrap();
+        }
+        let names = policies.map(|(name, _, _)| name.to_string());
+        TaskService::set_periodic_tasks_scheduled_until(
+            &prepare.pool,
+            &prepare.name,
+            &names,
+            &down_since,
+        )
+        .await
+        .unwrap();
+
+        run_scheduler(&prepare).await;
+
+        for (name, _, expected) in policies {
+            let tasks = TaskService::get_tasks_by_kind(&prepare.pool, name, &prepare.name, 10)
+                .await
+                .unwrap();
+            assert_eq!(tasks.len(), expected, "{}", name);
+        }
+
+        utils::test::cleanup(prepare).await;
+    }
+
+    #[tokio::test]
+    async fn backfills_slots_in_range() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::tasks(&prepare.pool).await;
+
+        let from = "2014-11-28T09:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let to = "2014-11-28T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let report = job("report", "0 * * * *");
+
+        let ids =
+            TaskService::backfill_periodic_task(&prepare.pool, &prepare.name, &report, &from, &to)
+                .await
+                .unwrap();
+        assert_eq!(ids.len(), 4);
+
+        let ids =
+            TaskService::backfill_periodic_task(&prepare.pool, &prepare.name, &report, &from, &to)
+                .await
+                .unwrap();
+        assert!(ids.is_empty(), "backfilled pending slots twice");
+
+        utils::test::cleanup(prepare).await;
+    }
+
+    async fn run_scheduler(prepare: &utils::test::Prepare) {
+        let id = Task::builder()
+            .kind(&ChangSchedulePeriodicTask::kind())
+            .args(serde_json::Value::Null)
+            .queue(&prepare.name)
+            .build()
+            .unwrap()
+            .insert(&prepare.pool)
+            .await
+            .unwrap();
+        let task = TaskService::get_task(&prepare.pool, &id)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let mut ctx = Context::new();
+        ctx.put(prepare.pool.clone());
+        ctx.put(task);
+
+        let state = schedule_periodic_task(ctx).await.unwrap();
+        assert_eq!(state, TaskState::Completed);
+    }
+
+    #[test]
+    fn applies_misfire_policy() {
+        let scheduled_until = "2014-11-28T08:00:00Z".parse::<DateTime<Utc>>().unwrap();
+        let now = "2014-11-28T11:30:00Z".parse::<DateTime<Utc>>().unwrap();
+
+        let missed = |misfire_policy: MisfirePolicy| {
+            let mut report = job("report", "0 * * * *");
+            report.misfire_policy = misfire_policy;
+            get_misfired_tasks(&report, &scheduled_until, &now, "default")
+                .into_iter()
+                .filter_map(|task| task.scheduled_at)
+                .map(|scheduled_at| scheduled_at.to_rfc3339())
+                .collect::<Vec<String>>()
+        };
+
+        assert!(missed(MisfirePolicy::Skip).is_empty());
+        assert_eq!(
+            missed(MisfirePolicy::RunOnce),
+            vec!["2014-11-28T11:00:00+00:00"]
+        );
+        assert_eq!(
+            missed(MisfirePolicy::RunAll),
+            vec![
+                "2014-11-28T09:00:00+00:00",
+                "2014-11-28T10:00:00+00:00",
+                "2014-11-28T11:00:00+00:00"
+            ]
+        );
     }
 
     #[test]
     fn can_get_next_periodic_task_schedule() {
+        let hour = PeriodicHorizon::default();
         let expected = "2014-11-28T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
         let now = "2014-11-28T11:00:00Z".parse::<DateTime<Utc>>().unwrap();
 
-        assert_eq!(expected, get_next_periodic_task_schedule(now));
+        assert_eq!(expected, get_next_periodic_task_schedule(now, &hour));
 
         let now = "2014-11-28T11:05:05Z".parse::<DateTime<Utc>>().unwrap();
-        assert_eq!(expected, get_next_periodic_task_schedule(now));
+        assert_eq!(expected, get_next_periodic_task_schedule(now, &hour));
 
         let now = "2014-11-28T11:59:00Z".parse::<DateTime<Utc>>().unwrap();
-        assert_eq!(expected, get_next_periodic_task_schedule(now));
+        assert_eq!(expected, get_next_periodic_task_schedule(now, &hour));
     }
 }
@@ -0,0 +1,317 @@
+use serde::Deserialize;
+use sqlx::postgres::PgListener;
+use sqlx::PgPool;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+use super::store::TaskStore;
+use super::FromTaskContext;
+use crate::utils::context::Context;
+
+/// The channel progress updates are sent on.
+pub const PROGRESS_CHANNEL: &str = "chang_task_progress";
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProgressUpdate {
+    /// Percent done, from 0 to 100.
+    pub progress: Option<f32>,
+    pub message: Option<String>,
+    pub data: Option<serde_json::Value>,
+}
+
+/// Lets a handler report how far along the current task is. Updates are
+/// stored on the task and, by Postgres, sent on [`PROGRESS_CHANNEL`], at most
+/// once per interval. An update that falls into the interval is kept and
+/// written with the next one, or once the handler returns.
+#[derive(Clone, Debug)]
+pub struct Progress(Arc<ProgressInner>);
+
+#[derive(Debug)]
+struct ProgressInner {
+    store: Arc<dyn TaskStore>,
+    task_id: Uuid,
+    interval: Duration,
+    state: Mutex<ProgressState>,
+}
+
+#[derive(Debug, Default)]
+struct ProgressState {
+    written_at: Option<Instant>,
+    pending: Option<ProgressUpdate>,
+}
+
+impl Progress {
+    pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(1);
+
+    pub fn new(store: &Arc<dyn TaskStore>, task_id: &Uuid, interval: Duration) -> Self {
+        Progress(Arc::new(ProgressInner {
+            store: store.clone(),
+            task_id: *task_id,
+            interval,
+            state: Mutex::new(ProgressState::default()),
+        }))
+    }
+
+    pub async fn report(
+        &self,
+        progress: f32,
+        message: impl Into<String>,
+    ) -> Result<(), ProgressError> {
+        self.update(ProgressUpdate {
+            progress: Some(progress),
+            message: Some(message.into()),
+            data: None,
+        })
+        .await
+    }
+
+    pub async fn report_data(
+        &self,
+        progress: f32,
+        message: impl Into<String>,
+        data: serde_json::Value,
+    ) -> Result<(), ProgressError> {
+        self.update(ProgressUpdate {
+            progress: Some(progress),
+            message: Some(message.into()),
+            data: Some(data),
+        })
+        .await
+    }
+
+    /// Fails without writing anything if the progress isn't between 0 and
+    /// 100.
+    pub async fn update(&self, update: ProgressUpdate) -> Result<(), ProgressError> {
+        if let Some(progress) = update.progress {
+            if !(0.0..=100.0).contains(&progress) {
+                return Err(ProgressError::OutOfRange(progress));
+            }
+        }
+
+        let due = {
+            let mut state = self.0.state.lock().expect("failed to lock progress");
+            state.pending = Some(update);
+
+            let now = Instant::now();
+            let is_due = state
+                .written_at
+                .map(|written_at| now - written_at >= self.0.interval)
+                .unwrap_or(true);
+            if is_due {
+                state.written_at = Some(now);
+                state.pending.take()
+            } else {
+                None
+            }
+        };
+
+        match due {
+            Some(update) => self.write(&update).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Writes the update held back by the throttle, if any.
+    pub async fn flush(&self) -> Result<(), ProgressError> {
+        let pending = self
+            .0
+            .state
+            .lock()
+            .expect("failed to lock progress")
+            .pending
+            .take();
+
+        match pending {
+            Some(update) => self.write(&update).await,
+            None => Ok(()),
+        }
+    }
+
+    async fn write(&self, update: &ProgressUpdate) -> Result<(), ProgressError> {
+        self.0
+            .store
+            .update_progress(
+                &self.0.task_id,
+                update.progress,
+                update.message.as_deref(),
+                update.data.as_ref(),
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
+impl FromTaskContext for Progress {
+    type Error = ProgressError;
+
+    fn from_context(ctx: &Context) -> Result<Self, Self::Error> {
+        ctx.get::<Progress>()
+            .ok_or(ProgressError::ProgressNotFound)
+            .cloned()
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ProgressError {
+    #[error("Progress not found in Context")]
+    ProgressNotFound,
+
+    #[error("progress {0} is not between 0 and 100")]
+    OutOfRange(f32),
+
+    #[error(transparent)]
+    Db(#[from] sqlx::Error),
+}
+
+/// A progress update as sent on [`PROGRESS_CHANNEL`]. `data` is left out when
+/// the payload would exceed the size limit of a notification, then `message`
+/// is cut short or left out as well.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ProgressEvent {
+    pub id: Uuid,
+    pub queue: Option<String>,
+    pub kind: String,
+    pub progress: Option<f32>,
+    pub message: Option<String>,
+    pub data: Option<serde_json::Value>,
+}
+
+/// Listens on [`PROGRESS_CHANNEL`], parse the payloads with
+/// `serde_json::from_str::<ProgressEvent>`.
+pub async fn listen_progress(db: &PgPool) -> sqlx::Result<PgListener> {
+    let mut listener = PgListener::connect_with(db).await?;
+    listener.listen(PROGRESS_CHANNEL).await?;
+    Ok(listener)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::migration;
+    use crate::task::{PgTaskStore, Task, TaskService};
+    use crate::utils;
+
+    #[tokio::test]
+    async fn throttles_and_notifies_progress() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::tasks(&prepare.pool).await;
+
+        let id = Task::builder()
+            .kind("import")
+            .args(serde_json::Value::Null)
+            .queue(&prepare.name)
+            .build()
+            .unwrap()
+            .insert(&prepare.pool)
+            .await
+            .unwrap();
+        let claimed = TaskService::get_tasks(&prepare.pool, &prepare.name, 1, &[])
+            .await
+            .unwrap();
+        assert_eq!(claimed.len(), 1);
+
+        let db = sqlx::postgres::PgPoolOptions::new()
+            .max_connections(2)
+            .connect(&prepare.connection_string.to_string())
+            .await
+            .unwrap();
+        let mut listener = listen_progress(&db).await.unwrap();
+
+        let store: Arc<dyn TaskStore> = Arc::new(PgTaskStore::new(&db));
+        let progress = Progress::new(&store, &id, Duration::from_secs(60));
+        progress.report(10.0, "reading rows").await.unwrap();
+        progress
+            .report_data(50.0, "writing rows", serde_json::json!({ "rows": 500 }))
+            .await
+            .unwrap();
+
+        // the second report is held back by the throttle
+        let task = TaskService::get_task(&db, &id).await.unwrap().unwrap();
+        assert_eq!(task.progress, Some(10.0));
+        assert_eq!(task.progress_message.as_deref(), Some("reading rows"));
+
+        progress.flush().await.unwrap();
+
+        let task = TaskService::get_task(&db, &id).await.unwrap().unwrap();
+        assert_eq!(task.progress, Some(50.0));
+        assert_eq!(task.progress_data, Some(serde_json::json!({ "rows": 500 })));
+
+        let mut events = vec![];
+        for _ in 0..2 {
+            let notification = listener.recv().await.unwrap();
+            events.push(serde_json::from_str::<ProgressEvent>(notification.payload()).unwrap());
+        }
+        assert_eq!(events[0].progress, Some(10.0));
+        assert_eq!(events[1].message.as_deref(), Some("writing rows"));
+        assert_eq!(events[1].data, Some(serde_json::json!({ "rows": 500 })));
+        assert_eq!(events[1].id, id);
+
+        drop(listener);
+        db.close().await;
+        utils::test::cleanup(prepare).await;
+    }
+
+    #[tokio::test]
+    async fn rejects_progress_out_of_range_and_fits_the_message_into_a_notification() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::tasks(&prepare.pool).await;
+
+        let id = Task::builder()
+            .kind("import")
+            .args(serde_json::Value::Null)
+            .queue(&prepare.name)
+            .build()
+            .unwrap()
+            .insert(&prepare.pool)
+            .await
+            .unwrap();
+        TaskService::get_tasks(&prepare.pool, &prepare.name, 1, &[])
+            .await
+            .unwrap();
+
+        let db = sqlx::postgres::PgPoolOptions::new()
+            .max_connections(2)
+            .connect(&prepare.connection_string.to_string())
+            .await
+            .unwrap();
+        let mut listener = listen_progress(&db).await.unwrap();
+
+        let store: Arc<dyn TaskStore> = Arc::new(PgTaskStore::new(&db));
+        let progress = Progress::new(&store, &id, Duration::ZERO);
+        for out_of_range in [-1.0, 100.5, f32::NAN] {
+            assert!(matches!(
+                progress.report(out_of_range, "").await,
+                Err(ProgressError::OutOfRange(_))
+            ));
+        }
+        assert!(
+            TaskService::update_progress(&db, &id, Some(101.0), None, None)
+                .await
+                .is_err()
+        );
+
+        let message = "ä".repeat(5000);
+        progress.report(20.0, message.clone()).await.unwrap();
+
+        let task = TaskService::get_task(&db, &id).await.unwrap().unwrap();
+        assert_eq!(task.progress_message.as_ref(), Some(&message));
+
+        let notification = listener.recv().await.unwrap();
+        assert!(notification.payload().len() < 8000);
+        let event = serde_json::from_str::<ProgressEvent>(notification.payload()).unwrap();
+        assert_eq!(event.progress, Some(20.0));
+        let cut = event.message.unwrap();
+        assert!(!cut.is_empty() && message.starts_with(&cut));
+
+        drop(listener);
+        db.close().await;
+        utils::test::cleanup(prepare).await;
+    }
+}
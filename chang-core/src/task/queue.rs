@@ -1,6 +1,18 @@
 pub enum SchedulingStrategy {
     FCFS,
     Priority,
+    /// Like `Priority`, but a task's effective priority grows the longer it
+    /// waits past its `scheduled_at`, so low priority tasks can't starve.
+    /// `rate` is the number of priority points gained per second of waiting.
+    PriorityAging {
+        rate: f64,
+    },
+    /// Spreads claims round-robin across the tasks' `fairness_key`, so a
+    /// single key with a large backlog can't block the others. When set,
+    /// `max_per_key` caps how many tasks of one key may run at once.
+    Fair {
+        max_per_key: Option<i64>,
+    },
 }
 
 pub struct TaskQueue {
@@ -1,25 +1,36 @@
-use crate::db::tasks::{Task, TaskService, TaskState};
-use crate::task::periodic_tasks::PeriodicJobs;
+use crate::db::tasks::{Task, TaskState};
+use crate::task::encryption::{ArgsBinding, ArgsEncryption};
+use crate::task::failure::Failure;
+use crate::task::lazy_args::LazyArgs;
+use crate::task::outcome::{Outcome, TaskOutcome};
+use crate::task::panic::catch_panic;
+use crate::task::progress::Progress;
+use crate::task::snooze::Snooze;
+use crate::task::store::{Store, TaskStore};
+use crate::task::task_log::TaskLogContext;
 use crate::task::TaskHandler;
 use crate::utils::context::Context;
 
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use log::{error, info};
-use sqlx::PgPool;
 use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::Duration;
 use std::{collections::HashMap, error::Error};
+use tokio::time;
+use uuid::Uuid;
 
 pub type TaskRouter<E> = HashMap<String, Box<dyn TaskHandler<Context, E> + Send + Sync>>;
 
-pub async fn run_task<E: Into<Box<dyn Error + Send + Sync>>>(
-    task_pool: &PgPool,
+pub async fn run_task<E>(
+    store: &Arc<dyn TaskStore>,
     task: Task,
     router: &TaskRouter<E>,
     context: &Context,
     label: &str,
-    periodic_jobs: &PeriodicJobs,
-) where
-    E: std::fmt::Display + Debug,
+) -> TaskOutcome
+where
+    E: Into<Box<dyn Error + Send + Sync>> + std::fmt::Display + Debug + 'static,
 {
     info!("[{}] run task({}) with id({:?})", label, task.kind, task.id);
 
@@ -30,7 +41,7 @@ pub async fn run_task<E: Into<Box<dyn Error + Send + Sync>>>(
         );
         error!("[{}] task error: {}", label, error);
 
-        if let Err(err) = TaskService::failed(task_pool, &task.id, &error).await {
+        if let Err(err) = store.failed(&task.id, &error).await {
             error!(
                 "[{}] Failed to set task state {:?} task {:?}",
                 label,
@@ -38,31 +49,153 @@ pub async fn run_task<E: Into<Box<dyn Error + Send + Sync>>>(
                 err
             );
         };
-        return;
+        return TaskOutcome::new(&task, Outcome::Failed { error });
     };
 
     let task_id = task.id;
     let task_kind = task.kind.clone();
+    let finished = TaskOutcome::new(&task, Outcome::Completed);
+    let task_args = task.args.clone();
+    let args_binding = ArgsBinding::of(&task);
+    let timeout = task
+        .timeout_ms
+        .map(|timeout_ms| Duration::from_millis(timeout_ms.max(0) as u64));
+
+    let log_context = TaskLogContext::from(&task);
+    let lazy_args = LazyArgs::new(&task.args, store);
+    // offloaded args are read once the task is claimed, not with the claim
+    // itself, so extractors see them like inline args
+    if let Err(err) = lazy_args.load().await {
+        let error = err.to_string();
+        error!(
+            "[{}] task({}) failed to load args: {}",
+            label, task_id, error
+        );
+        fail_task(&**store, &task_id, label, &Failure::new(&error)).await;
+        return TaskOutcome {
+            outcome: Outcome::Failed { error },
+            ..finished
+        };
+    }
+    let snooze = Snooze::new(store);
+    let progress = Progress::new(store, &task_id, Progress::DEFAULT_INTERVAL);
 
     let mut ctx = Context::from(context);
     ctx.put(task);
-    ctx.put(task_pool.clone());
-    ctx.put(periodic_jobs.clone());
+    ctx.put(Store(store.clone()));
+    if let Some(pool) = store.pool() {
+        ctx.put(pool.clone());
+    }
+    ctx.put(lazy_args.clone());
+    ctx.put(snooze.clone());
+    ctx.put(progress.clone());
 
     let start = Utc::now();
-    match handler.call(ctx).await {
-        Err(err) => {
-            let error = format!("{:?}", err);
-            error!("[{}] task({}) failed to run: {:?}", label, task_id, error);
-            if let Err(err) = TaskService::failed(task_pool, &task_id, &error).await {
-                error!(
-                    "[{}] Failed to set task state {:?} task {:?}",
-                    label,
-                    TaskState::Discarded,
-                    err
-                );
+    let call = catch_panic(log_context.scope(async move { handler.call(ctx).await }));
+    let result = match timeout {
+        None => Some(call.await),
+        Some(timeout) => time::timeout(timeout, call).await.ok(),
+    };
+
+    // written before the state changes, progress is only stored on running tasks
+    if let Err(err) = progress.flush().await {
+        error!("[{}] Failed to write progress {:?}", label, err);
+    }
+
+    let Some(result) = result else {
+        let timeout_ms = timeout.unwrap_or_default().as_millis();
+        let error = format!("task timed out after {}ms", timeout_ms);
+        error!("[{}] task({}) failed to run: {}", label, task_id, error);
+        fail_task(&**store, &task_id, label, &Failure::new(&error)).await;
+        return TaskOutcome {
+            outcome: Outcome::Failed { error },
+            ..finished
+        };
+    };
+
+    let result = match result {
+        Ok(result) => result,
+        Err(panic) => {
+            let failure = panic.failure();
+            error!("[{}] task({}) {}", label, task_id, failure.error);
+            fail_task(&**store, &task_id, label, &failure).await;
+            return TaskOutcome {
+                outcome: Outcome::Failed {
+                    error: failure.error,
+                },
+                ..finished
             };
         }
+    };
+
+    let snoozed = if result.is_ok() { snooze.take() } else { None };
+
+    if let Some(request) = snoozed {
+        info!(
+            "[{}] task({}) with id({:?}) snoozed until {}",
+            label,
+            task_kind,
+            task_id,
+            request.scheduled_at.to_rfc3339()
+        );
+
+        // new args of an encrypted task stay encrypted
+        let template = lazy_args.loaded().unwrap_or(&task_args);
+        let args = match (request.args, context.get::<ArgsEncryption>()) {
+            (Some(args), Some(encryption)) => encryption
+                .encrypt_like(template, args, &args_binding)
+                .map(Some),
+            (args, _) => Ok(args),
+        };
+
+        let res = match args {
+            Ok(args) => store
+                .snooze(&task_id, &request.scheduled_at, args)
+                .await
+                .map_err(|err| err.to_string()),
+            Err(err) => Err(err.to_string()),
+        };
+        if let Err(err) = res {
+            error!("[{}] Failed to snooze task {:?}", label, err);
+        }
+        return TaskOutcome {
+            outcome: Outcome::Snoozed {
+                until: request.scheduled_at,
+            },
+            ..finished
+        };
+    }
+
+    let outcome = finish_task(&**store, &task_id, &task_kind, label, start, result).await;
+    TaskOutcome {
+        outcome,
+        ..finished
+    }
+}
+
+pub async fn finish_task<E>(
+    store: &dyn TaskStore,
+    task_id: &Uuid,
+    task_kind: &str,
+    label: &str,
+    start: DateTime<Utc>,
+    result: Result<TaskState, E>,
+) -> Outcome
+where
+    E: Into<Box<dyn Error + Send + Sync>> + Debug + 'static,
+{
+    match result {
+        Err(err) => {
+            let failure = Failure::from_error(err);
+            error!(
+                "[{}] task({}) failed to run: {:?}",
+                label, task_id, failure.error
+            );
+            fail_task(store, task_id, label, &failure).await;
+            Outcome::Failed {
+                error: failure.error,
+            }
+        }
         Ok(state) => {
             let end = Utc::now();
             let total = end - start;
@@ -74,8 +207,8 @@ pub async fn run_task<E: Into<Box<dyn Error + Send + Sync>>>(
                 total.num_milliseconds()
             );
             let res = match state {
-                TaskState::Completed => TaskService::complete(task_pool, &task_id).await,
-                _ => TaskService::complete(task_pool, &task_id).await,
+                TaskState::Completed => store.complete(task_id).await,
+                _ => store.complete(task_id).await,
             };
 
             if let Err(err) = res {
@@ -84,7 +217,20 @@ pub async fn run_task<E: Into<Box<dyn Error + Send + Sync>>>(
                     label, state, err
                 );
             };
+
+            Outcome::Completed
         }
+    }
+}
+
+pub async fn fail_task(store: &dyn TaskStore, task_id: &Uuid, label: &str, failure: &Failure) {
+    if let Err(err) = store.fail(task_id, failure).await {
+        error!(
+            "[{}] Failed to set task state {:?} task {:?}",
+            label,
+            TaskState::Discarded,
+            err
+        );
     };
 }
 
@@ -93,10 +239,15 @@ mod test {
     use anyhow::anyhow;
     use serde::Serialize;
 
+    use sqlx::PgPool;
+
     use super::*;
     use crate::db::migration;
-    use crate::task::periodic_tasks::PeriodicJobs;
-    use crate::task::{Db, FromTaskContext, Task, TaskKind};
+    use crate::db::tasks::TaskService;
+    use crate::task::{
+        CurrentTask, Db, FromTaskContext, MemoryTaskStore, Permanent, PgTaskStore, Retry, Task,
+        TaskKind,
+    };
     use crate::utils;
 
     #[tokio::test]
@@ -107,7 +258,6 @@ mod test {
         migration::tasks(&prepare.pool).await;
 
         let mut router: TaskRouter<_> = HashMap::new();
-        let periodic_jobs = PeriodicJobs(HashMap::new());
 
         router.insert(
             SimpleTask::kind(),
@@ -122,12 +272,11 @@ mod test {
         let context = &Context::new();
 
         run_task::<anyhow::Error>(
-            &prepare.pool,
+            &pg_store(&prepare.pool),
             task.clone(),
             &router,
-            &context,
+            context,
             &prepare.name,
-            &periodic_jobs,
         )
         .await;
 
@@ -142,6 +291,75 @@ mod test {
         utils::test::cleanup(prepare).await;
     }
 
+    #[tokio::test]
+    async fn classifies_handler_errors() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::tasks(&prepare.pool).await;
+
+        let mut router: TaskRouter<_> = HashMap::new();
+
+        router.insert(
+            SimpleTask::kind(),
+            Box::new(|ctx: Context| async move {
+                let CurrentTask(args) = CurrentTask::from_context(&ctx)?;
+                if args["value"] == "missing" {
+                    return Err(Permanent::new(anyhow!("user not found")).into());
+                }
+
+                let retry = Retry::new(anyhow!("rate limited"))
+                    .after(Duration::from_secs(3600))
+                    .details(serde_json::json!({ "status": 429 }));
+                Err::<TaskState, anyhow::Error>(retry.into())
+            }),
+        );
+
+        let missing = Task::builder()
+            .task(SimpleTask {
+                value: "missing".to_string(),
+            })
+            .unwrap()
+            .build()
+            .unwrap()
+            .insert(&prepare.pool)
+            .await
+            .unwrap();
+        let limited = insert_task(&prepare.pool).await.unwrap();
+        let store = pg_store(&prepare.pool);
+
+        for id in [missing, limited.id] {
+            let task = TaskService::get_task(&prepare.pool, &id)
+                .await
+                .unwrap()
+                .unwrap();
+            run_task::<anyhow::Error>(&store, task, &router, &Context::new(), &prepare.name).await;
+        }
+
+        let task = TaskService::get_task(&prepare.pool, &missing)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(task.state, TaskState::Discarded);
+
+        let task = TaskService::get_task(&prepare.pool, &limited.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(task.state, TaskState::Retryable);
+        assert!(task.scheduled_at.unwrap() > Utc::now() + chrono::Duration::minutes(50));
+
+        let errors = TaskService::errors(&prepare.pool, &limited.id)
+            .await
+            .unwrap();
+        assert_eq!(
+            errors[0].details,
+            Some(serde_json::json!({ "status": 429 }))
+        );
+
+        utils::test::cleanup(prepare).await;
+    }
+
     #[tokio::test]
     async fn discards_tasks_after_too_many_attempts() {
         let prepare = utils::test::prepare().await;
@@ -150,7 +368,6 @@ mod test {
         migration::tasks(&prepare.pool).await;
 
         let mut router: TaskRouter<_> = HashMap::new();
-        let periodic_jobs = PeriodicJobs(HashMap::new());
 
         router.insert(
             SimpleTask::kind(),
@@ -173,12 +390,11 @@ mod test {
         .unwrap();
 
         run_task::<anyhow::Error>(
-            &prepare.pool,
+            &pg_store(&prepare.pool),
             task.clone(),
             &router,
-            &context,
+            context,
             &prepare.name,
-            &periodic_jobs,
         )
         .await;
 
@@ -209,15 +425,126 @@ mod test {
 
         let task = insert_task(&prepare.pool).await.unwrap();
         let context = &Context::new();
-        let periodic_jobs = PeriodicJobs(HashMap::new());
 
         run_task::<anyhow::Error>(
-            &prepare.pool,
+            &pg_store(&prepare.pool),
+            task.clone(),
+            &router,
+            context,
+            &prepare.name,
+        )
+        .await;
+
+        let task = TaskService::get_task(&prepare.pool, &task.id)
+            .await
+            .unwrap();
+
+        let state = task.map(|t| t.state);
+
+        assert_eq!(Some(TaskState::Retryable), state);
+
+        utils::test::cleanup(prepare).await;
+    }
+
+    #[tokio::test]
+    async fn snoozes_without_consuming_an_attempt() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::tasks(&prepare.pool).await;
+
+        let mut router: TaskRouter<_> = HashMap::new();
+
+        router.insert(
+            SimpleTask::kind(),
+            Box::new(|ctx: Context| async move {
+                let snooze = Snooze::from_context(&ctx)?;
+                snooze.after(std::time::Duration::from_secs(3600));
+                snooze.with_args(serde_json::json!({ "value": "later" }));
+                Ok::<_, anyhow::Error>(TaskState::Completed)
+            }),
+        );
+
+        let task = insert_task(&prepare.pool).await.unwrap();
+        let task = TaskService::get_tasks(&prepare.pool, "default", 1, &[])
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|claimed| claimed.id == task.id)
+            .unwrap();
+        assert_eq!(task.attempt, 1);
+
+        let context = &Context::new();
+
+        run_task::<anyhow::Error>(
+            &pg_store(&prepare.pool),
+            task.clone(),
+            &router,
+            context,
+            &prepare.name,
+        )
+        .await;
+
+        let task = TaskService::get_task(&prepare.pool, &task.id)
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(task.state, TaskState::Available);
+        assert_eq!(task.attempt, 0);
+        assert_eq!(task.args, serde_json::json!({ "value": "later" }));
+        assert!(task.scheduled_at.unwrap() > Utc::now());
+
+        let comment: String = sqlx::query_scalar(
+            "select comment from chang.task_history where task_id = $1 and to_state = 'available'",
+        )
+        .bind(task.id)
+        .fetch_one(&prepare.pool)
+        .await
+        .unwrap();
+        assert!(comment.starts_with("snoozed until"));
+
+        utils::test::cleanup(prepare).await;
+    }
+
+    #[tokio::test]
+    async fn fails_when_handler_times_out() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::tasks(&prepare.pool).await;
+
+        let mut router: TaskRouter<_> = HashMap::new();
+
+        router.insert(
+            SimpleTask::kind(),
+            Box::new(|_ctx: Context| async {
+                time::sleep(Duration::from_secs(5)).await;
+                Ok::<_, anyhow::Error>(TaskState::Completed)
+            }),
+        );
+
+        let id = Task::builder()
+            .kind(&SimpleTask::kind())
+            .args(serde_json::Value::Null)
+            .timeout(Duration::from_millis(50))
+            .build()
+            .unwrap()
+            .insert(&prepare.pool)
+            .await
+            .unwrap();
+        let task = TaskService::get_task(&prepare.pool, &id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(task.timeout_ms, Some(50));
+
+        run_task::<anyhow::Error>(
+            &pg_store(&prepare.pool),
             task.clone(),
             &router,
-            &context,
+            &Context::new(),
             &prepare.name,
-            &periodic_jobs,
         )
         .await;
 
@@ -232,6 +559,75 @@ mod test {
         utils::test::cleanup(prepare).await;
     }
 
+    #[tokio::test]
+    async fn fails_when_handler_panics() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::tasks(&prepare.pool).await;
+
+        let mut router: TaskRouter<_> = HashMap::new();
+
+        router.insert(
+            SimpleTask::kind(),
+            Box::new(|ctx: Context| async move {
+                let task = ctx.get::<Task>().unwrap();
+                if task.args["value"] == "panic" {
+                    panic!("args missing");
+                }
+                Ok::<_, anyhow::Error>(TaskState::Completed)
+            }),
+        );
+
+        let store = pg_store(&prepare.pool);
+        let panics = Task {
+            args: serde_json::json!({ "value": "panic" }),
+            ..insert_task(&prepare.pool).await.unwrap()
+        };
+        let outcome = run_task::<anyhow::Error>(
+            &store,
+            panics.clone(),
+            &router,
+            &Context::new(),
+            &prepare.name,
+        )
+        .await;
+        assert_eq!(
+            outcome.outcome,
+            Outcome::Failed {
+                error: "task panicked: args missing".to_string()
+            }
+        );
+
+        let task = TaskService::get_task(&prepare.pool, &panics.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(task.state, TaskState::Retryable);
+
+        let errors = TaskService::errors(&prepare.pool, &panics.id)
+            .await
+            .unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].error.as_deref(),
+            Some("task panicked: args missing")
+        );
+        assert!(errors[0]
+            .details
+            .as_ref()
+            .is_some_and(|details| details["backtrace"].is_string()));
+
+        // the same router keeps running tasks after a panic
+        let completes = insert_task(&prepare.pool).await.unwrap();
+        let outcome =
+            run_task::<anyhow::Error>(&store, completes, &router, &Context::new(), &prepare.name)
+                .await;
+        assert_eq!(outcome.outcome, Outcome::Completed);
+
+        utils::test::cleanup(prepare).await;
+    }
+
     #[tokio::test]
     async fn failes_when_handler_does_not_exist() {
         let prepare = utils::test::prepare().await;
@@ -243,15 +639,13 @@ mod test {
 
         let task = insert_task(&prepare.pool).await.unwrap();
         let context = &Context::new();
-        let periodic_jobs = PeriodicJobs(HashMap::new());
 
         run_task::<anyhow::Error>(
-            &prepare.pool,
+            &pg_store(&prepare.pool),
             task.clone(),
             &router,
-            &context,
+            context,
             &prepare.name,
-            &periodic_jobs,
         )
         .await;
 
@@ -266,6 +660,105 @@ mod test {
         utils::test::cleanup(prepare).await;
     }
 
+    #[tokio::test]
+    async fn runs_against_the_memory_store() {
+        let memory = Arc::new(MemoryTaskStore::new());
+        let store: Arc<dyn TaskStore> = memory.clone();
+
+        let mut router: TaskRouter<_> = HashMap::new();
+
+        router.insert(
+            SimpleTask::kind(),
+            Box::new(|ctx: Context| async move {
+                assert!(Db::from_context(&ctx).is_err());
+                let store = Store::from_context(&ctx)?;
+                let progress = Progress::from_context(&ctx)?;
+                progress.report(100.0, "done").await?;
+
+                store
+                    .insert(
+                        Task::builder()
+                            .kind("follow_up")
+                            .args(serde_json::Value::Null)
+                            .build()?,
+                    )
+                    .await?;
+
+                Ok::<_, anyhow::Error>(TaskState::Completed)
+            }),
+        );
+
+        let id = store
+            .insert(
+                Task::builder()
+                    .task(SimpleTask {
+                        value: "Chang".to_string(),
+                    })
+                    .unwrap()
+                    .build()
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let queue = crate::task::TaskQueue::builder().name("default").build();
+        let task = store.claim(&queue, &[]).await.unwrap().pop().unwrap();
+
+        run_task::<anyhow::Error>(&store, task, &router, &Context::new(), "memory").await;
+
+        let tasks = memory.tasks();
+        assert_eq!(tasks.len(), 2);
+        assert_eq!(tasks[0].id, id);
+        assert_eq!(tasks[0].state, TaskState::Completed);
+        assert_eq!(tasks[0].progress_message.as_deref(), Some("done"));
+        assert_eq!(tasks[1].kind, "follow_up");
+        assert_eq!(tasks[1].state, TaskState::Available);
+    }
+
+    #[tokio::test]
+    async fn loads_offloaded_args() {
+        let memory = Arc::new(MemoryTaskStore::new());
+        let store: Arc<dyn TaskStore> = memory.clone();
+
+        let mut router: TaskRouter<_> = HashMap::new();
+
+        router.insert(
+            "render_report".to_string(),
+            Box::new(|ctx: Context| async move {
+                let lazy_args = LazyArgs::from_context(&ctx)?;
+                assert!(lazy_args.is_offloaded());
+                assert!(lazy_args.loaded().is_some());
+
+                let CurrentTask(args) = CurrentTask::from_context(&ctx)?;
+                if args["rows"].as_array().map(Vec::len) != Some(100) {
+                    return Err(anyhow!("unexpected args {}", args));
+                }
+
+                Ok::<_, anyhow::Error>(TaskState::Completed)
+            }),
+        );
+
+        let rows = (0..100).collect::<Vec<_>>();
+        let task = Task::builder()
+            .kind("render_report")
+            .args(serde_json::json!({ "rows": rows }))
+            .offload_above(64)
+            .build()
+            .unwrap();
+        assert!(task.payload.is_some());
+        let id = store.insert(task).await.unwrap();
+
+        let queue = crate::task::TaskQueue::builder().name("default").build();
+        let task = store.claim(&queue, &[]).await.unwrap().pop().unwrap();
+        assert!(crate::db::payloads::Payload::reference(&task.args).is_some());
+
+        let outcome =
+            run_task::<anyhow::Error>(&store, task, &router, &Context::new(), "memory").await;
+
+        assert_eq!(outcome.outcome, Outcome::Completed);
+        assert_eq!(memory.tasks()[0].id, id);
+    }
+
     #[derive(Serialize)]
     struct SimpleTask {
         value: String,
@@ -277,6 +770,10 @@ mod test {
         }
     }
 
+    fn pg_store(db: &PgPool) -> Arc<dyn TaskStore> {
+        Arc::new(PgTaskStore::new(db))
+    }
+
     async fn insert_task(db: &PgPool) -> anyhow::Result<Task> {
         let task = SimpleTask {
             value: "Chang".to_string(),
@@ -0,0 +1,83 @@
+use chrono::{DateTime, Utc};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::store::TaskStore;
+use super::FromTaskContext;
+use crate::utils::context::Context;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SnoozeRequest {
+    pub scheduled_at: DateTime<Utc>,
+    pub args: Option<serde_json::Value>,
+}
+
+/// Lets a handler put the current task back in the queue instead of
+/// completing it. The snooze is applied once the handler returns `Ok`, and
+/// doesn't count as an attempt.
+#[derive(Clone, Debug, Default)]
+pub struct Snooze {
+    inner: Arc<Mutex<SnoozeInner>>,
+    /// Tells the time `after` counts from, the system clock if unset.
+    store: Option<Arc<dyn TaskStore>>,
+}
+
+#[derive(Debug, Default)]
+struct SnoozeInner {
+    scheduled_at: Option<DateTime<Utc>>,
+    args: Option<serde_json::Value>,
+}
+
+impl Snooze {
+    pub fn new(store: &Arc<dyn TaskStore>) -> Self {
+        Snooze {
+            inner: Arc::default(),
+            store: Some(store.clone()),
+        }
+    }
+
+    pub fn until(&self, scheduled_at: DateTime<Utc>) {
+        self.lock().scheduled_at = Some(scheduled_at);
+    }
+
+    pub fn after(&self, duration: Duration) {
+        let now = self
+            .store
+            .as_ref()
+            .map_or_else(Utc::now, |store| store.now());
+        self.until(now + duration);
+    }
+
+    /// Replaces the task's args for the next run. Has no effect unless
+    /// `until` or `after` is called as well.
+    pub fn with_args(&self, args: serde_json::Value) {
+        self.lock().args = Some(args);
+    }
+
+    pub fn take(&self) -> Option<SnoozeRequest> {
+        let mut inner = self.lock();
+        let scheduled_at = inner.scheduled_at.take()?;
+        let args = inner.args.take();
+        Some(SnoozeRequest { scheduled_at, args })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, SnoozeInner> {
+        self.inner.lock().expect("failed to lock snooze")
+    }
+}
+
+impl FromTaskContext for Snooze {
+    type Error = SnoozeError;
+
+    fn from_context(ctx: &Context) -> Result<Self, Self::Error> {
+        ctx.get::<Snooze>()
+            .ok_or(SnoozeError::SnoozeNotFound)
+            .cloned()
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum SnoozeError {
+    #[error("Snooze not found in Context")]
+    SnoozeNotFound,
+}
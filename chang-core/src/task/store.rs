@@ -0,0 +1,219 @@
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::PgPool;
+use std::fmt::Debug;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use super::failure::Failure;
+use super::FromTaskContext;
+use crate::db::payloads::PayloadService;
+use crate::db::tasks::{NewTask, Task, TaskService};
+use crate::task::queue::{SchedulingStrategy, TaskQueue};
+use crate::utils::context::Context;
+
+/// The queue operations the runner needs. [`PgTaskStore`] is what
+/// [`crate::task::TasksBuilder::connect`] uses, [`crate::task::MemoryTaskStore`]
+/// keeps tasks in memory for tests.
+#[async_trait]
+pub trait TaskStore: Debug + Send + Sync {
+    async fn insert(&self, task: NewTask) -> sqlx::Result<Uuid>;
+
+    async fn get_task(&self, task_id: &Uuid) -> sqlx::Result<Option<Task>>;
+
+    /// Claims up to `queue.limit` tasks in the order of `queue.strategy`
+    /// and moves them to `running`.
+    async fn claim(&self, queue: &TaskQueue, excluded_kinds: &[String]) -> sqlx::Result<Vec<Task>>;
+
+    /// Claims up to `limit` tasks of `kind` in the order they were scheduled.
+    async fn claim_kind(&self, queue: &str, kind: &str, limit: i64) -> sqlx::Result<Vec<Task>>;
+
+    /// Counts up to `limit` tasks of `kind` that are due, without claiming
+    /// them.
+    async fn count_due(&self, queue: &str, kind: &str, limit: i64) -> sqlx::Result<i64>;
+
+    async fn complete(&self, task_id: &Uuid) -> sqlx::Result<()>;
+
+    /// Moves the task to `retryable`, or to `discarded` if the failure is
+    /// permanent or the task used up its attempts.
+    async fn fail(&self, task_id: &Uuid, failure: &Failure) -> sqlx::Result<()>;
+
+    /// Fails the task with a transient error.
+    async fn failed(&self, task_id: &Uuid, error: &str) -> sqlx::Result<()> {
+        self.fail(task_id, &Failure::new(error)).await
+    }
+
+    /// Makes the task available again at `scheduled_at` without using up an
+    /// attempt, `args` replaces the task's args when set.
+    async fn snooze(
+        &self,
+        task_id: &Uuid,
+        scheduled_at: &DateTime<Utc>,
+        args: Option<serde_json::Value>,
+    ) -> sqlx::Result<()>;
+
+    /// Returns `false` if the task isn't running.
+    async fn update_progress(
+        &self,
+        task_id: &Uuid,
+        progress: Option<f32>,
+        message: Option<&str>,
+        data: Option<&serde_json::Value>,
+    ) -> sqlx::Result<bool>;
+
+    /// Args that were offloaded with [`crate::task::TaskBuilder::offload_above`].
+    async fn load_payload(&self, payload_id: &Uuid) -> sqlx::Result<Option<serde_json::Value>>;
+
+    /// The pool behind the store. Handlers get it as [`crate::task::Db`], and
+    /// features that need Postgres, like periodic jobs, kind limits and
+    /// advisory locks, are only used when there is one.
+    fn pool(&self) -> Option<&PgPool> {
+        None
+    }
+
+    /// The time tasks are due against.
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn is_closed(&self) -> bool {
+        false
+    }
+
+    /// Resolves once the store is closed.
+    async fn closed(&self) {
+        futures::future::pending::<()>().await
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PgTaskStore {
+    db: PgPool,
+}
+
+impl PgTaskStore {
+    pub fn new(db: &PgPool) -> Self {
+        PgTaskStore { db: db.clone() }
+    }
+}
+
+#[async_trait]
+impl TaskStore for PgTaskStore {
+    async fn insert(&self, task: NewTask) -> sqlx::Result<Uuid> {
+        TaskService::insert(&self.db, task).await
+    }
+
+    async fn get_task(&self, task_id: &Uuid) -> sqlx::Result<Option<Task>> {
+        TaskService::get_task(&self.db, task_id).await
+    }
+
+    async fn claim(&self, queue: &TaskQueue, excluded_kinds: &[String]) -> sqlx::Result<Vec<Task>> {
+        let db = &self.db;
+        match queue.strategy {
+            SchedulingStrategy::Priority => {
+                TaskService::get_priority_tasks(db, &queue.name, queue.limit, excluded_kinds).await
+            }
+            SchedulingStrategy::PriorityAging { rate } => {
+                TaskService::get_aging_priority_tasks(
+                    db,
+                    &queue.name,
+                    queue.limit,
+                    rate,
+                    excluded_kinds,
+                )
+                .await
+            }
+            SchedulingStrategy::Fair { max_per_key } => {
+                TaskService::get_fair_tasks(
+                    db,
+                    &queue.name,
+                    queue.limit,
+                    max_per_key,
+                    excluded_kinds,
+                )
+                .await
+            }
+            SchedulingStrategy::FCFS => {
+                TaskService::get_tasks(db, &queue.name, queue.limit, excluded_kinds).await
+            }
+        }
+    }
+
+    async fn claim_kind(&self, queue: &str, kind: &str, limit: i64) -> sqlx::Result<Vec<Task>> {
+        TaskService::get_batch_tasks(&self.db, queue, kind, limit).await
+    }
+
+    async fn count_due(&self, queue: &str, kind: &str, limit: i64) -> sqlx::Result<i64> {
+        TaskService::count_due_tasks(&self.db, queue, kind, limit).await
+    }
+
+    async fn complete(&self, task_id: &Uuid) -> sqlx::Result<()> {
+        TaskService::complete(&self.db, task_id).await
+    }
+
+    async fn fail(&self, task_id: &Uuid, failure: &Failure) -> sqlx::Result<()> {
+        TaskService::fail(&self.db, task_id, failure).await
+    }
+
+    async fn snooze(
+        &self,
+        task_id: &Uuid,
+        scheduled_at: &DateTime<Utc>,
+        args: Option<serde_json::Value>,
+    ) -> sqlx::Result<()> {
+        TaskService::snooze(&self.db, task_id, scheduled_at, args).await
+    }
+
+    async fn update_progress(
+        &self,
+        task_id: &Uuid,
+        progress: Option<f32>,
+        message: Option<&str>,
+        data: Option<&serde_json::Value>,
+    ) -> sqlx::Result<bool> {
+        TaskService::update_progress(&self.db, task_id, progress, message, data).await
+    }
+
+    async fn load_payload(&self, payload_id: &Uuid) -> sqlx::Result<Option<serde_json::Value>> {
+        PayloadService::get(&self.db, payload_id).await
+    }
+
+    fn pool(&self) -> Option<&PgPool> {
+        Some(&self.db)
+    }
+
+    fn is_closed(&self) -> bool {
+        self.db.is_closed()
+    }
+
+    async fn closed(&self) {
+        self.db.close_event().await
+    }
+}
+
+/// The store the running task came from, lets handlers enqueue follow-up
+/// tasks without depending on Postgres.
+#[derive(Clone, Debug)]
+pub struct Store(pub Arc<dyn TaskStore>);
+
+#[derive(thiserror::Error, Debug)]
+pub enum StoreError {
+    #[error("TaskStore not found in Context")]
+    StoreNotFound,
+}
+
+impl FromTaskContext for Store {
+    type Error = StoreError;
+
+    fn from_context(ctx: &Context) -> Result<Self, Self::Error> {
+        ctx.get::<Store>().ok_or(StoreError::StoreNotFound).cloned()
+    }
+}
+
+impl std::ops::Deref for Store {
+    type Target = dyn TaskStore;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.0
+    }
+}
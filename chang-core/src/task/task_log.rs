@@ -0,0 +1,39 @@
+use std::future::Future;
+use uuid::Uuid;
+
+use crate::db::tasks::Task;
+
+tokio::task_local! {
+    static CURRENT_TASK: TaskLogContext;
+}
+
+/// The task a handler runs for. [`crate::otel::logs::ChangLogBridge`] adds it
+/// to records logged inside the handler as `task.id`, `task.kind` and
+/// `task.attempt`. Futures the handler spawns onto other tokio tasks don't
+/// inherit it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TaskLogContext {
+    pub id: Uuid,
+    pub kind: String,
+    pub attempt: i16,
+}
+
+impl TaskLogContext {
+    pub fn current() -> Option<TaskLogContext> {
+        CURRENT_TASK.try_with(|task| task.clone()).ok()
+    }
+
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        CURRENT_TASK.scope(self, future).await
+    }
+}
+
+impl From<&Task> for TaskLogContext {
+    fn from(task: &Task) -> Self {
+        TaskLogContext {
+            id: task.id,
+            kind: task.kind.clone(),
+            attempt: task.attempt,
+        }
+    }
+}
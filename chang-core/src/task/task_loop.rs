@@ -1,47 +1,40 @@
-use std::collections::HashMap;
 use std::time::Duration;
 
 use futures::future;
 use log::error;
-use sqlx::PgPool;
 use std::error::Error;
 use std::fmt::Debug;
+use std::sync::Arc;
 use tokio::time;
 use tokio::{self, select};
 use tokio_util::sync::CancellationToken;
 
-use crate::task::periodic_tasks::PeriodicJobs;
 use crate::task::{
     run_task::{run_task, TaskRouter},
-    SchedulingStrategy, TaskQueue, TaskService,
+    store::TaskStore,
+    TaskQueue,
 };
 use crate::utils::context::Context;
 
-pub async fn start<E: Into<Box<dyn Error + Send + Sync>>>(
+pub async fn start<E>(
     label: &str,
     cancel_token: &CancellationToken,
-    db: &PgPool,
+    store: &Arc<dyn TaskStore>,
     queue: &TaskQueue,
     router: &TaskRouter<E>,
     context: &Context,
-    periodic_jobs: &HashMap<String, String>,
+    excluded_kinds: &[String],
 ) where
-    E: std::fmt::Display + Debug,
+    E: Into<Box<dyn Error + Send + Sync>> + std::fmt::Display + Debug + 'static,
 {
     let mut interval = time::interval(Duration::from_millis(queue.interval));
-    let periodic_jobs = PeriodicJobs(periodic_jobs.clone());
 
     loop {
-        if cancel_token.is_cancelled() || db.is_closed() {
+        if cancel_token.is_cancelled() || store.is_closed() {
             break;
         }
 
-        let get_tasks = match queue.strategy {
-            SchedulingStrategy::Priority => {
-                TaskService::get_priority_tasks(db, &queue.name, queue.limit).await
-            }
-            SchedulingStrategy::FCFS => TaskService::get_tasks(db, &queue.name, queue.limit).await,
-        };
+        let get_tasks = store.claim(queue, excluded_kinds).await;
 
         let tasks = match get_tasks {
             Ok(tasks) => tasks,
@@ -60,7 +53,7 @@ pub async fn start<E: Into<Box<dyn Error + Send + Sync>>>(
         let mut futures: Vec<_> = vec![];
 
         for task in tasks.into_iter() {
-            let fut = run_task::<E>(&db, task, &router, &context, &label, &periodic_jobs);
+            let fut = run_task::<E>(store, task, router, context, label);
             futures.push(Box::pin(fut));
         }
 
@@ -71,7 +64,7 @@ pub async fn start<E: Into<Box<dyn Error + Send + Sync>>>(
                 break;
             }
 
-            _ = db.close_event() => {
+            _ = store.closed() => {
                 break;
             }
 
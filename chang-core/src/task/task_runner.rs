@@ -1,10 +1,17 @@
-use super::periodic_tasks::PeriodicJobs;
+use super::batch_loop::{self, run_batch, BatchRoute, BatchRouter};
+use super::encryption::ArgsEncryption;
+use super::exclusive_loop;
+use super::outcome::DrainReport;
+use super::periodic_tasks::schedule::PeriodicHorizon;
+use super::periodic_tasks::{MisfirePolicy, PeriodicJob, PeriodicSchedule, PeriodicScheduleError};
 use super::queue::{SchedulingStrategy, TaskQueue};
-use super::run_task::TaskRouter;
-use super::{task_loop, FromTaskContext};
+use super::run_task::{run_task, TaskRouter};
+use super::store::{PgTaskStore, TaskStore};
+use super::upcast::Upcasters;
+use super::{task_loop, FromTaskContext, TaskService};
 
 use crate::task::periodic_tasks;
-use crate::task::traits::TaskHandler;
+use crate::task::traits::{BatchTaskHandler, TaskHandler};
 use crate::utils::context::{AnyClone, Context};
 
 use chrono::Utc;
@@ -26,9 +33,12 @@ pub struct TaskRunner<E: Into<Box<dyn Error + Send + Sync>> + 'static>
 where
     E: std::fmt::Display + Debug,
 {
-    db: PgPool,
+    store: Arc<dyn TaskStore>,
     routes: Arc<TaskRouter<E>>,
-    periodic_jobs: Arc<HashMap<String, String>>,
+    batch_routes: Arc<BatchRouter<E>>,
+    exclusive_kinds: Arc<Vec<String>>,
+    kind_limits: Arc<HashMap<String, i32>>,
+    periodic_jobs: Arc<HashMap<String, PeriodicJob>>,
     context: Arc<Context>,
     queue: Arc<TaskQueue>,
     concurrency: i64,
@@ -47,11 +57,15 @@ where
 
         let inner = TasksBuilderInner {
             routes: HashMap::new(),
+            batch_routes: HashMap::new(),
+            exclusive_kinds: vec![],
+            kind_limits: HashMap::new(),
             context: Context::new(),
             queue: default_queue,
             concurrency: 10,
             label: String::from("chang-tasks"),
             periodic_jobs: HashMap::new(),
+            upcasters: Upcasters::default(),
         };
 
         TasksBuilder { inner }
@@ -62,15 +76,22 @@ where
 
         let mut handles: Vec<tokio::task::JoinHandle<()>> = vec![];
         let token = CancellationToken::new();
+        let excluded_kinds: Arc<Vec<String>> = Arc::new(
+            self.batch_routes
+                .keys()
+                .chain(self.exclusive_kinds.iter())
+                .cloned()
+                .collect(),
+        );
 
         for thread in 0..concurrency {
             let context = self.context.clone();
             let router = self.routes.clone();
-            let task_pool = self.db.clone();
+            let store = self.store.clone();
             let queue = self.queue.clone();
             let label = self.label.clone();
             let cancel_token = token.clone();
-            let periodic_jobs = self.periodic_jobs.clone();
+            let excluded_kinds = excluded_kinds.clone();
 
             let handle = tokio::spawn(async move {
                 let thread_label = format!("{} {} queue({})", thread, label, queue.name);
@@ -78,11 +99,11 @@ where
                 task_loop::start(
                     &thread_label,
                     &cancel_token,
-                    &task_pool,
+                    &store,
                     &queue,
                     &router,
                     &context,
-                    &periodic_jobs,
+                    &excluded_kinds,
                 )
                 .await;
             });
@@ -90,20 +111,96 @@ where
             handles.push(handle);
         }
 
-        let periodic_jobs = self.periodic_jobs.clone();
-        let queue = self.queue.clone();
-        let db = self.db.clone();
-        tokio::spawn(async move {
-            // try insert chang_schedule_periodic_tasks task
-            if periodic_jobs.is_empty() {
-                return;
-            }
+        for kind in self.batch_routes.keys() {
+            let kind = kind.clone();
+            let context = self.context.clone();
+            let batch_routes = self.batch_routes.clone();
+            let store = self.store.clone();
+            let queue = self.queue.clone();
+            let label = self.label.clone();
+            let cancel_token = token.clone();
 
-            let now = Utc::now() - Duration::from_secs(3600);
-            if let Err(error) = periodic_tasks::init(&periodic_jobs, &db, &queue.name, &now).await {
-                error!("{:?}", error);
-            };
-        });
+            let handle = tokio::spawn(async move {
+                let batch_label = format!("batch({}) {} queue({})", kind, label, queue.name);
+                let Some(route) = batch_routes.get(&kind) else {
+                    return;
+                };
+
+                batch_loop::start(
+                    &batch_label,
+                    &cancel_token,
+                    &store,
+                    &queue,
+                    &kind,
+                    route,
+                    &context,
+                )
+                .await;
+            });
+
+            handles.push(handle);
+        }
+
+        for kind in self.exclusive_kinds.iter() {
+            let kind = kind.clone();
+            let context = self.context.clone();
+            let router = self.routes.clone();
+            let store = self.store.clone();
+            let queue = self.queue.clone();
+            let label = self.label.clone();
+            let cancel_token = token.clone();
+
+            let handle = tokio::spawn(async move {
+                let exclusive_label =
+                    format!("exclusive({}) {} queue({})", kind, label, queue.name);
+
+                exclusive_loop::start(
+                    &exclusive_label,
+                    &cancel_token,
+                    &store,
+                    &queue,
+                    &kind,
+                    &router,
+                    &context,
+                )
+                .await;
+            });
+
+            handles.push(handle);
+        }
+
+        // kind limits and periodic jobs live in Postgres
+        if let Some(db) = self.store.pool() {
+            let kind_limits = self.kind_limits.clone();
+            let limits_db = db.clone();
+            tokio::spawn(async move {
+                for (kind, max_running) in kind_limits.iter() {
+                    if let Err(error) =
+                        TaskService::add_kind_limit(&limits_db, kind, *max_running).await
+                    {
+                        error!("failed to limit kind({}): {:?}", kind, error);
+                    }
+                }
+            });
+
+            let periodic_jobs = self.periodic_jobs.clone();
+            let queue = self.queue.clone();
+            let db = db.clone();
+            let horizon = self
+                .context
+                .get::<PeriodicHorizon>()
+                .copied()
+                .unwrap_or_default();
+            tokio::spawn(async move {
+                // try insert chang_schedule_periodic_tasks task
+                let now = Utc::now();
+                if let Err(error) =
+                    periodic_tasks::init(&periodic_jobs, &db, &queue.name, &now, &horizon).await
+                {
+                    error!("{:?}", error);
+                };
+            });
+        }
 
         tokio::spawn(async move {
             tokio::signal::ctrl_c().await.unwrap();
@@ -113,6 +210,49 @@ where
 
         future::select_all(handles)
     }
+
+    /// Runs every task of the queue that is due, one after the other on the
+    /// current task, until there is nothing left to claim. Tasks that become
+    /// due while draining, like dependents and tasks enqueued by handlers, run
+    /// as well. Meant for tests, together with a [`super::MemoryTaskStore`]
+    /// whose clock is moved between drains.
+    pub async fn drain(&self) -> sqlx::Result<DrainReport> {
+        let mut report = DrainReport::default();
+        let excluded_kinds = self.batch_routes.keys().cloned().collect::<Vec<_>>();
+        let label = format!("drain {} queue({})", self.label, self.queue.name);
+
+        loop {
+            let mut ran = false;
+
+            for (kind, route) in self.batch_routes.iter() {
+                let tasks = self
+                    .store
+                    .claim_kind(&self.queue.name, kind, route.size)
+                    .await?;
+
+                if !tasks.is_empty() {
+                    ran = true;
+                    let runs = run_batch(&self.store, tasks, route, &self.context, &label).await;
+                    report.runs.extend(runs);
+                }
+            }
+
+            let tasks = self.store.claim(&self.queue, &excluded_kinds).await?;
+            for task in tasks {
+                ran = true;
+                let run = run_task(&self.store, task, &self.routes, &self.context, &label).await;
+                report.runs.push(run);
+            }
+
+            if !ran {
+                return Ok(report);
+            }
+        }
+    }
+
+    pub fn store(&self) -> &Arc<dyn TaskStore> {
+        &self.store
+    }
 }
 
 pub struct TasksBuilderInner<E: Into<Box<dyn Error + Send + Sync>> + 'static>
@@ -120,11 +260,15 @@ where
     E: std::fmt::Display + Debug,
 {
     routes: HashMap<String, Box<dyn TaskHandler<Context, E> + Send + Sync>>,
+    batch_routes: BatchRouter<E>,
+    exclusive_kinds: Vec<String>,
+    kind_limits: HashMap<String, i32>,
     context: Context,
     queue: TaskQueue,
     concurrency: i64,
     label: String,
-    periodic_jobs: HashMap<String, String>,
+    periodic_jobs: HashMap<String, PeriodicJob>,
+    upcasters: Upcasters,
 }
 pub struct TasksBuilder<E: Into<Box<dyn Error + Send + Sync>> + 'static>
 where
@@ -147,16 +291,146 @@ where
         self
     }
 
-    pub fn register_periodic<S, K, H, Arg>(mut self, schedule: S, kind: K, handler: H) -> Self
+    /// Registers a handler that receives up to `size` claimed tasks of `kind`
+    /// at once. Once the first task is due, the runner waits for at most
+    /// `window` for the batch to fill up, then claims it and calls the
+    /// handler right away. The handler gets the args loaded, decrypted and
+    /// upcast to the latest registered version.
+    pub fn register_batch<K, H>(mut self, kind: K, size: i64, window: Duration, handler: H) -> Self
     where
-        S: Into<String>,
-        K: Into<String> + Clone,
+        K: Into<String>,
+        H: BatchTaskHandler<E> + Send + Sync + 'static,
+    {
+        let route = BatchRoute {
+            handler: Box::new(handler),
+            size,
+            window,
+        };
+        self.inner.batch_routes.insert(kind.into(), route);
+        self
+    }
+
+    /// Never runs two tasks of `kind` at the same time, on any queue or
+    /// runner. Tasks of the kind are claimed one at a time while holding a
+    /// Postgres advisory lock, see [`super::AdvisoryLock`], which keeps one
+    /// extra pool connection busy per exclusive kind. The kind also gets a
+    /// limit of one running task, like [`TasksBuilder::max_running`] but
+    /// replacing a limit that already exists, so runners that don't mark it
+    /// exclusive skip it while a task runs.
+    pub fn exclusive<K>(mut self, kind: K) -> Self
+    where
+        K: Into<String>,
+    {
+        self.set_exclusive(kind);
+        self
+    }
+
+    pub fn set_exclusive<K>(&mut self, kind: K)
+    where
+        K: Into<String>,
+    {
+        let kind = kind.into();
+        if !self.inner.exclusive_kinds.contains(&kind) {
+            self.inner.exclusive_kinds.push(kind);
+        }
+    }
+
+    /// Runs at most `max_running` tasks of `kind` at once across all runners.
+    /// A limit that already exists in `chang.kind_limits` is kept, so changes
+    /// made at runtime with [`TaskService::set_kind_limit`] survive a
+    /// restart.
+    pub fn max_running<K>(mut self, kind: K, max_running: i32) -> Self
+    where
+        K: Into<String>,
+    {
+        self.set_max_running(kind, max_running);
+        self
+    }
+
+    pub fn set_max_running<K>(&mut self, kind: K, max_running: i32)
+    where
+        K: Into<String>,
+    {
+        self.inner.kind_limits.insert(kind.into(), max_running);
+    }
+
+    /// Registers a handler that runs on `schedule` with `null` args, see
+    /// [`PeriodicSchedule`] for the accepted formats. Fails if the schedule
+    /// can't be parsed. Use [`TasksBuilder::add_periodic`] for args and
+    /// per-schedule options.
+    pub fn register_periodic<S, K, H, Arg>(
+        mut self,
+        schedule: S,
+        kind: K,
+        handler: H,
+    ) -> Result<Self, PeriodicScheduleError>
+    where
+        S: TryInto<PeriodicSchedule>,
+        PeriodicScheduleError: From<S::Error>,
+        K: Into<String>,
         H: TaskHandler<Arg, E> + Sync + 'static + Send,
     {
-        self.inner
-            .periodic_jobs
-            .insert(kind.clone().into(), schedule.into());
-        self.register(kind, handler)
+        let kind = kind.into();
+        let job = PeriodicJob {
+            name: kind.clone(),
+            kind: kind.clone(),
+            schedule: schedule.try_into()?,
+            args: serde_json::Value::Null,
+            priority: None,
+            max_attempts: None,
+            tags: vec![],
+            timeout: None,
+            misfire_policy: MisfirePolicy::Skip,
+            args_version: 1,
+        };
+
+        self.set_periodic(job);
+        Ok(self.register(kind, handler))
+    }
+
+    /// Adds a periodic job, the handler for its kind has to be registered
+    /// separately. A job with the same name replaces the previous one.
+    pub fn add_periodic(mut self, job: PeriodicJob) -> Self {
+        self.set_periodic(job);
+        self
+    }
+
+    pub fn set_periodic(&mut self, job: PeriodicJob) {
+        self.inner.periodic_jobs.insert(job.name.clone(), job);
+    }
+
+    /// How far ahead periodic tasks are enqueued, defaults to an hour. The
+    /// scheduler runs once per horizon.
+    pub fn periodic_horizon(mut self, horizon: Duration) -> Self {
+        self.set_context(PeriodicHorizon(horizon));
+        self
+    }
+
+    /// Registers `upcast` to migrate args of `kind` stored with
+    /// `from_version` to `from_version + 1`. Args are upcast one version at
+    /// a time until they reach [`super::TaskKind::version`].
+    pub fn upcast<K, F>(mut self, kind: K, from_version: i16, upcast: F) -> Self
+    where
+        K: Into<String>,
+        F: Fn(serde_json::Value) -> serde_json::Value + Send + Sync + 'static,
+    {
+        self.set_upcast(kind, from_version, upcast);
+        self
+    }
+
+    pub fn set_upcast<K, F>(&mut self, kind: K, from_version: i16, upcast: F)
+    where
+        K: Into<String>,
+        F: Fn(serde_json::Value) -> serde_json::Value + Send + Sync + 'static,
+    {
+        self.inner.upcasters.add(&kind.into(), from_version, upcast);
+    }
+
+    /// Decrypts encrypted args for handlers, see [`super::ArgsEncryption`].
+    /// Handlers can take it from the context to encrypt the tasks they add.
+    pub fn args_encryption(mut self, encryption: ArgsEncryption) -> Self {
+        self.set_context(encryption);
+        self
     }
 
     pub fn add_context<Val>(mut self, value: Val) -> Self
@@ -189,13 +463,25 @@ where
         self
     }
 
-    pub fn connect(mut self, db: &PgPool) -> TaskRunner<E> {
-        self.set_context(db.clone());
-        self.set_context(PeriodicJobs(self.inner.periodic_jobs.clone()));
+    pub fn connect(self, db: &PgPool) -> TaskRunner<E> {
+        self.connect_store(Arc::new(PgTaskStore::new(db)))
+    }
+
+    /// Runs the tasks of `store`, e.g. a [`super::MemoryTaskStore`] in tests.
+    /// Periodic jobs and kind limits need a store backed by Postgres.
+    pub fn connect_store(mut self, store: Arc<dyn TaskStore>) -> TaskRunner<E> {
+        if let Some(db) = store.pool() {
+            self.set_context(db.clone());
+        }
+        let upcasters = std::mem::take(&mut self.inner.upcasters);
+        self.set_context(upcasters);
 
         TaskRunner {
             routes: Arc::new(self.inner.routes),
-            db: db.clone(),
+            batch_routes: Arc::new(self.inner.batch_routes),
+            exclusive_kinds: Arc::new(self.inner.exclusive_kinds),
+            kind_limits: Arc::new(self.inner.kind_limits),
+            store,
             context: Arc::new(self.inner.context),
             queue: Arc::new(self.inner.queue),
             concurrency: self.inner.concurrency,
@@ -230,3 +516,105 @@ impl Deref for Db {
         &self.0
     }
 }
+
+#[cfg(test)]
+mod test {
+    use anyhow::anyhow;
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::task::{MemoryTaskStore, Outcome, Snooze, Store, Task, TaskState, TaskStore};
+
+    #[tokio::test]
+    async fn drains_due_tasks_inline() {
+        let store = Arc::new(MemoryTaskStore::new());
+        let start = Utc.with_ymd_and_hms(2024, 3, 1, 12, 0, 0).unwrap();
+        store.set_now(start);
+
+        let runner = TaskRunner::<anyhow::Error>::builder()
+            .register("parent", |ctx: Context| async move {
+                let store = Store::from_context(&ctx)?;
+                let task = Task::builder()
+                    .kind("spawned")
+                    .args(serde_json::Value::Null)
+                    .build()?;
+                store.insert(task).await?;
+                Ok(TaskState::Completed)
+            })
+            .register("spawned", |_ctx: Context| async {
+                Ok(TaskState::Completed)
+            })
+            .register("flaky", |_ctx: Context| async { Err(anyhow!("flaky")) })
+            .register("sleepy", |ctx: Context| async move {
+                let task = Task::from_context(&ctx)?;
+                if task.args["slept"].is_null() {
+                    let snooze = Snooze::from_context(&ctx)?;
+                    snooze.after(Duration::from_secs(3600));
+                    snooze.with_args(serde_json::json!({ "slept": true }));
+                }
+                Ok(TaskState::Completed)
+            })
+            .connect_store(store.clone());
+
+        let group = uuid::Uuid::new_v4();
+        let insert = |builder: crate::task::TaskBuilder| {
+            let store = store.clone();
+            async move { store.insert(builder.build().unwrap()).await.unwrap() }
+        };
+        let dependent = insert(
+            Task::builder()
+                .kind("spawned")
+                .args(serde_json::Value::Null)
+                .depends_on(&group),
+        )
+        .await;
+        let parent = insert(
+            Task::builder()
+                .kind("parent")
+                .args(serde_json::Value::Null)
+                .dependend_id(&group),
+        )
+        .await;
+        let flaky = insert(
+            Task::builder()
+                .kind("flaky")
+                .args(serde_json::Value::Null)
+                .max_attempts(2),
+        )
+        .await;
+        let sleepy = insert(Task::builder().kind("sleepy").args(serde_json::json!({}))).await;
+
+        let report = runner.drain().await.unwrap();
+
+        assert_eq!(report.outcome(&parent), Some(&Outcome::Completed));
+        assert_eq!(report.outcome(&dependent), Some(&Outcome::Completed));
+        assert_eq!(report.runs_of(&flaky).count(), 2);
+        assert_eq!(report.failed().count(), 2);
+        assert_eq!(
+            report.outcome(&sleepy),
+            Some(&Outcome::Snoozed {
+                until: start + chrono::Duration::hours(1)
+            })
+        );
+
+        // the task the parent spawned ran in the same drain
+        let spawned = store
+            .tasks()
+            .into_iter()
+            .filter(|task| task.kind == "spawned" && task.id != dependent)
+            .collect::<Vec<_>>();
+        assert_eq!(spawned.len(), 1);
+        assert_eq!(spawned[0].state, TaskState::Completed);
+        assert_eq!(
+            store.get_task(&flaky).await.unwrap().unwrap().state,
+            TaskState::Discarded
+        );
+
+        assert!(runner.drain().await.unwrap().is_empty());
+
+        store.advance(Duration::from_secs(3600));
+        let report = runner.drain().await.unwrap();
+        assert_eq!(report.runs.len(), 1);
+        assert_eq!(report.outcome(&sleepy), Some(&Outcome::Completed));
+    }
+}
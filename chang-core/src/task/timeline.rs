@@ -0,0 +1,221 @@
+use chrono::{DateTime, Utc};
+use uuid::Uuid;
+
+use crate::db::tasks::{TaskErrorRecord, TaskHistory, TaskState};
+
+/// One run of a task, from its claim to the state it left `running` for.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AttemptTimeline {
+    /// Counted like the task's `attempt`, a snoozed run doesn't use one up,
+    /// so the next run has the same number.
+    pub attempt: i16,
+    pub started_at: DateTime<Utc>,
+    /// `None` while the task is still running.
+    pub finished_at: Option<DateTime<Utc>>,
+    pub finished_as: Option<TaskState>,
+    pub comment: String,
+    pub errors: Vec<TaskErrorRecord>,
+}
+
+impl AttemptTimeline {
+    pub fn duration(&self) -> Option<chrono::Duration> {
+        self.finished_at
+            .map(|finished_at| finished_at - self.started_at)
+    }
+
+    /// A snooze leaves `running` for `available`, or for `retryable` while a
+    /// deduped sibling waits, and is told apart from a failure by the
+    /// comment chang.snooze records.
+    pub fn is_snoozed(&self) -> bool {
+        matches!(
+            self.finished_as,
+            Some(TaskState::Available | TaskState::Retryable)
+        ) && self.comment.starts_with("snoozed until ")
+    }
+}
+
+/// The history of a task grouped into its runs, for debugging tasks that
+/// fail now and then.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TaskTimeline {
+    pub task_id: Uuid,
+    pub attempts: Vec<AttemptTimeline>,
+    /// Every state change, including those outside of runs like a
+    /// cancellation or an expiry.
+    pub history: Vec<TaskHistory>,
+}
+
+impl TaskTimeline {
+    /// Groups `history` into runs, an error belongs to the run it was
+    /// recorded in.
+    pub fn new(task_id: &Uuid, history: Vec<TaskHistory>, errors: Vec<TaskErrorRecord>) -> Self {
+        let mut attempts: Vec<AttemptTimeline> = vec![];
+        let mut attempt = 0;
+
+        for entry in history.iter() {
+            if entry.to_state == TaskState::Running {
+                attempt += 1;
+                attempts.push(AttemptTimeline {
+                    attempt,
+                    started_at: entry.created_at,
+                    finished_at: None,
+                    finished_as: None,
+                    comment: String::new(),
+                    errors: vec![],
+                });
+                continue;
+            }
+
+            let Some(run) = attempts
+                .last_mut()
+                .filter(|run| entry.from_state == TaskState::Running && run.finished_at.is_none())
+            else {
+                continue;
+            };
+
+            run.finished_at = Some(entry.created_at);
+            run.finished_as = Some(entry.to_state.clone());
+            run.comment.clone_from(&entry.comment);
+            if run.is_snoozed() {
+                attempt -= 1;
+            }
+        }
+
+        for error in errors {
+            let run = attempts.iter_mut().rev().find(|run| {
+                run.started_at <= error.created_at
+                    && run
+                        .finished_at
+                        .map(|finished_at| error.created_at <= finished_at)
+                        .unwrap_or(true)
+            });
+            if let Some(run) = run {
+                run.errors.push(error);
+            }
+        }
+
+        TaskTimeline {
+            task_id: *task_id,
+            attempts,
+            history,
+        }
+    }
+
+    pub fn last_attempt(&self) -> Option<&AttemptTimeline> {
+        self.attempts.last()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::db::migration;
+    use crate::task::{Task, TaskService};
+    use crate::utils;
+
+    #[tokio::test]
+    async fn groups_history_into_attempts() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::tasks(&prepare.pool).await;
+
+        let id = Task::builder()
+            .kind("flaky")
+            .args(serde_json::Value::Null)
+            .scheduled_at(&(Utc::now() - chrono::Duration::minutes(1)))
+            .queue(&prepare.name)
+            .build()
+            .unwrap()
+            .insert(&prepare.pool)
+            .await
+            .unwrap();
+
+        let claim = || TaskService::get_tasks(&prepare.pool, &prepare.name, 10, &[]);
+
+        claim().await.unwrap();
+        TaskService::failed(&prepare.pool, &id, "connection reset")
+            .await
+            .unwrap();
+        claim().await.unwrap();
+        TaskService::snooze(
+            &prepare.pool,
+            &id,
+            &(Utc::now() - chrono::Duration::seconds(1)),
+            None,
+        )
+        .await
+        .unwrap();
+        claim().await.unwrap();
+        TaskService::complete(&prepare.pool, &id).await.unwrap();
+
+        let history = TaskService::history(&prepare.pool, &id).await.unwrap();
+        assert_eq!(history.len(), 6);
+        assert_eq!(history[0].from_state, TaskState::Available);
+        assert_eq!(history[0].to_state, TaskState::Running);
+
+        let errors = TaskService::errors(&prepare.pool, &id).await.unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].error.as_deref(), Some("connection reset"));
+
+        let timeline = TaskService::timeline(&prepare.pool, &id).await.unwrap();
+        let attempts = timeline
+            .attempts
+            .iter()
+            .map(|run| (run.attempt, run.finished_as.clone(), run.errors.len()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            attempts,
+            vec![
+                (1, Some(TaskState::Retryable), 1),
+                (2, Some(TaskState::Available), 0),
+                (2, Some(TaskState::Completed), 0),
+            ]
+        );
+        assert!(timeline.attempts[1].is_snoozed());
+        assert!(timeline.attempts[1].comment.starts_with("snoozed until"));
+        assert!(timeline.attempts.iter().all(|run| run
+            .duration()
+            .is_some_and(|duration| duration >= chrono::Duration::zero())));
+
+        utils::test::cleanup(prepare).await;
+    }
+
+    #[tokio::test]
+    async fn counts_a_snooze_behind_a_waiting_sibling_as_snoozed() {
+        let prepare = utils::test::prepare().await;
+
+        migration::base(&prepare.pool).await;
+        migration::tasks(&prepare.pool).await;
+
+        let insert = || {
+            Task::builder()
+                .kind("reindex_user")
+                .args(serde_json::Value::Null)
+                .debounce("user-1", std::time::Duration::ZERO)
+                .queue(&prepare.name)
+                .build()
+                .unwrap()
+                .insert(&prepare.pool)
+        };
+
+        let id = insert().await.unwrap();
+        TaskService::get_tasks(&prepare.pool, &prepare.name, 10, &[])
+            .await
+            .unwrap();
+        insert().await.unwrap();
+        TaskService::snooze(&prepare.pool, &id, &Utc::now(), None)
+            .await
+            .unwrap();
+        TaskService::get_tasks(&prepare.pool, &prepare.name, 10, &[])
+            .await
+            .unwrap();
+
+        let timeline = TaskService::timeline(&prepare.pool, &id).await.unwrap();
+        assert_eq!(timeline.attempts[0].finished_as, Some(TaskState::Retryable));
+        assert!(timeline.attempts[0].is_snoozed());
+        assert_eq!(timeline.attempts[1].attempt, 1);
+
+        utils::test::cleanup(prepare).await;
+    }
+}
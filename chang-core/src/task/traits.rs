@@ -1,12 +1,16 @@
 use crate::db::tasks::Task;
 use crate::utils::context::{Context, CurrentTask};
 
+use crate::db::payloads::Payload;
 use crate::db::tasks::TaskState;
+use crate::task::encryption::{ArgsBinding, ArgsEncryption, EncryptionError};
+use crate::task::lazy_args::LazyArgs;
 use futures_util::Future;
-use log::error;
+use std::collections::HashMap;
 use std::error::Error;
 use std::fmt::Debug;
 use std::pin::Pin;
+use uuid::Uuid;
 
 pub trait FromTaskContext {
     type Error: Into<Box<dyn Error + Send + Sync>>;
@@ -38,6 +42,12 @@ pub enum CurrentTaskError {
 
     #[error(transparent)]
     Task(#[from] TaskError),
+
+    #[error(transparent)]
+    Encryption(#[from] EncryptionError),
+
+    #[error("args are offloaded, load them with LazyArgs::load first")]
+    NotLoaded,
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -47,14 +57,35 @@ pub enum TaskContextError {
 
     #[error(transparent)]
     CurrentTask(#[from] CurrentTaskError),
+
+    #[error(transparent)]
+    Upcast(#[from] super::UpcastError),
 }
 
+/// The args of the current task, decrypted with the [`ArgsEncryption`] in the
+/// context if they are encrypted. Offloaded args are taken from the
+/// [`LazyArgs`] that `run_task` loads before the handler runs.
 impl FromTaskContext for CurrentTask {
     type Error = CurrentTaskError;
 
     fn from_context(ctx: &Context) -> Result<Self, Self::Error> {
         let current_task = Task::from_context(ctx)?;
-        Ok(CurrentTask(current_task.args.clone()))
+        let binding = ArgsBinding::of(&current_task);
+        let args = match Payload::reference(&current_task.args) {
+            None => current_task.args,
+            Some(_) => ctx
+                .get::<LazyArgs>()
+                .and_then(LazyArgs::loaded)
+                .cloned()
+                .ok_or(CurrentTaskError::NotLoaded)?,
+        };
+
+        if !ArgsEncryption::is_encrypted(&args) {
+            return Ok(CurrentTask(args));
+        }
+
+        let encryption = ArgsEncryption::from_context(ctx)?;
+        Ok(CurrentTask(encryption.decrypt(args, &binding)?))
     }
 }
 
@@ -72,3 +103,30 @@ where
         Box::pin(self(ctx))
     }
 }
+
+/// Per-task results of a batch handler, keyed by task id. Tasks without an
+/// entry are treated as failed.
+pub type BatchOutcome<E> = HashMap<Uuid, Result<TaskState, E>>;
+
+pub trait BatchTaskHandler<E> {
+    fn call(
+        &self,
+        ctx: Context,
+        tasks: Vec<Task>,
+    ) -> Pin<Box<dyn Future<Output = Result<BatchOutcome<E>, E>> + Send>>;
+}
+
+impl<F: Sync + 'static, Ret, E> BatchTaskHandler<E> for F
+where
+    F: Fn(Context, Vec<Task>) -> Ret + Sync + 'static,
+    Ret: Future<Output = Result<BatchOutcome<E>, E>> + Send + 'static,
+    E: Into<Box<dyn Error + Send + Sync>>,
+{
+    fn call(
+        &self,
+        ctx: Context,
+        tasks: Vec<Task>,
+    ) -> Pin<Box<dyn Future<Output = Result<BatchOutcome<E>, E>> + Send>> {
+        Box::pin(self(ctx, tasks))
+    }
+}
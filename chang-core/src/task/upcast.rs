@@ -0,0 +1,208 @@
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+use super::traits::TaskContextError;
+use super::FromTaskContext;
+use crate::db::tasks::{Task, TaskKind};
+use crate::utils::context::{Context, CurrentTask};
+
+type Upcaster = Arc<dyn Fn(serde_json::Value) -> serde_json::Value + Send + Sync>;
+
+/// Functions that migrate the args of a kind from one version to the next,
+/// registered with [`crate::task::TasksBuilder::upcast`].
+#[derive(Clone, Default)]
+pub struct Upcasters {
+    steps: HashMap<(String, i16), Upcaster>,
+}
+
+impl Upcasters {
+    /// Registers `upcast`, which turns args of `kind` at `from_version` into
+    /// args at `from_version + 1`.
+    pub fn add<F>(&mut self, kind: &str, from_version: i16, upcast: F)
+    where
+        F: Fn(serde_json::Value) -> serde_json::Value + Send + Sync + 'static,
+    {
+        self.steps
+            .insert((kind.to_string(), from_version), Arc::new(upcast));
+    }
+
+    /// Runs the upcasters from `version` up to `current` one after another.
+    pub fn upcast(
+        &self,
+        kind: &str,
+        args: serde_json::Value,
+        version: i16,
+        current: i16,
+    ) -> Result<serde_json::Value, UpcastError> {
+        if version > current || version < 1 {
+            return Err(UpcastError::UnknownVersion {
+                kind: kind.to_string(),
+                version,
+                current,
+            });
+        }
+
+        (version..current).try_fold(args, |args, from_version| {
+            let upcast = self
+                .steps
+                .get(&(kind.to_string(), from_version))
+                .ok_or_else(|| UpcastError::MissingUpcaster {
+                    kind: kind.to_string(),
+                    from_version,
+                })?;
+
+            Ok(upcast(args))
+        })
+    }
+}
+
+impl Upcasters {
+    /// Runs the upcasters from `version` on as long as there is one, for
+    /// handlers like batch handlers that don't name the version they expect.
+    /// Returns the args and the version they were upcast to.
+    pub fn upcast_latest(
+        &self,
+        kind: &str,
+        mut args: serde_json::Value,
+        mut version: i16,
+    ) -> (serde_json::Value, i16) {
+        while let Some(upcast) = self.steps.get(&(kind.to_string(), version)) {
+            args = upcast(args);
+            version += 1;
+        }
+
+        (args, version)
+    }
+}
+
+impl fmt::Debug for Upcasters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.steps.keys()).finish()
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum UpcastError {
+    #[error("args of {kind} have unknown version {version}, the current version is {current}")]
+    UnknownVersion {
+        kind: String,
+        version: i16,
+        current: i16,
+    },
+
+    #[error("no upcaster registered for {kind} args from version {from_version}")]
+    MissingUpcaster { kind: String, from_version: i16 },
+}
+
+/// Deserializes the args of the current task as `T`, decrypting and upcasting
+/// them first if needed. Used by `#[derive(Task)]`.
+pub fn task_args<T>(ctx: &Context) -> Result<T, TaskContextError>
+where
+    T: TaskKind + DeserializeOwned,
+{
+    let task = Task::from_context(ctx).map_err(super::CurrentTaskError::from)?;
+    let CurrentTask(args) = CurrentTask::from_context(ctx)?;
+    let current = T::version();
+
+    let args = if task.args_version == current {
+        args
+    } else {
+        let upcasters = ctx.get::<Upcasters>().cloned().unwrap_or_default();
+        upcasters.upcast(&task.kind, args, task.args_version, current)?
+    };
+
+    Ok(serde_json::from_value(args)?)
+}
+
+#[cfg(test)]
+mod test {
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::task::TaskState;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct SendMail {
+        to: Vec<String>,
+        subject: String,
+    }
+
+    impl TaskKind for SendMail {
+        fn kind() -> String {
+            String::from("send_mail")
+        }
+
+        fn version() -> i16 {
+            3
+        }
+    }
+
+    fn context(args: serde_json::Value, args_version: i16) -> Context {
+        let mut upcasters = Upcasters::default();
+        upcasters.add("send_mail", 1, |mut args| {
+            args["to"] = serde_json::json!([args["to"].take()]);
+            args
+        });
+        upcasters.add("send_mail", 2, |mut args| {
+            args["subject"] = serde_json::json!("(no subject)");
+            args
+        });
+
+        let task = Task {
+            id: uuid::Uuid::new_v4(),
+            state: TaskState::Running,
+            scheduled_at: None,
+            attempt: 1,
+            max_attempts: 3,
+            attempted_by: None,
+            tags: None,
+            kind: "send_mail".to_string(),
+            args,
+            priority: 100,
+            queue: None,
+            depends_on: None,
+            dependend_id: None,
+            fairness_key: None,
+            timeout_ms: None,
+            periodic_name: None,
+            progress: None,
+            progress_message: None,
+            progress_data: None,
+            args_version,
+            expires_at: None,
+            dedupe_key: None,
+        };
+
+        let mut ctx = Context::new();
+        ctx.put(task);
+        ctx.put(upcasters);
+        ctx
+    }
+
+    #[test]
+    fn upcasts_old_args() {
+        let ctx = context(serde_json::json!({ "to": "chang@example.com" }), 1);
+        let mail = task_args::<SendMail>(&ctx).unwrap();
+
+        assert_eq!(
+            mail,
+            SendMail {
+                to: vec!["chang@example.com".to_string()],
+                subject: "(no subject)".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn fails_on_unknown_versions() {
+        let ctx = context(serde_json::json!({}), 4);
+        let error = task_args::<SendMail>(&ctx).unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "args of send_mail have unknown version 4, the current version is 3"
+        );
+    }
+}
@@ -1,5 +1,6 @@
 // https://www.postgresql.org/docs/current/libpq-connect.html#LIBPQ-CONNSTRING
 
+use std::fmt;
 use url::Url;
 
 use crate::utils::name;
@@ -33,12 +34,12 @@ impl ConnectionString {
     }
 }
 
-impl ToString for ConnectionString {
-    fn to_string(&self) -> String {
+impl fmt::Display for ConnectionString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut url = String::from("postgresql://");
 
         if let Some(username) = &self.inner.username {
-            url.push_str(&username);
+            url.push_str(username);
         }
 
         if let Some(password) = &self.inner.password {
@@ -50,7 +51,7 @@ impl ToString for ConnectionString {
         }
 
         if let Some(host) = &self.inner.host {
-            url.push_str(&host);
+            url.push_str(host);
         }
 
         if let Some(port) = &self.inner.port {
@@ -61,7 +62,7 @@ impl ToString for ConnectionString {
             url.push_str(&format!("/{}", database));
         }
 
-        url
+        f.write_str(&url)
     }
 }
 
@@ -74,11 +75,15 @@ impl TryFrom<&str> for ConnectionString {
         let database = url.path_segments().and_then(|mut segments| segments.next());
 
         if let Some(db) = database {
-            name::is_valid(&db)?;
+            name::is_valid(db)?;
         }
 
         let username = url.username();
-        let username = if username == "" { None } else { Some(username) };
+        let username = if username.is_empty() {
+            None
+        } else {
+            Some(username)
+        };
 
         let inner = ConnectionStringInner {
             username: username.map(|val| val.into()),
@@ -101,11 +106,15 @@ impl TryFrom<String> for ConnectionString {
         let database = url.path_segments().and_then(|mut segments| segments.next());
 
         if let Some(db) = database {
-            name::is_valid(&db)?;
+            name::is_valid(db)?;
         }
 
         let username = url.username();
-        let username = if username == "" { None } else { Some(username) };
+        let username = if username.is_empty() {
+            None
+        } else {
+            Some(username)
+        };
 
         let inner = ConnectionStringInner {
             username: username.map(|val| val.into()),
@@ -61,6 +61,13 @@ pub async fn cleanup(mut prepare: Prepare) {
         .await
         .expect("Valid DB connection");
 
+    // closed connections can take a moment to end on the server
+    sqlx::query("select pg_terminate_backend(pid) from pg_stat_activity where datname = $1")
+        .bind(&prepare.name)
+        .execute(&pool)
+        .await
+        .expect("failed to end open sessions");
+
     database::drop(&pool, &prepare.name)
         .await
         .expect("failed to drop database");
@@ -35,7 +35,7 @@ fn impl_event_macro(ast: &syn::DeriveInput) -> TokenStream {
     TokenStream::from(impl_event)
 }
 
-#[proc_macro_derive(Task)]
+#[proc_macro_derive(Task, attributes(task))]
 pub fn task_macro_derive(input: TokenStream) -> TokenStream {
     // Construct a representation of Rust code as a syntax tree
     // that we can manipulate
@@ -49,20 +49,40 @@ fn impl_task_macro(ast: &syn::DeriveInput) -> TokenStream {
     let name = &ast.ident;
     let kind = name.to_string().to_case(Case::Snake);
 
+    // #[task(version = 2)]
+    let mut version: i16 = 1;
+    for attr in ast.attrs.iter().filter(|attr| attr.path().is_ident("task")) {
+        let parsed = attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("version") {
+                let value: syn::LitInt = meta.value()?.parse()?;
+                version = value.base10_parse()?;
+                Ok(())
+            } else {
+                Err(meta.error("unsupported task attribute"))
+            }
+        });
+
+        if let Err(error) = parsed {
+            return TokenStream::from(error.to_compile_error());
+        }
+    }
+
     let impl_event = quote! {
         impl chang::task::TaskKind for #name {
             fn kind() -> String {
                 #kind.to_string()
             }
+
+            fn version() -> i16 {
+                #version
+            }
         }
 
         impl chang::task::FromTaskContext for #name {
             type Error = chang::task::TaskContextError;
 
             fn from_context(ctx: &Context) -> Result<Self, Self::Error> {
-                let current_task = chang::task::CurrentTask::from_context(ctx)?;
-                let task = serde_json::from_value(current_task.0.clone())?;
-                Ok(task)
+                chang::task::task_args::<Self>(ctx)
             }
         }
 
@@ -5,7 +5,6 @@ use log::{info, Level, LevelFilter, Metadata, Record};
 use sqlx::postgres::PgPoolOptions;
 use sqlx::Postgres;
 use std::env;
-use tokio;
 
 struct SimpleLogger;
 
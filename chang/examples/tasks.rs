@@ -5,7 +5,6 @@ use serde::{Deserialize, Serialize};
 use sqlx::PgPool;
 use sqlx::{postgres::PgPoolOptions, types::Uuid};
 use std::env;
-use tokio;
 
 use chang::{
     task::{self, Context, Db, FromTaskContext, Task, TaskBuilder, TaskKind, TaskRunner},
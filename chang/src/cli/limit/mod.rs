@@ -0,0 +1,75 @@
+use std::env;
+
+use chang_core::task::{KindLimit, TaskService};
+use clap::{Args, Subcommand};
+use sqlx::postgres::PgPoolOptions;
+
+#[derive(Args)]
+pub struct LimitArgs {
+    database_url: Option<String>,
+
+    #[command(subcommand)]
+    command: LimitCommands,
+}
+
+#[derive(Subcommand)]
+enum LimitCommands {
+    List,
+    /// Creates or changes the running task limit of a kind
+    Set {
+        kind: String,
+        max_running: i32,
+    },
+    Delete {
+        kind: String,
+    },
+}
+
+pub async fn run(args: LimitArgs) {
+    let database_url = args
+        .database_url
+        .unwrap_or_else(|| env::var("DATABASE_URL").expect("DATABASE_URL environment variable"));
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&database_url)
+        .await
+        .expect("Valid DB connection");
+
+    match args.command {
+        LimitCommands::List => {
+            let kind_limits = TaskService::get_kind_limits(&pool)
+                .await
+                .expect("Failed to fetch kind limits");
+
+            for kind_limit in kind_limits.iter() {
+                print_kind_limit(kind_limit);
+            }
+        }
+        LimitCommands::Set { kind, max_running } => {
+            let kind_limit = TaskService::set_kind_limit(&pool, &kind, max_running)
+                .await
+                .expect("Failed to set kind limit");
+
+            print_kind_limit(&kind_limit);
+        }
+        LimitCommands::Delete { kind } => {
+            let deleted = TaskService::delete_kind_limit(&pool, &kind)
+                .await
+                .expect("Failed to delete kind limit");
+
+            if deleted {
+                println!("deleted limit of {}", kind);
+            } else {
+                println!("{} has no limit", kind);
+            }
+        }
+    }
+}
+
+fn print_kind_limit(kind_limit: &KindLimit) {
+    println!(
+        "{}\t{}/{}",
+        kind_limit.kind, kind_limit.running, kind_limit.max_running
+    );
+}
@@ -1,9 +1,13 @@
 use clap::{Parser, Subcommand};
 use dotenv::dotenv;
 
+mod limit;
 mod migrate;
+mod periodic;
 
+use limit::LimitArgs;
 use migrate::MigrateArgs;
+use periodic::PeriodicArgs;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -14,7 +18,9 @@ struct Cli {
 
 #[derive(Subcommand)]
 enum Commands {
+    Limit(LimitArgs),
     Migrate(MigrateArgs),
+    Periodic(PeriodicArgs),
 }
 
 #[tokio::main]
@@ -25,7 +31,9 @@ async fn main() {
 
     if let Some(command) = cli.command {
         match command {
+            Commands::Limit(args) => limit::run(args).await,
             Commands::Migrate(args) => migrate::run(args).await,
+            Commands::Periodic(args) => periodic::run(args).await,
         }
     }
 }
@@ -0,0 +1,291 @@
+use std::env;
+use std::time::Duration;
+
+use chang_core::task::{
+    schedule_periodic_job, MisfirePolicy, PeriodicHorizon, PeriodicJob, PeriodicSchedule,
+    PeriodicTask, TaskService, DEFAULT_QUEUE,
+};
+use chrono::{DateTime, Utc};
+use clap::{Args, Subcommand};
+use sqlx::postgres::PgPoolOptions;
+
+#[derive(Args)]
+pub struct PeriodicArgs {
+    database_url: Option<String>,
+
+    #[arg(long, default_value = DEFAULT_QUEUE)]
+    queue: String,
+
+    /// Seconds ahead runs are enqueued, has to match the runner's periodic horizon
+    #[arg(long, default_value_t = 3600)]
+    horizon: u64,
+
+    #[command(subcommand)]
+    command: PeriodicCommands,
+}
+
+#[derive(Subcommand)]
+enum PeriodicCommands {
+    List,
+    Add {
+        kind: String,
+        schedule: String,
+
+        /// Defaults to the kind
+        #[arg(long)]
+        name: Option<String>,
+
+        #[command(flatten)]
+        options: JobOptions,
+    },
+    Update {
+        name: String,
+
+        #[arg(long)]
+        schedule: Option<String>,
+
+        #[command(flatten)]
+        options: JobOptions,
+    },
+    Pause {
+        name: String,
+    },
+    Resume {
+        name: String,
+    },
+    Delete {
+        name: String,
+    },
+    /// Enqueues the job for every slot between two RFC 3339 timestamps
+    Backfill {
+        name: String,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    },
+}
+
+#[derive(Args)]
+struct JobOptions {
+    /// Task args as JSON
+    #[arg(long)]
+    args: Option<String>,
+
+    #[arg(long)]
+    priority: Option<i16>,
+
+    #[arg(long)]
+    max_attempts: Option<i16>,
+
+    #[arg(long = "tag")]
+    tags: Vec<String>,
+
+    #[arg(long)]
+    timeout_ms: Option<u64>,
+
+    /// skip, run_once or run_all
+    #[arg(long, value_parser = parse_misfire_policy)]
+    misfire_policy: Option<MisfirePolicy>,
+}
+
+impl JobOptions {
+    fn apply(self, job: &mut PeriodicJob) {
+        if let Some(args) = self.args {
+            job.args = serde_json::from_str(&args).expect("Valid JSON args");
+        }
+
+        if self.priority.is_some() {
+            job.priority = self.priority;
+        }
+
+        if self.max_attempts.is_some() {
+            job.max_attempts = self.max_attempts;
+        }
+
+        if !self.tags.is_empty() {
+            job.tags = self.tags;
+        }
+
+        if let Some(timeout_ms) = self.timeout_ms {
+            job.timeout = Some(Duration::from_millis(timeout_ms));
+        }
+
+        if let Some(misfire_policy) = self.misfire_policy {
+            job.misfire_policy = misfire_policy;
+        }
+    }
+}
+
+pub async fn run(args: PeriodicArgs) {
+    let database_url = args
+        .database_url
+        .unwrap_or_else(|| env::var("DATABASE_URL").expect("DATABASE_URL environment variable"));
+
+    let pool = PgPoolOptions::new()
+        .max_connections(1)
+        .connect(&database_url)
+        .await
+        .expect("Valid DB connection");
+
+    let queue = &args.queue;
+    let horizon = PeriodicHorizon(Duration::from_secs(args.horizon));
+
+    match args.command {
+        PeriodicCommands::List => {
+            let periodic_tasks = TaskService::get_periodic_tasks(&pool, queue)
+                .await
+                .expect("Failed to fetch periodic tasks");
+
+            for periodic_task in periodic_tasks.iter() {
+                print_periodic_task(periodic_task);
+            }
+        }
+        PeriodicCommands::Add {
+            kind,
+            schedule,
+            name,
+            options,
+        } => {
+            let mut job = PeriodicJob::builder()
+                .name(name.as_deref().unwrap_or(&kind))
+                .kind(&kind)
+                .schedule(parse_schedule(&schedule))
+                .build()
+                .expect("Valid periodic job");
+            options.apply(&mut job);
+
+            let periodic_task = TaskService::add_periodic_task(&pool, queue, &job)
+                .await
+                .expect("Failed to add periodic task");
+
+            let Some(periodic_task) = periodic_task else {
+                println!("periodic task({}) already exists in {}", job.name, queue);
+                return;
+            };
+
+            // enqueue the first runs now instead of waiting for the scheduler
+            schedule_periodic_job(&pool, queue, &job, &Utc::now(), &horizon)
+                .await
+                .expect("Failed to schedule periodic task");
+
+            print_periodic_task(&periodic_task);
+        }
+        PeriodicCommands::Update {
+            name,
+            schedule,
+            options,
+        } => {
+            let current = TaskService::get_periodic_task(&pool, queue, &name)
+                .await
+                .expect("Failed to fetch periodic task");
+
+            let Some(current) = current else {
+                println!("periodic task({}) not found in {}", name, queue);
+                return;
+            };
+
+            let mut job = current.job().expect("Valid stored schedule");
+            if let Some(schedule) = schedule {
+                job.schedule = parse_schedule(&schedule);
+            }
+            options.apply(&mut job);
+
+            let periodic_task = TaskService::update_periodic_task(&pool, queue, &job)
+                .await
+                .expect("Failed to update periodic task");
+
+            // the runs enqueued with the old job were cancelled
+            if periodic_task.is_some() {
+                schedule_periodic_job(&pool, queue, &job, &Utc::now(), &horizon)
+                    .await
+                    .expect("Failed to schedule periodic task");
+            }
+
+            print_result(periodic_task, &name, queue);
+        }
+        PeriodicCommands::Pause { name } => {
+            let periodic_task = TaskService::pause_periodic_task(&pool, queue, &name)
+                .await
+                .expect("Failed to pause periodic task");
+
+            print_result(periodic_task, &name, queue);
+        }
+        PeriodicCommands::Resume { name } => {
+            let periodic_task = TaskService::resume_periodic_task(&pool, queue, &name)
+                .await
+                .expect("Failed to resume periodic task");
+
+            if let Some(periodic_task) = &periodic_task {
+                let job = periodic_task.job().expect("Valid stored schedule");
+                schedule_periodic_job(&pool, queue, &job, &Utc::now(), &horizon)
+                    .await
+                    .expect("Failed to schedule periodic task");
+            }
+
+            print_result(periodic_task, &name, queue);
+        }
+        PeriodicCommands::Delete { name } => {
+            let deleted = TaskService::delete_periodic_task(&pool, queue, &name)
+                .await
+                .expect("Failed to delete periodic task");
+
+            if deleted {
+                println!("periodic task({}) deleted from {}", name, queue);
+            } else {
+                println!("periodic task({}) not found in {}", name, queue);
+            }
+        }
+        PeriodicCommands::Backfill { name, from, to } => {
+            let periodic_task = TaskService::get_periodic_task(&pool, queue, &name)
+                .await
+                .expect("Failed to fetch periodic task");
+
+            let Some(periodic_task) = periodic_task else {
+                println!("periodic task({}) not found in {}", name, queue);
+                return;
+            };
+
+            let job = periodic_task.job().expect("Valid stored schedule");
+            let ids = TaskService::backfill_periodic_task(&pool, queue, &job, &from, &to)
+                .await
+                .expect("Failed to backfill periodic task");
+
+            println!("enqueued {} tasks for periodic task({})", ids.len(), name);
+        }
+    }
+}
+
+fn parse_schedule(schedule: &str) -> PeriodicSchedule {
+    match schedule.parse() {
+        Ok(schedule) => schedule,
+        Err(err) => panic!("{}", err),
+    }
+}
+
+fn parse_misfire_policy(value: &str) -> Result<MisfirePolicy, String> {
+    match value {
+        "skip" => Ok(MisfirePolicy::Skip),
+        "run_once" => Ok(MisfirePolicy::RunOnce),
+        "run_all" => Ok(MisfirePolicy::RunAll),
+        _ => Err(format!("unknown misfire policy {:?}", value)),
+    }
+}
+
+fn print_result(periodic_task: Option<PeriodicTask>, name: &str, queue: &str) {
+    match periodic_task {
+        Some(periodic_task) => print_periodic_task(&periodic_task),
+        None => println!("periodic task({}) not found in {}", name, queue),
+    }
+}
+
+fn print_periodic_task(periodic_task: &PeriodicTask) {
+    let state = if periodic_task.paused {
+        "paused"
+    } else {
+        "active"
+    };
+
+    println!(
+        "{}\t{}\t{}\t{}\t{}",
+        periodic_task.name, periodic_task.kind, state, periodic_task.schedule, periodic_task.args
+    );
+}
@@ -0,0 +1,42 @@
+use std::sync::Arc;
+
+use chang::task::{
+    Context, FromTaskContext, MemoryTaskStore, Outcome, TaskBuilder, TaskRunner, TaskState,
+    TaskStore,
+};
+use chang::Task;
+use serde::{Deserialize, Serialize};
+
+#[derive(Task, Deserialize, Serialize, Debug)]
+struct RenderReport {
+    tenant: String,
+    rows: Vec<i32>,
+}
+
+#[tokio::test]
+async fn extracts_offloaded_args_of_derived_tasks() {
+    let store = Arc::new(MemoryTaskStore::new());
+
+    let runner = TaskRunner::<anyhow::Error>::builder()
+        .register("render_report", |ctx: Context| async move {
+            let report = RenderReport::from_context(&ctx)?;
+            anyhow::ensure!(report.tenant == "chang", "unexpected tenant");
+            anyhow::ensure!(report.rows.len() == 100, "unexpected rows");
+            Ok(TaskState::Completed)
+        })
+        .connect_store(store.clone());
+
+    let task = chang::task::try_from(RenderReport {
+        tenant: "chang".to_string(),
+        rows: (0..100).collect(),
+    })
+    .unwrap()
+    .offload_above(64)
+    .build()
+    .unwrap();
+    assert!(task.payload.is_some());
+    let id = store.insert(task).await.unwrap();
+
+    let report = runner.drain().await.unwrap();
+    assert_eq!(report.outcome(&id), Some(&Outcome::Completed));
+}